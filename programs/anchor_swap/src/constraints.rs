@@ -0,0 +1,173 @@
+//! Program-owner constraints on the pools that can be created
+
+use crate::curve::{
+    base::{CurveType, SwapCurve},
+    fees::{CurveFees, DynamicFee, DYNAMIC_FEE_DENOMINATOR},
+};
+use crate::error::SwapError;
+use anchor_lang::prelude::*;
+use std::convert::TryFrom;
+
+/// Seed of the swap constraints config PDA
+pub const SWAP_CONSTRAINTS_SEED: &[u8] = b"swap_constraints";
+
+/// Maximum number of curve types the config can allow
+pub const MAX_VALID_CURVE_TYPES: usize = 8;
+
+/// Constraints set by the program owner, checked on every pool creation
+#[account]
+pub struct SwapConstraints {
+    /// Admin allowed to update the constraints, and required owner of every
+    /// pool fee account
+    pub owner_key: Pubkey,
+    /// Curve types allowed for new pools, encoded as `CurveType as u8`
+    pub valid_curve_types: Vec<u8>,
+    /// Fee fractions for new pools: trade, owner trade and owner withdraw
+    /// numerators are minimums and their denominators are required, while
+    /// the host fee must match exactly
+    pub fees: CurveFees,
+    /// Bump seed of the config PDA
+    pub bump_seed: u8,
+}
+
+impl SwapConstraints {
+    /// Space of the config account, discriminator included
+    pub const LEN: usize = 8 + 32 + (4 + MAX_VALID_CURVE_TYPES) + 64 + 1;
+
+    /// Check that the given curve types are known and fit in the account
+    pub fn validate_curve_types(valid_curve_types: &[u8]) -> Result<()> {
+        if valid_curve_types.len() > MAX_VALID_CURVE_TYPES {
+            return Err(SwapError::InvalidInput.into());
+        }
+        if valid_curve_types
+            .iter()
+            .any(|curve_type| CurveType::try_from(*curve_type).is_err())
+        {
+            return Err(SwapError::UnsupportedCurveType.into());
+        }
+        Ok(())
+    }
+
+    /// Checks that the provided curve is valid for the given constraints
+    pub fn validate_curve(&self, swap_curve: &SwapCurve) -> Result<()> {
+        if self
+            .valid_curve_types
+            .contains(&(swap_curve.curve_type as u8))
+        {
+            Ok(())
+        } else {
+            Err(SwapError::UnsupportedCurveType.into())
+        }
+    }
+
+    /// Checks that the provided fees are valid for the given constraints
+    pub fn validate_fees(&self, fees: &CurveFees) -> Result<()> {
+        if fees.trade_fee_numerator >= self.fees.trade_fee_numerator
+            && fees.trade_fee_denominator == self.fees.trade_fee_denominator
+            && fees.owner_trade_fee_numerator >= self.fees.owner_trade_fee_numerator
+            && fees.owner_trade_fee_denominator == self.fees.owner_trade_fee_denominator
+            && fees.owner_withdraw_fee_numerator >= self.fees.owner_withdraw_fee_numerator
+            && fees.owner_withdraw_fee_denominator == self.fees.owner_withdraw_fee_denominator
+            && fees.host_fee_numerator == self.fees.host_fee_numerator
+            && fees.host_fee_denominator == self.fees.host_fee_denominator
+        {
+            Ok(())
+        } else {
+            Err(SwapError::InvalidFee.into())
+        }
+    }
+
+    /// Checks that a dynamic trade fee never falls below the owner's trade fee
+    pub fn validate_dynamic_fee(&self, dynamic_fee: &DynamicFee) -> Result<()> {
+        if !dynamic_fee.is_enabled() || self.fees.trade_fee_numerator == 0 {
+            return Ok(());
+        }
+        if u128::from(dynamic_fee.base_fee_numerator) * u128::from(self.fees.trade_fee_denominator)
+            >= u128::from(self.fees.trade_fee_numerator) * u128::from(DYNAMIC_FEE_DENOMINATOR)
+        {
+            Ok(())
+        } else {
+            Err(SwapError::InvalidFee.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::constant_product::ConstantProductCurve;
+
+    fn constraints(fees: CurveFees) -> SwapConstraints {
+        SwapConstraints {
+            owner_key: Pubkey::new_unique(),
+            valid_curve_types: vec![CurveType::ConstantProduct as u8],
+            fees,
+            bump_seed: 255,
+        }
+    }
+
+    #[test]
+    fn validate_fees() {
+        let trade_fee_numerator = 1;
+        let trade_fee_denominator = 4;
+        let owner_trade_fee_numerator = 2;
+        let owner_trade_fee_denominator = 5;
+        let owner_withdraw_fee_numerator = 4;
+        let owner_withdraw_fee_denominator = 10;
+        let host_fee_numerator = 10;
+        let host_fee_denominator = 100;
+        let fees = CurveFees {
+            trade_fee_numerator,
+            trade_fee_denominator,
+            owner_trade_fee_numerator,
+            owner_trade_fee_denominator,
+            owner_withdraw_fee_numerator,
+            owner_withdraw_fee_denominator,
+            host_fee_numerator,
+            host_fee_denominator,
+        };
+        let swap_constraints = constraints(fees.clone());
+
+        swap_constraints.validate_fees(&fees).unwrap();
+
+        let mut higher_fees = fees.clone();
+        higher_fees.trade_fee_numerator = trade_fee_numerator + 1;
+        higher_fees.owner_trade_fee_numerator = owner_trade_fee_numerator + 1;
+        swap_constraints.validate_fees(&higher_fees).unwrap();
+
+        let mut lower_fees = fees.clone();
+        lower_fees.owner_trade_fee_numerator = owner_trade_fee_numerator - 1;
+        assert!(swap_constraints.validate_fees(&lower_fees).is_err());
+
+        let mut other_denominator = fees.clone();
+        other_denominator.trade_fee_denominator = trade_fee_denominator + 1;
+        assert!(swap_constraints.validate_fees(&other_denominator).is_err());
+
+        let mut other_host_fee = fees;
+        other_host_fee.host_fee_numerator = host_fee_numerator + 1;
+        assert!(swap_constraints.validate_fees(&other_host_fee).is_err());
+    }
+
+    #[test]
+    fn validate_curve() {
+        let swap_constraints = constraints(CurveFees::default());
+        let swap_curve = SwapCurve {
+            curve_type: CurveType::ConstantProduct,
+            calculator: Box::new(ConstantProductCurve {}),
+        };
+        swap_constraints.validate_curve(&swap_curve).unwrap();
+
+        let swap_constraints = SwapConstraints {
+            valid_curve_types: vec![CurveType::Stable as u8],
+            ..swap_constraints
+        };
+        assert!(swap_constraints.validate_curve(&swap_curve).is_err());
+    }
+
+    #[test]
+    fn validate_curve_types() {
+        SwapConstraints::validate_curve_types(&[0, 1, 2, 3]).unwrap();
+        assert!(SwapConstraints::validate_curve_types(&[0, 42]).is_err());
+        assert!(SwapConstraints::validate_curve_types(&[0u8; MAX_VALID_CURVE_TYPES + 1]).is_err());
+    }
+}
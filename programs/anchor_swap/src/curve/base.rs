@@ -7,13 +7,16 @@ use solana_program::{
 
 use crate::curve::{
     calculator::{CurveCalculator, SwapWithoutFeesResult, TradeDirection},
+    concentrated::ConcentratedCurve,
     constant_price::ConstantPriceCurve,
     constant_product::ConstantProductCurve,
-    fees::CurveFees,
+    fees::{CurveFees, ImbalanceFee},
     offset::OffsetCurve,
     stable::StableCurve,
+    weighted::WeightedCurve,
 };
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use spl_math::precise_number::PreciseNumber;
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Debug;
 
@@ -33,6 +36,10 @@ pub enum CurveType {
     Stable,
     /// Offset curve, like Uniswap, but the token B side has a faked offset
     Offset,
+    /// Balancer-style weighted product curve, invariant = token_a_amount ^ weight_a * token_b_amount ^ weight_b
+    Weighted,
+    /// Uniswap-style curve over virtual reserves, holding all liquidity within a price range
+    Concentrated,
 }
 
 /// Encodes all results of swapping from a source token to a destination token
@@ -50,6 +57,10 @@ pub struct SwapResult {
     pub trade_fee: u128,
     /// Amount of source tokens going to owner
     pub owner_fee: u128,
+    /// Trade fee charged, the effective one for dynamic fees, numerator
+    pub trade_fee_numerator: u64,
+    /// Trade fee charged, the effective one for dynamic fees, denominator
+    pub trade_fee_denominator: u64,
 }
 
 /// Concrete struct to wrap around the trait object which performs calculation.
@@ -66,7 +77,8 @@ pub struct SwapCurve {
 
 impl SwapCurve {
     /// Subtract fees and calculate how much destination token will be provided
-    /// given an amount of source token.
+    /// given an amount of source token. The trade fee is raised by
+    /// `imbalance_fee` for the reserves the trade leaves at the unraised fee.
     pub fn swap(
         &self,
         source_amount: u128,
@@ -74,6 +86,35 @@ impl SwapCurve {
         swap_destination_amount: u128,
         trade_direction: TradeDirection,
         fees: &CurveFees,
+        imbalance_fee: &ImbalanceFee,
+    ) -> Option<SwapResult> {
+        let result = self.swap_with_fees(
+            source_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+            fees,
+        )?;
+        if !imbalance_fee.is_enabled() {
+            return Some(result);
+        }
+        let fees = self.swap_imbalance_fees(fees, imbalance_fee, &result, trade_direction)?;
+        self.swap_with_fees(
+            source_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+            &fees,
+        )
+    }
+
+    fn swap_with_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+        fees: &CurveFees,
     ) -> Option<SwapResult> {
         // debit the fee to calculate the amount swapped
         let trade_fee = fees.trading_fee(source_amount)?;
@@ -101,10 +142,146 @@ impl SwapCurve {
             destination_amount_swapped,
             trade_fee,
             owner_fee,
+            trade_fee_numerator: fees.trade_fee_numerator,
+            trade_fee_denominator: fees.trade_fee_denominator,
+        })
+    }
+
+    /// Calculate how much source token, fees included, is required to get the
+    /// given amount of destination token, the trade fee being raised by
+    /// `imbalance_fee` as in `swap`.
+    pub fn swap_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+        fees: &CurveFees,
+        imbalance_fee: &ImbalanceFee,
+    ) -> Option<SwapResult> {
+        let result = self.swap_exact_out_with_fees(
+            destination_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+            fees,
+        )?;
+        if !imbalance_fee.is_enabled() {
+            return Some(result);
+        }
+        let fees = self.swap_imbalance_fees(fees, imbalance_fee, &result, trade_direction)?;
+        self.swap_exact_out_with_fees(
+            destination_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+            &fees,
+        )
+    }
+
+    fn swap_exact_out_with_fees(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+        fees: &CurveFees,
+    ) -> Option<SwapResult> {
+        let SwapWithoutFeesResult {
+            source_amount_swapped,
+            destination_amount_swapped,
+        } = self.calculator.swap_without_fees_exact_out(
+            destination_amount,
+            swap_source_amount,
+            swap_destination_amount,
+            trade_direction,
+        )?;
+
+        // gross up the amount swapped by the fees `swap` would debit from it
+        let source_amount_swapped = fees.pre_trading_fees_amount(source_amount_swapped)?;
+        let trade_fee = fees.trading_fee(source_amount_swapped)?;
+        let owner_fee = fees.owner_trading_fee(source_amount_swapped)?;
+
+        Some(SwapResult {
+            new_swap_source_amount: swap_source_amount.checked_add(source_amount_swapped)?,
+            new_swap_destination_amount: swap_destination_amount
+                .checked_sub(destination_amount_swapped)?,
+            source_amount_swapped,
+            destination_amount_swapped,
+            trade_fee,
+            owner_fee,
+            trade_fee_numerator: fees.trade_fee_numerator,
+            trade_fee_denominator: fees.trade_fee_denominator,
         })
     }
 
-    /// Get the amount of pool tokens for the deposited amount of token A or B
+    /// `fees` with the trade fee raised by `imbalance_fee` for a pool holding
+    /// the given reserves
+    fn imbalance_fees(
+        &self,
+        fees: &CurveFees,
+        imbalance_fee: &ImbalanceFee,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<CurveFees> {
+        if !imbalance_fee.is_enabled() {
+            return Some(fees.clone());
+        }
+        let imbalance = self
+            .calculator
+            .imbalance(swap_token_a_amount, swap_token_b_amount)?;
+        imbalance_fee.apply(fees, &imbalance)
+    }
+
+    /// `fees` raised by `imbalance_fee` for the reserves left by a swap
+    fn swap_imbalance_fees(
+        &self,
+        fees: &CurveFees,
+        imbalance_fee: &ImbalanceFee,
+        result: &SwapResult,
+        trade_direction: TradeDirection,
+    ) -> Option<CurveFees> {
+        match trade_direction {
+            TradeDirection::AtoB => self.imbalance_fees(
+                fees,
+                imbalance_fee,
+                result.new_swap_source_amount,
+                result.new_swap_destination_amount,
+            ),
+            TradeDirection::BtoA => self.imbalance_fees(
+                fees,
+                imbalance_fee,
+                result.new_swap_destination_amount,
+                result.new_swap_source_amount,
+            ),
+        }
+    }
+
+    /// Marginal price of token A in token B, quoted for a trade of a basis
+    /// point of the token A reserve
+    pub fn spot_price(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let probe_amount = std::cmp::max(1, swap_token_a_amount.checked_div(10_000)?);
+        let SwapWithoutFeesResult {
+            source_amount_swapped,
+            destination_amount_swapped,
+        } = self.calculator.swap_without_fees(
+            probe_amount,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            TradeDirection::AtoB,
+        )?;
+        PreciseNumber::new(destination_amount_swapped)?
+            .checked_div(&PreciseNumber::new(source_amount_swapped)?)
+    }
+
+    /// Get the amount of pool tokens for the deposited amount of token A or B,
+    /// the trade fee being raised by `imbalance_fee` for the reserves after the
+    /// deposit
+    #[allow(clippy::too_many_arguments)]
     pub fn deposit_single_token_type(
         &self,
         source_amount: u128,
@@ -113,10 +290,25 @@ impl SwapCurve {
         pool_supply: u128,
         trade_direction: TradeDirection,
         fees: &CurveFees,
+        imbalance_fee: &ImbalanceFee,
     ) -> Option<u128> {
         if source_amount == 0 {
             return Some(0);
         }
+        let fees = match trade_direction {
+            TradeDirection::AtoB => self.imbalance_fees(
+                fees,
+                imbalance_fee,
+                swap_token_a_amount.checked_add(source_amount)?,
+                swap_token_b_amount,
+            )?,
+            TradeDirection::BtoA => self.imbalance_fees(
+                fees,
+                imbalance_fee,
+                swap_token_a_amount,
+                swap_token_b_amount.checked_add(source_amount)?,
+            )?,
+        };
         // Get the trading fee incurred if *half* the source amount is swapped
         // for the other side. Reference at:
         // https://github.com/balancer-labs/balancer-core/blob/f4ed5d65362a8d6cec21662fb6eae233b0babc1f/contracts/BMath.sol#L117
@@ -132,7 +324,10 @@ impl SwapCurve {
         )
     }
 
-    /// Get the amount of pool tokens for the withdrawn amount of token A or B
+    /// Get the amount of pool tokens for the withdrawn amount of token A or B,
+    /// the trade fee being raised by `imbalance_fee` for the reserves after the
+    /// withdrawal
+    #[allow(clippy::too_many_arguments)]
     pub fn withdraw_single_token_type_exact_out(
         &self,
         source_amount: u128,
@@ -141,10 +336,25 @@ impl SwapCurve {
         pool_supply: u128,
         trade_direction: TradeDirection,
         fees: &CurveFees,
+        imbalance_fee: &ImbalanceFee,
     ) -> Option<u128> {
         if source_amount == 0 {
             return Some(0);
         }
+        let fees = match trade_direction {
+            TradeDirection::AtoB => self.imbalance_fees(
+                fees,
+                imbalance_fee,
+                swap_token_a_amount.checked_sub(source_amount)?,
+                swap_token_b_amount,
+            )?,
+            TradeDirection::BtoA => self.imbalance_fees(
+                fees,
+                imbalance_fee,
+                swap_token_a_amount,
+                swap_token_b_amount.checked_sub(source_amount)?,
+            )?,
+        };
         // Get the trading fee incurred if *half* the source amount is swapped
         // for the other side. Reference at:
         // https://github.com/balancer-labs/balancer-core/blob/f4ed5d65362a8d6cec21662fb6eae233b0babc1f/contracts/BMath.sol#L117
@@ -223,6 +433,10 @@ impl Pack for SwapCurve {
                 }
                 CurveType::Stable => Box::new(StableCurve::unpack_from_slice(calculator)?),
                 CurveType::Offset => Box::new(OffsetCurve::unpack_from_slice(calculator)?),
+                CurveType::Weighted => Box::new(WeightedCurve::unpack_from_slice(calculator)?),
+                CurveType::Concentrated => {
+                    Box::new(ConcentratedCurve::unpack_from_slice(calculator)?)
+                }
             },
         })
     }
@@ -253,6 +467,8 @@ impl TryFrom<u8> for CurveType {
             1 => Ok(CurveType::ConstantPrice),
             2 => Ok(CurveType::Stable),
             3 => Ok(CurveType::Offset),
+            4 => Ok(CurveType::Weighted),
+            5 => Ok(CurveType::Concentrated),
             _ => Err(ProgramError::InvalidAccountData),
         }
     }
@@ -319,6 +535,7 @@ mod tests {
                 swap_destination_amount,
                 TradeDirection::AtoB,
                 &fees,
+                &ImbalanceFee::default(),
             )
             .unwrap();
         assert_eq!(result.new_swap_source_amount, 1100);
@@ -364,6 +581,7 @@ mod tests {
                 swap_destination_amount,
                 TradeDirection::AtoB,
                 &fees,
+                &ImbalanceFee::default(),
             )
             .unwrap();
         assert_eq!(result.new_swap_source_amount, 1100);
@@ -391,10 +609,219 @@ mod tests {
                 swap_destination_amount,
                 TradeDirection::AtoB,
                 &fees,
+                &ImbalanceFee::default(),
             )
             .unwrap();
         assert_eq!(result.new_swap_source_amount, 1100);
         assert_eq!(result.destination_amount_swapped, 4545);
         assert_eq!(result.new_swap_destination_amount, 45455);
     }
+
+    #[test]
+    fn constant_product_exact_out_fee() {
+        let swap_source_amount: u128 = 1_000;
+        let swap_destination_amount: u128 = 50_000;
+        let fees = CurveFees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 100,
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 100,
+            ..CurveFees::default()
+        };
+        let swap_curve = SwapCurve {
+            curve_type: CurveType::ConstantProduct,
+            calculator: Box::new(ConstantProductCurve {}),
+        };
+        let result = swap_curve
+            .swap_exact_out(
+                4_504,
+                swap_source_amount,
+                swap_destination_amount,
+                TradeDirection::AtoB,
+                &fees,
+                &ImbalanceFee::default(),
+            )
+            .unwrap();
+        // 100 tokens reach the curve once the 2% in fees are taken out
+        assert_eq!(result.source_amount_swapped, 102);
+        assert_eq!(result.trade_fee, 1);
+        assert_eq!(result.owner_fee, 1);
+        assert_eq!(result.new_swap_source_amount, 1_102);
+        assert_eq!(result.new_swap_destination_amount, 45_496);
+
+        // the whole destination vault can never be bought
+        assert!(swap_curve
+            .swap_exact_out(
+                swap_destination_amount,
+                swap_source_amount,
+                swap_destination_amount,
+                TradeDirection::AtoB,
+                &fees,
+                &ImbalanceFee::default(),
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn swap_exact_out_inverts_swap() {
+        let fees = CurveFees {
+            trade_fee_numerator: 25,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 5,
+            owner_trade_fee_denominator: 10_000,
+            ..CurveFees::default()
+        };
+        let swap_curves = [
+            SwapCurve {
+                curve_type: CurveType::ConstantProduct,
+                calculator: Box::new(ConstantProductCurve {}),
+            },
+            SwapCurve {
+                curve_type: CurveType::ConstantPrice,
+                calculator: Box::new(ConstantPriceCurve::new(7)),
+            },
+            SwapCurve {
+                curve_type: CurveType::Offset,
+                calculator: Box::new(OffsetCurve {
+                    token_b_offset: 1_000_000,
+                }),
+            },
+            SwapCurve {
+                curve_type: CurveType::Stable,
+                calculator: Box::new(StableCurve::new(100)),
+            },
+            SwapCurve {
+                curve_type: CurveType::Weighted,
+                calculator: Box::new(WeightedCurve {
+                    token_a_weight: 80,
+                    token_b_weight: 20,
+                }),
+            },
+            SwapCurve {
+                curve_type: CurveType::Concentrated,
+                calculator: Box::new(ConcentratedCurve {
+                    sqrt_price_lower: 1 << 63,
+                    sqrt_price_upper: 1 << 65,
+                }),
+            },
+        ];
+        let swap_token_a_amount: u128 = 5_000_000;
+        let swap_token_b_amount: u128 = 3_000_000;
+        for swap_curve in swap_curves.iter() {
+            for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+                let (swap_source_amount, swap_destination_amount) = match trade_direction {
+                    TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
+                    TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
+                };
+                for destination_amount in [1, 777, 10_000, 250_000] {
+                    let exact_out = swap_curve
+                        .swap_exact_out(
+                            destination_amount,
+                            swap_source_amount,
+                            swap_destination_amount,
+                            trade_direction,
+                            &fees,
+                            &ImbalanceFee::default(),
+                        )
+                        .unwrap();
+                    assert_eq!(exact_out.destination_amount_swapped, destination_amount);
+
+                    // swapping the computed input gets at least the desired output
+                    let result = swap_curve
+                        .swap(
+                            exact_out.source_amount_swapped,
+                            swap_source_amount,
+                            swap_destination_amount,
+                            trade_direction,
+                            &fees,
+                            &ImbalanceFee::default(),
+                        )
+                        .unwrap();
+                    assert!(
+                        result.destination_amount_swapped >= destination_amount,
+                        "{:?} {:?} {}",
+                        swap_curve.curve_type,
+                        trade_direction,
+                        destination_amount
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn imbalance_fee_charges_unbalancing_trades_more() {
+        let fees = CurveFees {
+            trade_fee_numerator: 4,
+            trade_fee_denominator: 10_000,
+            ..CurveFees::default()
+        };
+        let imbalance_fee = ImbalanceFee::new(500).unwrap();
+        let swap_curve = SwapCurve {
+            curve_type: CurveType::Stable,
+            calculator: Box::new(StableCurve::new(100)),
+        };
+        // a pool already holding more token A than token B
+        let swap_token_a_amount: u128 = 1_500_000;
+        let swap_token_b_amount: u128 = 500_000;
+        let swap = |trade_direction, imbalance_fee: &ImbalanceFee| {
+            let (swap_source_amount, swap_destination_amount) = match trade_direction {
+                TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
+                TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
+            };
+            swap_curve
+                .swap(
+                    100_000,
+                    swap_source_amount,
+                    swap_destination_amount,
+                    trade_direction,
+                    &fees,
+                    imbalance_fee,
+                )
+                .unwrap()
+        };
+
+        let unbalancing = swap(TradeDirection::AtoB, &imbalance_fee);
+        let rebalancing = swap(TradeDirection::BtoA, &imbalance_fee);
+        assert!(unbalancing.trade_fee_numerator > rebalancing.trade_fee_numerator);
+        assert!(unbalancing.trade_fee > rebalancing.trade_fee);
+        // never below the unraised fee
+        let unraised = swap(TradeDirection::BtoA, &ImbalanceFee::default());
+        assert_eq!(unraised.trade_fee_numerator, fees.trade_fee_numerator);
+        assert!(rebalancing.trade_fee_numerator >= unraised.trade_fee_numerator);
+        assert!(rebalancing.destination_amount_swapped <= unraised.destination_amount_swapped);
+
+        // the exact out of a swap charges the same raised fee
+        let exact_out = swap_curve
+            .swap_exact_out(
+                unbalancing.destination_amount_swapped,
+                swap_token_a_amount,
+                swap_token_b_amount,
+                TradeDirection::AtoB,
+                &fees,
+                &imbalance_fee,
+            )
+            .unwrap();
+        assert_eq!(
+            exact_out.trade_fee_numerator,
+            unbalancing.trade_fee_numerator
+        );
+
+        // single-sided deposits of the abundant token earn fewer pool tokens
+        let pool_supply = 2_000_000;
+        let deposit = |imbalance_fee: &ImbalanceFee| {
+            swap_curve
+                .deposit_single_token_type(
+                    100_000,
+                    swap_token_a_amount,
+                    swap_token_b_amount,
+                    pool_supply,
+                    TradeDirection::AtoB,
+                    &fees,
+                    imbalance_fee,
+                )
+                .unwrap()
+        };
+        assert!(deposit(&imbalance_fee) < deposit(&ImbalanceFee::default()));
+    }
 }
@@ -15,6 +15,10 @@ pub const INITIAL_SWAP_POOL_AMOUNT: u128 = 1_000_000_000;
 /// equivalent pool tokens for the owner trading fee.
 pub const TOKENS_IN_POOL: u128 = 2;
 
+/// Largest number of token types in a pool, only stable pools holding more
+/// than `TOKENS_IN_POOL`
+pub const MAX_TOKENS_IN_POOL: usize = 4;
+
 /// Helper function for mapping to SwapError::CalculationFailure
 pub fn map_zero_to_none(x: u128) -> Option<u128> {
     if x == 0 {
@@ -95,6 +99,17 @@ pub trait CurveCalculator: Debug + DynPack {
         trade_direction: TradeDirection,
     ) -> Option<SwapWithoutFeesResult>;
 
+    /// Calculate how much source token is required to get the given amount
+    /// of destination token, the inverse of `swap_without_fees`. The source
+    /// amount is rounded up so that the pool never loses value.
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult>;
+
     /// Get the supply for a new pool
     /// The default implementation is a Balancer-style fixed initial supply
     fn new_pool_supply(&self) -> u128 {
@@ -173,6 +188,18 @@ pub trait CurveCalculator: Debug + DynPack {
         true
     }
 
+    /// Calculates how far the given reserves are from balance, from zero for
+    /// a balanced pool to about one as the pool nears holding a single token,
+    /// used to raise the trade fee of trades leaving the pool imbalanced.
+    /// Curves without a point of balance are always balanced.
+    fn imbalance(
+        &self,
+        _swap_token_a_amount: u128,
+        _swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        PreciseNumber::new(0)
+    }
+
     /// Calculates the total normalized value of the curve given the liquidity
     /// parameters.
     ///
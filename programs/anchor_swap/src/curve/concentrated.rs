@@ -0,0 +1,574 @@
+//! Concentrated liquidity calculator: the Uniswap invariant over virtual
+//! reserves, holding all of the pool's liquidity within a price range.
+
+use {
+    crate::{
+        curve::{
+            calculator::{
+                map_zero_to_none, CurveCalculator, DynPack, RoundDirection, SwapWithoutFeesResult,
+                TradeDirection, TradingTokenResult,
+            },
+            constant_product::pool_tokens_to_trading_tokens,
+        },
+        error::SwapError,
+    },
+    arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs},
+    solana_program::{
+        program_error::ProgramError,
+        program_pack::{IsInitialized, Pack, Sealed},
+    },
+    spl_math::{
+        precise_number::{PreciseNumber, ONE},
+        uint::U256,
+    },
+    std::convert::TryFrom,
+};
+
+/// Error bound of the computed liquidity, relative, in units of 10^-12.
+/// Virtual reserves and liquidity ratios are moved by it in the pool's favor.
+const LIQUIDITY_ERROR: u128 = 100;
+
+/// ConcentratedCurve struct implementing CurveCalculator
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConcentratedCurve {
+    /// Square root of the lowest price of token A in token B, as a Q64.64
+    pub sqrt_price_lower: u128,
+    /// Square root of the highest price of token A in token B, as a Q64.64
+    pub sqrt_price_upper: u128,
+}
+
+/// Converts a Q64.64 square root price to a precise number
+fn sqrt_price(sqrt_price_x64: u128) -> Option<PreciseNumber> {
+    let value = U256::from(sqrt_price_x64).checked_mul(U256::from(ONE))? >> 64;
+    Some(PreciseNumber { value })
+}
+
+/// Square root of a precise number of any size, since `PreciseNumber::sqrt`
+/// only takes values up to u128::MAX
+fn checked_sqrt(value: &PreciseNumber) -> Option<PreciseNumber> {
+    let max_sqrt_base = PreciseNumber::new(u128::MAX)?;
+    let scale = PreciseNumber::new(1u128 << 64)?;
+    let scale_squared = scale.checked_mul(&scale)?;
+    let mut value = value.clone();
+    let mut root_scale = PreciseNumber::new(1)?;
+    while value.greater_than(&max_sqrt_base) {
+        value = value.checked_div(&scale_squared)?;
+        root_scale = root_scale.checked_mul(&scale)?;
+    }
+    value.sqrt()?.checked_mul(&root_scale)
+}
+
+/// Error bound of a value derived from the liquidity
+fn liquidity_error(value: &PreciseNumber) -> Option<PreciseNumber> {
+    value.checked_mul(&PreciseNumber {
+        value: U256::from(LIQUIDITY_ERROR),
+    })
+}
+
+/// Division of two U256, rounded up
+fn ceil_div(numerator: U256, denominator: U256) -> Option<U256> {
+    numerator
+        .checked_add(denominator.checked_sub(U256::one())?)?
+        .checked_div(denominator)
+}
+
+impl ConcentratedCurve {
+    /// Liquidity `L` of the pool, solving
+    /// (token_a + L / sqrt_price_upper) * (token_b + L * sqrt_price_lower) = L ^ 2
+    /// which is the quadratic:
+    /// (sqrt_upper - sqrt_lower) L ^ 2 - (token_a sqrt_lower sqrt_upper + token_b) L - token_a token_b sqrt_upper = 0
+    pub fn liquidity(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let sqrt_price_lower = sqrt_price(self.sqrt_price_lower)?;
+        let sqrt_price_upper = sqrt_price(self.sqrt_price_upper)?;
+        let swap_token_a_amount = PreciseNumber::new(swap_token_a_amount)?;
+        let swap_token_b_amount = PreciseNumber::new(swap_token_b_amount)?;
+
+        let a = sqrt_price_upper.checked_sub(&sqrt_price_lower)?;
+        let b = swap_token_a_amount
+            .checked_mul(&sqrt_price_lower)?
+            .checked_mul(&sqrt_price_upper)?
+            .checked_add(&swap_token_b_amount)?;
+        let c = swap_token_a_amount
+            .checked_mul(&swap_token_b_amount)?
+            .checked_mul(&sqrt_price_upper)?;
+        let four = PreciseNumber::new(4)?;
+        let two = PreciseNumber::new(2)?;
+        let discriminant = b
+            .checked_mul(&b)?
+            .checked_add(&four.checked_mul(&a)?.checked_mul(&c)?)?;
+        b.checked_add(&checked_sqrt(&discriminant)?)?
+            .checked_div(&two.checked_mul(&a)?)
+    }
+
+    /// Virtual reserves of the source and destination tokens of a trade:
+    /// token A is offset by `L / sqrt_price_upper` and token B by
+    /// `L * sqrt_price_lower`. The source offset is rounded up and the
+    /// destination offset down, so that the pool never gives a better price.
+    fn virtual_reserves(
+        &self,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<(U256, U256)> {
+        let (swap_token_a_amount, swap_token_b_amount) = match trade_direction {
+            TradeDirection::AtoB => (swap_source_amount, swap_destination_amount),
+            TradeDirection::BtoA => (swap_destination_amount, swap_source_amount),
+        };
+        let liquidity = self.liquidity(swap_token_a_amount, swap_token_b_amount)?;
+        let error = liquidity_error(&liquidity)?;
+        let liquidity_up = liquidity.checked_add(&error)?;
+        let (liquidity_down, _) = liquidity.unsigned_sub(&error);
+
+        let token_a_offset =
+            |liquidity: &PreciseNumber| liquidity.checked_div(&sqrt_price(self.sqrt_price_upper)?);
+        let token_b_offset =
+            |liquidity: &PreciseNumber| liquidity.checked_mul(&sqrt_price(self.sqrt_price_lower)?);
+        let (source_offset, destination_offset) = match trade_direction {
+            TradeDirection::AtoB => (
+                token_a_offset(&liquidity_up)?,
+                token_b_offset(&liquidity_down)?,
+            ),
+            TradeDirection::BtoA => (
+                token_b_offset(&liquidity_up)?,
+                token_a_offset(&liquidity_down)?,
+            ),
+        };
+        let source_offset = source_offset.ceiling()?.to_imprecise()?;
+        let destination_offset = destination_offset.floor()?.to_imprecise()?;
+        Some((
+            U256::from(swap_source_amount).checked_add(U256::from(source_offset))?,
+            U256::from(swap_destination_amount).checked_add(U256::from(destination_offset))?,
+        ))
+    }
+
+    /// Ratio of the liquidity after a single-sided deposit or withdrawal to the
+    /// liquidity before, rounded down by its error bound
+    fn liquidity_ratio(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        new_swap_token_a_amount: u128,
+        new_swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let liquidity = self.liquidity(swap_token_a_amount, swap_token_b_amount)?;
+        let new_liquidity = self.liquidity(new_swap_token_a_amount, new_swap_token_b_amount)?;
+        let ratio = new_liquidity.checked_div(&liquidity)?;
+        let (ratio, _) = ratio.unsigned_sub(&liquidity_error(&ratio)?);
+        Some(ratio)
+    }
+}
+
+impl CurveCalculator for ConcentratedCurve {
+    /// Constant product swap over the virtual reserves. Once the trade moves
+    /// the price to the bound of the range, the real destination reserve is
+    /// empty: the whole of it is paid out, and only the source amount needed
+    /// to reach the bound is taken.
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        let (virtual_source_amount, virtual_destination_amount) =
+            self.virtual_reserves(swap_source_amount, swap_destination_amount, trade_direction)?;
+        let invariant = virtual_source_amount.checked_mul(virtual_destination_amount)?;
+
+        let new_virtual_source_amount =
+            virtual_source_amount.checked_add(U256::from(source_amount))?;
+        let new_virtual_destination_amount = ceil_div(invariant, new_virtual_source_amount)?;
+        let destination_amount_swapped =
+            virtual_destination_amount.checked_sub(new_virtual_destination_amount)?;
+
+        let (source_amount_swapped, destination_amount_swapped) = if destination_amount_swapped
+            > U256::from(swap_destination_amount)
+        {
+            let new_virtual_destination_amount =
+                virtual_destination_amount.checked_sub(U256::from(swap_destination_amount))?;
+            let new_virtual_source_amount = ceil_div(invariant, new_virtual_destination_amount)?;
+            (
+                u128::try_from(new_virtual_source_amount.checked_sub(virtual_source_amount)?)
+                    .ok()?,
+                swap_destination_amount,
+            )
+        } else {
+            (
+                source_amount,
+                u128::try_from(destination_amount_swapped).ok()?,
+            )
+        };
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: map_zero_to_none(source_amount_swapped)?,
+            destination_amount_swapped: map_zero_to_none(destination_amount_swapped)?,
+        })
+    }
+
+    /// Inverse of the constant product swap over the virtual reserves, up to
+    /// the whole real destination reserve
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        if destination_amount > swap_destination_amount {
+            return None;
+        }
+        let (virtual_source_amount, virtual_destination_amount) =
+            self.virtual_reserves(swap_source_amount, swap_destination_amount, trade_direction)?;
+        let invariant = virtual_source_amount.checked_mul(virtual_destination_amount)?;
+
+        let new_virtual_destination_amount =
+            virtual_destination_amount.checked_sub(U256::from(destination_amount))?;
+        if new_virtual_destination_amount.is_zero() {
+            return None;
+        }
+        let new_virtual_source_amount = ceil_div(invariant, new_virtual_destination_amount)?;
+        let source_amount_swapped =
+            u128::try_from(new_virtual_source_amount.checked_sub(virtual_source_amount)?).ok()?;
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: map_zero_to_none(source_amount_swapped)?,
+            destination_amount_swapped: map_zero_to_none(destination_amount)?,
+        })
+    }
+
+    /// Both tokens are withdrawn in proportion to the pool's real reserves, as
+    /// for the constant product curve, which scales the liquidity by the same
+    /// proportion
+    fn pool_tokens_to_trading_tokens(
+        &self,
+        pool_tokens: u128,
+        pool_token_supply: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        round_direction: RoundDirection,
+    ) -> Option<TradingTokenResult> {
+        pool_tokens_to_trading_tokens(
+            pool_tokens,
+            pool_token_supply,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            round_direction,
+        )
+    }
+
+    /// Pool tokens are minted in proportion to the liquidity added by the
+    /// deposit, which is the constant product result for an infinite range:
+    ///
+    /// pool_tokens = pool_supply * (new_liquidity / liquidity - 1)
+    fn deposit_single_token_type(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        let (new_swap_token_a_amount, new_swap_token_b_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                swap_token_a_amount.checked_add(source_amount)?,
+                swap_token_b_amount,
+            ),
+            TradeDirection::BtoA => (
+                swap_token_a_amount,
+                swap_token_b_amount.checked_add(source_amount)?,
+            ),
+        };
+        let ratio = self.liquidity_ratio(
+            swap_token_a_amount,
+            swap_token_b_amount,
+            new_swap_token_a_amount,
+            new_swap_token_b_amount,
+        )?;
+        let one = PreciseNumber::new(1)?;
+        if ratio.less_than_or_equal(&one) {
+            return Some(0);
+        }
+        PreciseNumber::new(pool_supply)?
+            .checked_mul(&ratio.checked_sub(&one)?)?
+            .floor()?
+            .to_imprecise()
+    }
+
+    /// Pool tokens are burnt in proportion to the liquidity removed by the
+    /// withdrawal:
+    ///
+    /// pool_tokens = pool_supply * (1 - new_liquidity / liquidity)
+    fn withdraw_single_token_type_exact_out(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        if source_amount == 0 {
+            return Some(0);
+        }
+        let (new_swap_token_a_amount, new_swap_token_b_amount) = match trade_direction {
+            TradeDirection::AtoB => (
+                swap_token_a_amount.checked_sub(source_amount)?,
+                swap_token_b_amount,
+            ),
+            TradeDirection::BtoA => (
+                swap_token_a_amount,
+                swap_token_b_amount.checked_sub(source_amount)?,
+            ),
+        };
+        let ratio = self.liquidity_ratio(
+            swap_token_a_amount,
+            swap_token_b_amount,
+            new_swap_token_a_amount,
+            new_swap_token_b_amount,
+        )?;
+        PreciseNumber::new(pool_supply)?
+            .checked_mul(&PreciseNumber::new(1)?.checked_sub(&ratio)?)?
+            .ceiling()?
+            .to_imprecise()
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        let sqrt_price_lower = sqrt_price(self.sqrt_price_lower).ok_or(SwapError::InvalidCurve)?;
+        if sqrt_price_lower.value.is_zero() || self.sqrt_price_lower >= self.sqrt_price_upper {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// At a bound of the range, the pool only holds one of the tokens
+    fn validate_supply(&self, token_a_amount: u64, token_b_amount: u64) -> Result<(), SwapError> {
+        if token_a_amount == 0 && token_b_amount == 0 {
+            return Err(SwapError::EmptySupply);
+        }
+        Ok(())
+    }
+
+    /// The liquidity, which is the square root of the invariant over the
+    /// virtual reserves, like the constant product's normalized value
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        self.liquidity(swap_token_a_amount, swap_token_b_amount)
+    }
+}
+
+/// IsInitialized is required to use `Pack::pack` and `Pack::unpack`
+impl IsInitialized for ConcentratedCurve {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+}
+impl Sealed for ConcentratedCurve {}
+impl Pack for ConcentratedCurve {
+    const LEN: usize = 32;
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        (self as &dyn DynPack).pack_into_slice(output);
+    }
+
+    fn unpack_from_slice(input: &[u8]) -> Result<ConcentratedCurve, ProgramError> {
+        let input = array_ref![input, 0, 32];
+        let (sqrt_price_lower, sqrt_price_upper) = array_refs![input, 16, 16];
+        Ok(Self {
+            sqrt_price_lower: u128::from_le_bytes(*sqrt_price_lower),
+            sqrt_price_upper: u128::from_le_bytes(*sqrt_price_upper),
+        })
+    }
+}
+
+impl DynPack for ConcentratedCurve {
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, 32];
+        let (sqrt_price_lower, sqrt_price_upper) = mut_array_refs![output, 16, 16];
+        *sqrt_price_lower = self.sqrt_price_lower.to_le_bytes();
+        *sqrt_price_upper = self.sqrt_price_upper.to_le_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::constant_product::{self, ConstantProductCurve};
+
+    /// Q64.64 square root price of 2 ^ exponent
+    fn sqrt_price_pow2(exponent: i32) -> u128 {
+        1u128 << (64 + exponent)
+    }
+
+    /// Range of prices [1 / 4, 4], around the price of 1
+    fn narrow_curve() -> ConcentratedCurve {
+        ConcentratedCurve {
+            sqrt_price_lower: sqrt_price_pow2(-1),
+            sqrt_price_upper: sqrt_price_pow2(1),
+        }
+    }
+
+    /// Range of prices [2 ^ -40, 2 ^ 40], close to the constant product curve
+    fn wide_curve() -> ConcentratedCurve {
+        ConcentratedCurve {
+            sqrt_price_lower: sqrt_price_pow2(-20),
+            sqrt_price_upper: sqrt_price_pow2(20),
+        }
+    }
+
+    #[test]
+    fn liquidity() {
+        // a pool at the price of 1 in the [1 / 4, 4] range: with L = 2 x,
+        // (x + 2 x / 2) * (x + 2 x / 2) = (2 x) ^ 2
+        let precision = U256::from(ONE / 1_000_000);
+        let liquidity = narrow_curve().liquidity(1_000_000, 1_000_000).unwrap();
+        assert!(liquidity.almost_eq(&PreciseNumber::new(2_000_000).unwrap(), precision));
+
+        // only token B at the upper bound: L = y / (sqrt_upper - sqrt_lower)
+        let liquidity = narrow_curve().liquidity(0, 1_500_000).unwrap();
+        assert!(liquidity.almost_eq(&PreciseNumber::new(1_000_000).unwrap(), precision));
+    }
+
+    #[test]
+    fn wide_range_matches_constant_product() {
+        let curve = wide_curve();
+        let swap_source_amount = 1_000_000_000;
+        let swap_destination_amount = 50_000_000_000;
+        for source_amount in [1_000, 100_000, 10_000_000] {
+            let result = curve
+                .swap_without_fees(
+                    source_amount,
+                    swap_source_amount,
+                    swap_destination_amount,
+                    TradeDirection::AtoB,
+                )
+                .unwrap();
+            let expected =
+                constant_product::swap(source_amount, swap_source_amount, swap_destination_amount)
+                    .unwrap();
+            let difference = result
+                .destination_amount_swapped
+                .max(expected.destination_amount_swapped)
+                - result
+                    .destination_amount_swapped
+                    .min(expected.destination_amount_swapped);
+            assert!(difference * 10_000 <= expected.destination_amount_swapped);
+        }
+
+        let pool_supply = 1_000_000_000;
+        let deposited = curve
+            .deposit_single_token_type(
+                1_000_000,
+                swap_source_amount,
+                swap_destination_amount,
+                pool_supply,
+                TradeDirection::AtoB,
+            )
+            .unwrap();
+        let expected = ConstantProductCurve {}
+            .deposit_single_token_type(
+                1_000_000,
+                swap_source_amount,
+                swap_destination_amount,
+                pool_supply,
+                TradeDirection::AtoB,
+            )
+            .unwrap();
+        let difference = deposited.max(expected) - deposited.min(expected);
+        assert!(difference * 10_000 <= expected);
+    }
+
+    #[test]
+    fn narrow_range_concentrates_liquidity() {
+        let curve = narrow_curve();
+        let result = curve
+            .swap_without_fees(10_000, 1_000_000, 1_000_000, TradeDirection::AtoB)
+            .unwrap();
+        let constant_product = constant_product::swap(10_000, 1_000_000, 1_000_000).unwrap();
+        // virtual reserves of 2M on each side
+        assert_eq!(result.destination_amount_swapped, 9_950);
+        assert!(result.destination_amount_swapped > constant_product.destination_amount_swapped);
+    }
+
+    #[test]
+    fn swap_to_bound() {
+        let curve = narrow_curve();
+        let swap_token_a_amount = 1_000_000;
+        let swap_token_b_amount = 1_000_000;
+        let liquidity = curve
+            .liquidity(swap_token_a_amount, swap_token_b_amount)
+            .unwrap();
+
+        // selling far more token A than the range holds pays out all of token B
+        let result = curve
+            .swap_without_fees(
+                10_000_000,
+                swap_token_a_amount,
+                swap_token_b_amount,
+                TradeDirection::AtoB,
+            )
+            .unwrap();
+        assert_eq!(result.destination_amount_swapped, swap_token_b_amount);
+        // the pool only takes the token A needed to reach the lower bound,
+        // where it holds L / sqrt_lower - L / sqrt_upper = 3M token A
+        assert!(result.source_amount_swapped < 10_000_000);
+        let new_swap_token_a_amount = swap_token_a_amount + result.source_amount_swapped;
+        assert!((3_000_000..=3_000_010).contains(&new_swap_token_a_amount));
+        let new_liquidity = curve.liquidity(new_swap_token_a_amount, 0).unwrap();
+        assert!(new_liquidity.greater_than_or_equal(&liquidity));
+
+        // nothing left to sell at the bound
+        assert!(curve
+            .swap_without_fees(1_000, new_swap_token_a_amount, 0, TradeDirection::AtoB)
+            .is_none());
+        let result = curve
+            .swap_without_fees(1_000, 0, new_swap_token_a_amount, TradeDirection::BtoA)
+            .unwrap();
+        assert!(result.destination_amount_swapped > 0);
+
+        // the exact out swap stops at the bound too
+        assert!(curve
+            .swap_without_fees_exact_out(
+                swap_token_b_amount + 1,
+                swap_token_a_amount,
+                swap_token_b_amount,
+                TradeDirection::AtoB,
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn pack_curve() {
+        let curve = narrow_curve();
+
+        let mut packed = [0u8; ConcentratedCurve::LEN];
+        Pack::pack_into_slice(&curve, &mut packed[..]);
+        let unpacked = ConcentratedCurve::unpack(&packed).unwrap();
+        assert_eq!(curve, unpacked);
+
+        let mut packed = vec![];
+        packed.extend_from_slice(&sqrt_price_pow2(-1).to_le_bytes());
+        packed.extend_from_slice(&sqrt_price_pow2(1).to_le_bytes());
+        let unpacked = ConcentratedCurve::unpack(&packed).unwrap();
+        assert_eq!(curve, unpacked);
+    }
+
+    #[test]
+    fn validate_range() {
+        narrow_curve().validate().unwrap();
+        let curve = ConcentratedCurve {
+            sqrt_price_lower: sqrt_price_pow2(1),
+            sqrt_price_upper: sqrt_price_pow2(-1),
+        };
+        assert!(curve.validate().is_err());
+        let curve = ConcentratedCurve {
+            sqrt_price_lower: 1,
+            sqrt_price_upper: sqrt_price_pow2(1),
+        };
+        assert!(curve.validate().is_err());
+    }
+}
@@ -7,21 +7,50 @@ use {
         },
         error::SwapError,
     },
-    arrayref::{array_mut_ref, array_ref},
+    arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs},
     solana_program::{
         program_error::ProgramError,
         program_pack::{IsInitialized, Pack, Sealed},
     },
     spl_math::{checked_ceil_div::CheckedCeilDiv, precise_number::PreciseNumber, uint::U256},
+    std::convert::TryFrom,
 };
 
+/// `value * numerator / denominator`, rounded in the given direction
+fn mul_div(
+    value: u128,
+    numerator: u64,
+    denominator: u64,
+    round_direction: RoundDirection,
+) -> Option<u128> {
+    let product = U256::from(value).checked_mul(U256::from(numerator))?;
+    let denominator = U256::from(denominator);
+    let quotient = match round_direction {
+        RoundDirection::Floor => product.checked_div(denominator)?,
+        RoundDirection::Ceiling => {
+            let quotient = product.checked_div(denominator)?;
+            if product.checked_rem(denominator)?.is_zero() {
+                quotient
+            } else {
+                quotient.checked_add(U256::one())?
+            }
+        }
+    };
+    u128::try_from(quotient).ok()
+}
+
 /// Get the amount of pool tokens for the given amount of token A or B.
 ///
 /// The constant product implementation uses the Balancer formulas found at
 /// <https://balancer.finance/whitepaper/#single-asset-deposit>, specifically
 /// in the case for 2 tokens, each weighted at 1/2.
+///
+/// Values are compared in token A, scaled by the price denominator so the
+/// division only happens once.
+#[allow(clippy::too_many_arguments)]
 pub fn trading_tokens_to_pool_tokens(
     token_b_price: u64,
+    token_b_price_denominator: u64,
     source_amount: u128,
     swap_token_a_amount: u128,
     swap_token_b_amount: u128,
@@ -30,40 +59,77 @@ pub fn trading_tokens_to_pool_tokens(
     round_direction: RoundDirection,
 ) -> Option<u128> {
     let token_b_price = U256::from(token_b_price);
+    let token_b_price_denominator = U256::from(token_b_price_denominator);
     let given_value = match trade_direction {
-        TradeDirection::AtoB => U256::from(source_amount),
+        TradeDirection::AtoB => U256::from(source_amount).checked_mul(token_b_price_denominator)?,
         TradeDirection::BtoA => U256::from(source_amount).checked_mul(token_b_price)?,
     };
     let total_value = U256::from(swap_token_b_amount)
         .checked_mul(token_b_price)?
-        .checked_add(U256::from(swap_token_a_amount))?;
+        .checked_add(U256::from(swap_token_a_amount).checked_mul(token_b_price_denominator)?)?;
     let pool_supply = U256::from(pool_supply);
-    match round_direction {
-        RoundDirection::Floor => Some(
-            pool_supply
-                .checked_mul(given_value)?
-                .checked_div(total_value)?
-                .as_u128(),
-        ),
-        RoundDirection::Ceiling => Some(
+    let pool_tokens = match round_direction {
+        RoundDirection::Floor => pool_supply
+            .checked_mul(given_value)?
+            .checked_div(total_value)?,
+        RoundDirection::Ceiling => {
             pool_supply
                 .checked_mul(given_value)?
                 .checked_ceil_div(total_value)?
                 .0
-                .as_u128(),
-        ),
-    }
+        }
+    };
+    u128::try_from(pool_tokens).ok()
 }
 
 /// ConstantPriceCurve struct implementing CurveCalculator
-#[derive(Clone, Debug, Default, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ConstantPriceCurve {
-    /// Amount of token A required to get 1 token B
+    /// Amount of token A required to get `token_b_price_denominator` token B
     pub token_b_price: u64,
+    /// Amount of token B bought by `token_b_price` token A, 1 for a whole price
+    pub token_b_price_denominator: u64,
+}
+
+impl Default for ConstantPriceCurve {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl ConstantPriceCurve {
+    /// Curve at a whole price of `token_b_price` token A for 1 token B
+    pub fn new(token_b_price: u64) -> Self {
+        Self {
+            token_b_price,
+            token_b_price_denominator: 1,
+        }
+    }
+
+    /// Amount of token A worth `token_b_amount` token B
+    fn token_b_value(&self, token_b_amount: u128, round_direction: RoundDirection) -> Option<u128> {
+        mul_div(
+            token_b_amount,
+            self.token_b_price,
+            self.token_b_price_denominator,
+            round_direction,
+        )
+    }
+
+    /// Amount of token B worth `token_a_amount` token A
+    fn token_a_value(&self, token_a_amount: u128, round_direction: RoundDirection) -> Option<u128> {
+        mul_div(
+            token_a_amount,
+            self.token_b_price_denominator,
+            self.token_b_price,
+            round_direction,
+        )
+    }
 }
 
 impl CurveCalculator for ConstantPriceCurve {
-    /// Constant price curve always returns 1:1
+    /// Constant price curve always trades at the price, rounding down the
+    /// destination amount & taking only the source amount it is worth
     fn swap_without_fees(
         &self,
         source_amount: u128,
@@ -71,22 +137,24 @@ impl CurveCalculator for ConstantPriceCurve {
         _swap_destination_amount: u128,
         trade_direction: TradeDirection,
     ) -> Option<SwapWithoutFeesResult> {
-        let token_b_price = self.token_b_price as u128;
-
         let (source_amount_swapped, destination_amount_swapped) = match trade_direction {
-            TradeDirection::BtoA => (source_amount, source_amount.checked_mul(token_b_price)?),
+            TradeDirection::BtoA => {
+                let destination_amount_swapped =
+                    self.token_b_value(source_amount, RoundDirection::Floor)?;
+                // if there is a remainder from buying token A, only take the
+                // token B it is worth, but don't recalculate the fees
+                let source_amount_swapped =
+                    self.token_a_value(destination_amount_swapped, RoundDirection::Ceiling)?;
+                (source_amount_swapped, destination_amount_swapped)
+            }
             TradeDirection::AtoB => {
-                let destination_amount_swapped = source_amount.checked_div(token_b_price)?;
-                let mut source_amount_swapped = source_amount;
-
+                let destination_amount_swapped =
+                    self.token_a_value(source_amount, RoundDirection::Floor)?;
                 // if there is a remainder from buying token B, floor
                 // token_a_amount to avoid taking too many tokens, but
                 // don't recalculate the fees
-                let remainder = source_amount_swapped.checked_rem(token_b_price)?;
-                if remainder > 0 {
-                    source_amount_swapped = source_amount.checked_sub(remainder)?;
-                }
-
+                let source_amount_swapped =
+                    self.token_b_value(destination_amount_swapped, RoundDirection::Ceiling)?;
                 (source_amount_swapped, destination_amount_swapped)
             }
         };
@@ -98,6 +166,31 @@ impl CurveCalculator for ConstantPriceCurve {
         })
     }
 
+    /// Inverse of the constant price swap, rounding up the source amount
+    /// required
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        _swap_source_amount: u128,
+        _swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        let source_amount_swapped = match trade_direction {
+            TradeDirection::AtoB => {
+                self.token_b_value(destination_amount, RoundDirection::Ceiling)?
+            }
+            TradeDirection::BtoA => {
+                self.token_a_value(destination_amount, RoundDirection::Ceiling)?
+            }
+        };
+        let source_amount_swapped = map_zero_to_none(source_amount_swapped)?;
+        let destination_amount_swapped = map_zero_to_none(destination_amount)?;
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped,
+            destination_amount_swapped,
+        })
+    }
+
     /// Get the amount of trading tokens for the given amount of pool tokens,
     /// provided the total trading tokens and supply of pool tokens.
     /// For the constant price curve, the total value of the pool is weighted
@@ -110,37 +203,28 @@ impl CurveCalculator for ConstantPriceCurve {
         swap_token_b_amount: u128,
         round_direction: RoundDirection,
     ) -> Option<TradingTokenResult> {
-        let token_b_price = self.token_b_price as u128;
-        let total_value = self
-            .normalized_value(swap_token_a_amount, swap_token_b_amount)?
-            .to_imprecise()?;
-
-        let (token_a_amount, token_b_amount) = match round_direction {
-            RoundDirection::Floor => {
-                let token_a_amount = pool_tokens
-                    .checked_mul(total_value)?
-                    .checked_div(pool_token_supply)?;
-                let token_b_amount = pool_tokens
-                    .checked_mul(total_value)?
-                    .checked_div(token_b_price)?
-                    .checked_div(pool_token_supply)?;
-                (token_a_amount, token_b_amount)
-            }
-            RoundDirection::Ceiling => {
-                let (token_a_amount, _) = pool_tokens
-                    .checked_mul(total_value)?
-                    .checked_ceil_div(pool_token_supply)?;
-                let (pool_value_as_token_b, _) = pool_tokens
-                    .checked_mul(total_value)?
-                    .checked_ceil_div(token_b_price)?;
-                let (token_b_amount, _) =
-                    pool_value_as_token_b.checked_ceil_div(pool_token_supply)?;
-                (token_a_amount, token_b_amount)
-            }
+        // Half the pool's value in token A, scaled by the price denominator,
+        // is owed on each side
+        let total_value = U256::from(swap_token_b_amount)
+            .checked_mul(U256::from(self.token_b_price))?
+            .checked_add(
+                U256::from(swap_token_a_amount)
+                    .checked_mul(U256::from(self.token_b_price_denominator))?,
+            )?;
+        let pool_value = U256::from(pool_tokens).checked_mul(total_value)?;
+        let share = |denominator: U256| -> Option<u128> {
+            let denominator = denominator
+                .checked_mul(U256::from(2))?
+                .checked_mul(U256::from(pool_token_supply))?;
+            let amount = match round_direction {
+                RoundDirection::Floor => pool_value.checked_div(denominator)?,
+                RoundDirection::Ceiling => pool_value.checked_ceil_div(denominator)?.0,
+            };
+            u128::try_from(amount).ok()
         };
         Some(TradingTokenResult {
-            token_a_amount,
-            token_b_amount,
+            token_a_amount: share(U256::from(self.token_b_price_denominator))?,
+            token_b_amount: share(U256::from(self.token_b_price))?,
         })
     }
 
@@ -157,6 +241,7 @@ impl CurveCalculator for ConstantPriceCurve {
     ) -> Option<u128> {
         trading_tokens_to_pool_tokens(
             self.token_b_price,
+            self.token_b_price_denominator,
             source_amount,
             swap_token_a_amount,
             swap_token_b_amount,
@@ -176,6 +261,7 @@ impl CurveCalculator for ConstantPriceCurve {
     ) -> Option<u128> {
         trading_tokens_to_pool_tokens(
             self.token_b_price,
+            self.token_b_price_denominator,
             source_amount,
             swap_token_a_amount,
             swap_token_b_amount,
@@ -186,17 +272,26 @@ impl CurveCalculator for ConstantPriceCurve {
     }
 
     fn validate(&self) -> Result<(), SwapError> {
-        if self.token_b_price == 0 {
+        if self.token_b_price == 0 || self.token_b_price_denominator == 0 {
             Err(SwapError::InvalidCurve)
         } else {
             Ok(())
         }
     }
 
-    fn validate_supply(&self, token_a_amount: u64, _token_b_amount: u64) -> Result<(), SwapError> {
+    /// Token A MUST be supplied, and the supply be worth at least one token
+    /// on each side once normalized
+    fn validate_supply(&self, token_a_amount: u64, token_b_amount: u64) -> Result<(), SwapError> {
         if token_a_amount == 0 {
             return Err(SwapError::EmptySupply);
         }
+        let value = self
+            .normalized_value(token_a_amount.into(), token_b_amount.into())
+            .and_then(|value| value.to_imprecise())
+            .ok_or(SwapError::CalculationFailure)?;
+        if value == 0 {
+            return Err(SwapError::EmptySupply);
+        }
         Ok(())
     }
 
@@ -208,26 +303,18 @@ impl CurveCalculator for ConstantPriceCurve {
     /// ie. `token_a + token_b`.
     ///
     /// At the end, we divide by 2 to normalize the value between the two token
-    /// types.
+    /// types. The value of token B is rounded down, never overvaluing the pool.
     fn normalized_value(
         &self,
         swap_token_a_amount: u128,
         swap_token_b_amount: u128,
     ) -> Option<PreciseNumber> {
-        let swap_token_b_value = swap_token_b_amount.checked_mul(self.token_b_price as u128)?;
-        // special logic in case we're close to the limits, avoid overflowing u128
-        let value = if swap_token_b_value.saturating_sub(std::u64::MAX.into())
-            > (std::u128::MAX.saturating_sub(std::u64::MAX.into()))
-        {
-            swap_token_b_value
-                .checked_div(2)?
-                .checked_add(swap_token_a_amount.checked_div(2)?)?
-        } else {
-            swap_token_a_amount
-                .checked_add(swap_token_b_value)?
-                .checked_div(2)?
-        };
-        PreciseNumber::new(value)
+        let value = U256::from(swap_token_b_amount)
+            .checked_mul(U256::from(self.token_b_price))?
+            .checked_div(U256::from(self.token_b_price_denominator))?
+            .checked_add(U256::from(swap_token_a_amount))?
+            .checked_div(U256::from(2))?;
+        PreciseNumber::new(u128::try_from(value).ok()?)
     }
 }
 
@@ -239,23 +326,33 @@ impl IsInitialized for ConstantPriceCurve {
 }
 impl Sealed for ConstantPriceCurve {}
 impl Pack for ConstantPriceCurve {
-    const LEN: usize = 8;
+    const LEN: usize = 16;
     fn pack_into_slice(&self, output: &mut [u8]) {
         (self as &dyn DynPack).pack_into_slice(output);
     }
 
     fn unpack_from_slice(input: &[u8]) -> Result<ConstantPriceCurve, ProgramError> {
-        let token_b_price = array_ref![input, 0, 8];
+        let input = array_ref![input, 0, 16];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (token_b_price, token_b_price_denominator) = array_refs![input, 8, 8];
+        // Curves packed before rational prices only hold a whole price
+        let token_b_price_denominator = match u64::from_le_bytes(*token_b_price_denominator) {
+            0 => 1,
+            denominator => denominator,
+        };
         Ok(Self {
             token_b_price: u64::from_le_bytes(*token_b_price),
+            token_b_price_denominator,
         })
     }
 }
 
 impl DynPack for ConstantPriceCurve {
     fn pack_into_slice(&self, output: &mut [u8]) {
-        let token_b_price = array_mut_ref![output, 0, 8];
+        let output = array_mut_ref![output, 0, 16];
+        let (token_b_price, token_b_price_denominator) = mut_array_refs![output, 8, 8];
         *token_b_price = self.token_b_price.to_le_bytes();
+        *token_b_price_denominator = self.token_b_price_denominator.to_le_bytes();
     }
 }
 
@@ -654,3 +751,144 @@ impl DynPack for ConstantPriceCurve {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod price_tests {
+    use super::*;
+
+    /// 2 token A for 3 token B
+    fn fractional_curve() -> ConstantPriceCurve {
+        ConstantPriceCurve {
+            token_b_price: 2,
+            token_b_price_denominator: 3,
+        }
+    }
+
+    #[test]
+    fn pack_rational_price() {
+        let curve = fractional_curve();
+        let mut packed = [0u8; ConstantPriceCurve::LEN];
+        Pack::pack_into_slice(&curve, &mut packed[..]);
+        let unpacked = ConstantPriceCurve::unpack(&packed).unwrap();
+        assert_eq!(curve, unpacked);
+
+        // whole price packed before rational prices
+        let mut packed = [0u8; ConstantPriceCurve::LEN];
+        packed[..8].copy_from_slice(&1_251_258u64.to_le_bytes());
+        let unpacked = ConstantPriceCurve::unpack(&packed).unwrap();
+        assert_eq!(unpacked, ConstantPriceCurve::new(1_251_258));
+    }
+
+    #[test]
+    fn validate_price() {
+        assert!(fractional_curve().validate().is_ok());
+        assert!(ConstantPriceCurve::new(0).validate().is_err());
+        let curve = ConstantPriceCurve {
+            token_b_price: 1,
+            token_b_price_denominator: 0,
+        };
+        assert!(curve.validate().is_err());
+    }
+
+    #[test]
+    fn swap_at_fractional_price() {
+        let curve = fractional_curve();
+        // 11 A buys 16 B, worth 10.67 A, so all 11 are taken
+        let result = curve
+            .swap_without_fees(11, 0, 0, TradeDirection::AtoB)
+            .unwrap();
+        assert_eq!(result.destination_amount_swapped, 16);
+        assert_eq!(result.source_amount_swapped, 11);
+        // 10 B buys 6 A, worth only 9 B
+        let result = curve
+            .swap_without_fees(10, 0, 0, TradeDirection::BtoA)
+            .unwrap();
+        assert_eq!(result.destination_amount_swapped, 6);
+        assert_eq!(result.source_amount_swapped, 9);
+        // 1 B is worth less than 1 A
+        assert!(curve
+            .swap_without_fees(1, 0, 0, TradeDirection::BtoA)
+            .is_none());
+    }
+
+    #[test]
+    fn swap_never_loses_value() {
+        let curve = ConstantPriceCurve {
+            token_b_price: 1_000_003,
+            token_b_price_denominator: 999_983,
+        };
+        for source_amount in (1..10_000u128).step_by(7) {
+            if let Some(result) = curve.swap_without_fees(source_amount, 0, 0, TradeDirection::AtoB)
+            {
+                assert!(result.source_amount_swapped <= source_amount);
+                assert!(
+                    result.source_amount_swapped * 999_983
+                        >= result.destination_amount_swapped * 1_000_003
+                );
+            }
+            if let Some(result) = curve.swap_without_fees(source_amount, 0, 0, TradeDirection::BtoA)
+            {
+                assert!(result.source_amount_swapped <= source_amount);
+                assert!(
+                    result.source_amount_swapped * 1_000_003
+                        >= result.destination_amount_swapped * 999_983
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn swap_exact_out_at_fractional_price() {
+        let curve = fractional_curve();
+        let result = curve
+            .swap_without_fees_exact_out(16, 0, 0, TradeDirection::AtoB)
+            .unwrap();
+        assert_eq!(result.source_amount_swapped, 11);
+        let result = curve
+            .swap_without_fees_exact_out(7, 0, 0, TradeDirection::BtoA)
+            .unwrap();
+        assert_eq!(result.source_amount_swapped, 11);
+    }
+
+    #[test]
+    fn pool_tokens_round_in_favour_of_pool() {
+        let curve = fractional_curve();
+        // pool worth 1_000 + 2 / 3 * 1_501 = 2_000.67 A
+        let (token_a, token_b, supply) = (1_000, 1_501, 1_000);
+        let floor = curve
+            .pool_tokens_to_trading_tokens(10, supply, token_a, token_b, RoundDirection::Floor)
+            .unwrap();
+        let ceiling = curve
+            .pool_tokens_to_trading_tokens(10, supply, token_a, token_b, RoundDirection::Ceiling)
+            .unwrap();
+        assert_eq!((floor.token_a_amount, floor.token_b_amount), (10, 15));
+        assert_eq!((ceiling.token_a_amount, ceiling.token_b_amount), (11, 16));
+
+        let deposited = curve
+            .deposit_single_token_type(100, token_a, token_b, supply, TradeDirection::BtoA)
+            .unwrap();
+        let withdrawn = curve
+            .withdraw_single_token_type_exact_out(
+                100,
+                token_a,
+                token_b,
+                supply,
+                TradeDirection::BtoA,
+            )
+            .unwrap();
+        assert_eq!(deposited, 33);
+        assert_eq!(withdrawn, 34);
+    }
+
+    #[test]
+    fn supply_and_value() {
+        let curve = fractional_curve();
+        assert_eq!(
+            curve.normalized_value(10, 3).unwrap().to_imprecise(),
+            Some(6)
+        );
+        assert!(curve.validate_supply(0, 100).is_err());
+        assert!(curve.validate_supply(1, 1).is_err());
+        assert!(curve.validate_supply(1, 2).is_ok());
+    }
+}
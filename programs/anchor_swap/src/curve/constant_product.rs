@@ -45,6 +45,29 @@ pub fn swap(
     })
 }
 
+/// The constant product inverse swap calculation, given the desired amount
+/// of destination token
+pub fn swap_exact_out(
+    destination_amount: u128,
+    swap_source_amount: u128,
+    swap_destination_amount: u128,
+) -> Option<SwapWithoutFeesResult> {
+    let invariant = swap_source_amount.checked_mul(swap_destination_amount)?;
+
+    // The pool can never be emptied of the destination token
+    let new_swap_destination_amount =
+        map_zero_to_none(swap_destination_amount.checked_sub(destination_amount)?)?;
+    let (new_swap_source_amount, _) = invariant.checked_ceil_div(new_swap_destination_amount)?;
+
+    let source_amount_swapped =
+        map_zero_to_none(new_swap_source_amount.checked_sub(swap_source_amount)?)?;
+
+    Some(SwapWithoutFeesResult {
+        source_amount_swapped,
+        destination_amount_swapped: destination_amount,
+    })
+}
+
 /// Get the amount of trading tokens for the given amount of pool tokens,
 /// provided the total trading tokens and supply of pool tokens.
 ///
@@ -182,6 +205,21 @@ impl CurveCalculator for ConstantProductCurve {
         swap(source_amount, swap_source_amount, swap_destination_amount)
     }
 
+    /// Inverse of the constant product swap, rounding the source amount up
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        _trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        swap_exact_out(
+            destination_amount,
+            swap_source_amount,
+            swap_destination_amount,
+        )
+    }
+
     /// The constant product implementation is a simple ratio calculation for how many
     /// trading tokens correspond to a certain number of pool tokens
     fn pool_tokens_to_trading_tokens(
@@ -7,6 +7,7 @@ use solana_program::{
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
 };
+use spl_math::precise_number::PreciseNumber;
 use std::convert::TryFrom;
 
 /// Encapsulates all fee information and calculations for swap operations
@@ -62,6 +63,16 @@ pub fn calculate_fee(
     }
 }
 
+/// Fee as a fraction that is safe to compute with, zero fees having no
+/// denominator
+fn fee_fraction(numerator: u64, denominator: u64) -> (u128, u128) {
+    if numerator == 0 {
+        (0, 1)
+    } else {
+        (u128::from(numerator), u128::from(denominator))
+    }
+}
+
 fn validate_fraction(numerator: u64, denominator: u64) -> std::result::Result<(), SwapError> {
     if denominator == 0 && numerator == 0 {
         Ok(())
@@ -100,6 +111,41 @@ impl CurveFees {
         )
     }
 
+    /// Calculate the amount of trading tokens which, once the trading and
+    /// owner trading fees are taken out, leaves at least the given amount
+    pub fn pre_trading_fees_amount(&self, post_fees_amount: u128) -> Option<u128> {
+        if post_fees_amount == 0 {
+            return Some(0);
+        }
+        let (trade_fee_numerator, trade_fee_denominator) =
+            fee_fraction(self.trade_fee_numerator, self.trade_fee_denominator);
+        let (owner_trade_fee_numerator, owner_trade_fee_denominator) = fee_fraction(
+            self.owner_trade_fee_numerator,
+            self.owner_trade_fee_denominator,
+        );
+        // post = pre * (1 - trade fee - owner trade fee)
+        let denominator = trade_fee_denominator.checked_mul(owner_trade_fee_denominator)?;
+        let numerator = denominator
+            .checked_sub(trade_fee_numerator.checked_mul(owner_trade_fee_denominator)?)?
+            .checked_sub(owner_trade_fee_numerator.checked_mul(trade_fee_denominator)?)?;
+        let mut pre_fees_amount = post_fees_amount
+            .checked_mul(denominator)?
+            .checked_add(numerator.checked_sub(1)?)?
+            .checked_div(numerator)?;
+
+        // Fees are floored with a minimum of one token, so the exact fraction
+        // may still fall a token or two short
+        loop {
+            let fees = self
+                .trading_fee(pre_fees_amount)?
+                .checked_add(self.owner_trading_fee(pre_fees_amount)?)?;
+            if pre_fees_amount.saturating_sub(fees) >= post_fees_amount {
+                return Some(pre_fees_amount);
+            }
+            pre_fees_amount = pre_fees_amount.checked_add(1)?;
+        }
+    }
+
     /// Calculate the host fee based on the owner fee, only used in production
     /// situations where a program is hosted by multiple frontends
     pub fn host_fee(&self, owner_fee: u128) -> Option<u128> {
@@ -126,6 +172,213 @@ impl CurveFees {
     }
 }
 
+/// Denominator of the dynamic fee numerators & of the volatility
+pub const DYNAMIC_FEE_DENOMINATOR: u64 = 1_000_000;
+
+/// Trade fee rising with recent price movement, replacing the static trade
+/// fee of `CurveFees` while `max_fee_numerator` is non-zero. Every swap adds
+/// the relative price movement it caused to the volatility accumulator, which
+/// decays linearly to zero over `decay_period`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct DynamicFee {
+    /// Trade fee while the price is still, over `DYNAMIC_FEE_DENOMINATOR`
+    pub base_fee_numerator: u32,
+    /// Highest trade fee, over `DYNAMIC_FEE_DENOMINATOR`, zero while the mode is off
+    pub max_fee_numerator: u32,
+    /// Seconds for the volatility accumulator to decay to zero
+    pub decay_period: u32,
+    /// Recent price movement, over `DYNAMIC_FEE_DENOMINATOR`, as of `last_update_ts`
+    pub volatility_accumulator: u64,
+    /// Unix timestamp the volatility accumulator was last updated at
+    pub last_update_ts: i64,
+}
+
+impl DynamicFee {
+    /// Space of the serialized dynamic fee
+    pub const LEN: usize = 4 + 4 + 4 + 8 + 8;
+
+    /// Dynamic fee starting with no volatility, the fees being validated
+    pub fn new(
+        base_fee_numerator: u32,
+        max_fee_numerator: u32,
+        decay_period: u32,
+    ) -> std::result::Result<Self, SwapError> {
+        if max_fee_numerator == 0
+            || base_fee_numerator > max_fee_numerator
+            || u64::from(max_fee_numerator) >= DYNAMIC_FEE_DENOMINATOR
+            || decay_period == 0
+        {
+            return Err(SwapError::InvalidFee);
+        }
+        Ok(Self {
+            base_fee_numerator,
+            max_fee_numerator,
+            decay_period,
+            ..Self::default()
+        })
+    }
+
+    /// Is the trade fee dynamic
+    pub fn is_enabled(&self) -> bool {
+        self.max_fee_numerator > 0
+    }
+
+    /// Volatility accumulator decayed to `unix_timestamp`
+    pub fn volatility(&self, unix_timestamp: i64) -> Option<u64> {
+        let elapsed = u64::try_from(unix_timestamp.saturating_sub(self.last_update_ts)).ok()?;
+        let decay_period = u64::from(self.decay_period);
+        if elapsed >= decay_period {
+            return Some(0);
+        }
+        let volatility = u128::from(self.volatility_accumulator)
+            .checked_mul(u128::from(decay_period - elapsed))?
+            .checked_div(u128::from(decay_period))?;
+        u64::try_from(volatility).ok()
+    }
+
+    /// Trade fee at `unix_timestamp`, over `DYNAMIC_FEE_DENOMINATOR`
+    pub fn trade_fee_numerator(&self, unix_timestamp: i64) -> Option<u64> {
+        let fee_numerator =
+            u64::from(self.base_fee_numerator).saturating_add(self.volatility(unix_timestamp)?);
+        Some(std::cmp::min(
+            fee_numerator,
+            u64::from(self.max_fee_numerator),
+        ))
+    }
+
+    /// `fees` with the dynamic trade fee at `unix_timestamp` in place of the
+    /// static one, if the mode is on
+    pub fn apply(&self, fees: &CurveFees, unix_timestamp: i64) -> Option<CurveFees> {
+        if !self.is_enabled() {
+            return Some(fees.clone());
+        }
+        Some(CurveFees {
+            trade_fee_numerator: self.trade_fee_numerator(unix_timestamp)?,
+            trade_fee_denominator: DYNAMIC_FEE_DENOMINATOR,
+            ..fees.clone()
+        })
+    }
+
+    /// `fees` with the highest trade fee the mode can charge in place of the
+    /// static one, if the mode is on
+    pub fn max_fees(&self, fees: &CurveFees) -> CurveFees {
+        if !self.is_enabled() {
+            return fees.clone();
+        }
+        CurveFees {
+            trade_fee_numerator: u64::from(self.max_fee_numerator),
+            trade_fee_denominator: DYNAMIC_FEE_DENOMINATOR,
+            ..fees.clone()
+        }
+    }
+
+    /// Adds the relative movement from `price_before` to `price_after` to the
+    /// volatility decayed to `unix_timestamp`
+    pub fn record_price_movement(
+        &mut self,
+        price_before: &PreciseNumber,
+        price_after: &PreciseNumber,
+        unix_timestamp: i64,
+    ) -> Option<()> {
+        let movement = if price_after.greater_than(price_before) {
+            price_after.checked_sub(price_before)?
+        } else {
+            price_before.checked_sub(price_after)?
+        };
+        let movement = movement
+            .checked_mul(&PreciseNumber::new(u128::from(DYNAMIC_FEE_DENOMINATOR))?)?
+            .checked_div(price_before)?
+            .to_imprecise()?;
+        let volatility = self
+            .volatility(unix_timestamp)?
+            .saturating_add(u64::try_from(movement).unwrap_or(u64::MAX));
+        // Volatility past the highest fee makes no difference
+        self.volatility_accumulator = std::cmp::min(volatility, u64::from(self.max_fee_numerator));
+        self.last_update_ts = unix_timestamp;
+        Some(())
+    }
+}
+
+/// Denominator of the imbalance fee multiplier
+pub const IMBALANCE_FEE_MULTIPLIER_DENOMINATOR: u16 = 100;
+/// Highest imbalance fee multiplier, a fully imbalanced pool charging at most
+/// eleven times the trade fee
+pub const MAX_IMBALANCE_FEE_MULTIPLIER: u16 = 1_000;
+
+/// Extra trade fee on stable pools for trades leaving the pool away from
+/// balance. On top of the trade fee of `CurveFees`, a trade pays the trade fee
+/// times `multiplier` times the imbalance of the reserves it leaves, from zero
+/// on a balanced pool to one on a fully imbalanced pool, so trades restoring
+/// the balance pay less than those pushing it further. Off while `multiplier`
+/// is zero.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImbalanceFee {
+    /// Extra trade fee on a fully imbalanced pool, as a multiple of the trade
+    /// fee over `IMBALANCE_FEE_MULTIPLIER_DENOMINATOR`
+    pub multiplier: u16,
+}
+
+impl ImbalanceFee {
+    /// Space of the serialized imbalance fee
+    pub const LEN: usize = 2;
+
+    /// Imbalance fee with a validated multiplier
+    pub fn new(multiplier: u16) -> std::result::Result<Self, SwapError> {
+        if multiplier > MAX_IMBALANCE_FEE_MULTIPLIER {
+            return Err(SwapError::InvalidFee);
+        }
+        Ok(Self { multiplier })
+    }
+
+    /// Is the trade fee raised by the imbalance
+    pub fn is_enabled(&self) -> bool {
+        self.multiplier > 0
+    }
+
+    /// `fees` with the trade fee raised for reserves `imbalance` away from
+    /// balance, an imbalance past one counting as one. The extra fee is
+    /// rounded up.
+    pub fn apply(&self, fees: &CurveFees, imbalance: &PreciseNumber) -> Option<CurveFees> {
+        if !self.is_enabled() || fees.trade_fee_numerator == 0 {
+            return Some(fees.clone());
+        }
+        let one = PreciseNumber::new(1)?;
+        let imbalance = if imbalance.greater_than(&one) {
+            &one
+        } else {
+            imbalance
+        };
+        let extra_fee_numerator = PreciseNumber::new(
+            u128::from(fees.trade_fee_numerator).checked_mul(u128::from(self.multiplier))?,
+        )?
+        .checked_mul(imbalance)?
+        .checked_div(&PreciseNumber::new(u128::from(
+            IMBALANCE_FEE_MULTIPLIER_DENOMINATOR,
+        ))?)?
+        .ceiling()?
+        .to_imprecise()?;
+        let trade_fee_numerator =
+            u64::try_from(u128::from(fees.trade_fee_numerator).checked_add(extra_fee_numerator)?)
+                .ok()?;
+        // A fee taking the whole trade leaves nothing to swap
+        if trade_fee_numerator >= fees.trade_fee_denominator {
+            return None;
+        }
+        Some(CurveFees {
+            trade_fee_numerator,
+            ..fees.clone()
+        })
+    }
+
+    /// Validate that the trade fee of a fully imbalanced pool is still a valid
+    /// fee given `fees`
+    pub fn validate(&self, fees: &CurveFees) -> std::result::Result<(), SwapError> {
+        self.apply(fees, &PreciseNumber::new(1).ok_or(SwapError::InvalidFee)?)
+            .ok_or(SwapError::InvalidFee)?
+            .validate()
+    }
+}
+
 /// IsInitialized is required to use `Pack::pack` and `Pack::unpack`
 impl IsInitialized for CurveFees {
     fn is_initialized(&self) -> bool {
@@ -227,3 +480,144 @@ impl Pack for CurveFees {
 //         assert_eq!(fees, unpacked);
 //     }
 // }
+
+#[cfg(test)]
+mod dynamic_fee_tests {
+    use super::*;
+
+    fn price(value: u128) -> PreciseNumber {
+        PreciseNumber::new(value).unwrap()
+    }
+
+    #[test]
+    fn validate_dynamic_fee() {
+        assert!(DynamicFee::new(1_000, 10_000, 300).is_ok());
+        assert!(DynamicFee::new(10_000, 10_000, 300).is_ok());
+        assert!(DynamicFee::new(1_000, 0, 300).is_err());
+        assert!(DynamicFee::new(10_001, 10_000, 300).is_err());
+        assert!(DynamicFee::new(1_000, DYNAMIC_FEE_DENOMINATOR as u32, 300).is_err());
+        assert!(DynamicFee::new(1_000, 10_000, 0).is_err());
+        assert!(!DynamicFee::default().is_enabled());
+    }
+
+    #[test]
+    fn static_fee_while_disabled() {
+        let fees = CurveFees {
+            trade_fee_numerator: 25,
+            trade_fee_denominator: 10_000,
+            ..CurveFees::default()
+        };
+        assert_eq!(DynamicFee::default().apply(&fees, 1_000), Some(fees));
+    }
+
+    #[test]
+    fn fee_rises_with_movement_and_decays() {
+        let fees = CurveFees {
+            trade_fee_numerator: 25,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 5,
+            owner_trade_fee_denominator: 10_000,
+            ..CurveFees::default()
+        };
+        let mut dynamic_fee = DynamicFee::new(1_000, 10_000, 100).unwrap();
+        let applied = dynamic_fee.apply(&fees, 0).unwrap();
+        assert_eq!(applied.trade_fee_numerator, 1_000);
+        assert_eq!(applied.trade_fee_denominator, DYNAMIC_FEE_DENOMINATOR);
+        assert_eq!(applied.owner_trade_fee_numerator, 5);
+
+        // a 0.4% move, either way
+        dynamic_fee
+            .record_price_movement(&price(1_000), &price(1_004), 0)
+            .unwrap();
+        assert_eq!(dynamic_fee.trade_fee_numerator(0), Some(5_000));
+        dynamic_fee
+            .record_price_movement(&price(1_004), &price(1_000), 0)
+            .unwrap();
+        assert_eq!(dynamic_fee.volatility(0), Some(7_984));
+
+        // halfway through the decay period, then past it
+        assert_eq!(dynamic_fee.volatility(50), Some(3_992));
+        assert_eq!(dynamic_fee.trade_fee_numerator(50), Some(4_992));
+        assert_eq!(dynamic_fee.trade_fee_numerator(100), Some(1_000));
+
+        // capped at the highest fee
+        dynamic_fee
+            .record_price_movement(&price(1_000), &price(2_000), 100)
+            .unwrap();
+        assert_eq!(dynamic_fee.volatility_accumulator, 10_000);
+        assert_eq!(dynamic_fee.trade_fee_numerator(100), Some(10_000));
+    }
+}
+
+#[cfg(test)]
+mod imbalance_fee_tests {
+    use super::*;
+
+    fn fees() -> CurveFees {
+        CurveFees {
+            trade_fee_numerator: 4,
+            trade_fee_denominator: 10_000,
+            owner_trade_fee_numerator: 1,
+            owner_trade_fee_denominator: 10_000,
+            ..CurveFees::default()
+        }
+    }
+
+    fn imbalance(numerator: u128, denominator: u128) -> PreciseNumber {
+        PreciseNumber::new(numerator)
+            .unwrap()
+            .checked_div(&PreciseNumber::new(denominator).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn validate_imbalance_fee() {
+        assert!(ImbalanceFee::new(MAX_IMBALANCE_FEE_MULTIPLIER).is_ok());
+        assert!(ImbalanceFee::new(MAX_IMBALANCE_FEE_MULTIPLIER + 1).is_err());
+        assert!(!ImbalanceFee::default().is_enabled());
+
+        let imbalance_fee = ImbalanceFee::new(MAX_IMBALANCE_FEE_MULTIPLIER).unwrap();
+        assert!(imbalance_fee.validate(&fees()).is_ok());
+        let fees = CurveFees {
+            trade_fee_numerator: 1,
+            trade_fee_denominator: 10,
+            ..CurveFees::default()
+        };
+        assert!(imbalance_fee.validate(&fees).is_err());
+    }
+
+    #[test]
+    fn fee_rises_with_imbalance() {
+        let imbalance_fee = ImbalanceFee::new(500).unwrap();
+        // balanced, then a tenth, half & fully imbalanced
+        let trade_fee = |numerator, denominator| {
+            imbalance_fee
+                .apply(&fees(), &imbalance(numerator, denominator))
+                .unwrap()
+                .trade_fee_numerator
+        };
+        assert_eq!(trade_fee(0, 1), 4);
+        assert_eq!(trade_fee(1, 10), 6);
+        assert_eq!(trade_fee(1, 2), 14);
+        assert_eq!(trade_fee(1, 1), 24);
+        assert_eq!(trade_fee(3, 2), 24);
+
+        let applied = imbalance_fee.apply(&fees(), &imbalance(1, 2)).unwrap();
+        assert_eq!(applied.trade_fee_denominator, 10_000);
+        assert_eq!(applied.owner_trade_fee_numerator, 1);
+
+        // no trade fee to raise, or off
+        let no_trade_fee = CurveFees {
+            trade_fee_numerator: 0,
+            ..fees()
+        };
+        assert_eq!(
+            imbalance_fee.apply(&no_trade_fee, &imbalance(1, 1)),
+            Some(no_trade_fee)
+        );
+        assert_eq!(
+            ImbalanceFee::default().apply(&fees(), &imbalance(1, 1)),
+            Some(fees())
+        );
+    }
+}
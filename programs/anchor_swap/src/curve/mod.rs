@@ -2,8 +2,10 @@
 
 pub mod base;
 pub mod calculator;
+pub mod concentrated;
 pub mod constant_price;
 pub mod constant_product;
 pub mod fees;
 pub mod offset;
 pub mod stable;
+pub mod weighted;
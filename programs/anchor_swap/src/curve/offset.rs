@@ -9,7 +9,7 @@ use {
             },
             constant_product::{
                 deposit_single_token_type, normalized_value, pool_tokens_to_trading_tokens, swap,
-                withdraw_single_token_type_exact_out,
+                swap_exact_out, withdraw_single_token_type_exact_out,
             },
         },
         error::SwapError,
@@ -56,6 +56,30 @@ impl CurveCalculator for OffsetCurve {
         swap(source_amount, swap_source_amount, swap_destination_amount)
     }
 
+    /// Inverse of the offset swap, with the same offset on token B
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        let token_b_offset = self.token_b_offset as u128;
+        let swap_source_amount = match trade_direction {
+            TradeDirection::AtoB => swap_source_amount,
+            TradeDirection::BtoA => swap_source_amount.checked_add(token_b_offset)?,
+        };
+        let swap_destination_amount = match trade_direction {
+            TradeDirection::AtoB => swap_destination_amount.checked_add(token_b_offset)?,
+            TradeDirection::BtoA => swap_destination_amount,
+        };
+        swap_exact_out(
+            destination_amount,
+            swap_source_amount,
+            swap_destination_amount,
+        )
+    }
+
     /// The conversion for the offset curve needs to take into account the
     /// offset
     fn pool_tokens_to_trading_tokens(
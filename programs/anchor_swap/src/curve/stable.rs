@@ -3,23 +3,33 @@ use {
     crate::{
         curve::calculator::{
             CurveCalculator, DynPack, RoundDirection, SwapWithoutFeesResult, TradeDirection,
-            TradingTokenResult,
+            TradingTokenResult, MAX_TOKENS_IN_POOL,
         },
         error::SwapError,
     },
-    arrayref::{array_mut_ref, array_ref},
+    arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs},
     solana_program::{
+        clock::Clock,
         program_error::ProgramError,
         program_pack::{IsInitialized, Pack, Sealed},
+        sysvar::Sysvar,
     },
     spl_math::{precise_number::PreciseNumber, uint::U256},
     std::convert::TryFrom,
 };
 
 const N_COINS: u8 = 2;
-const N_COINS_SQUARED: u8 = 4;
 const ITERATIONS: u8 = 32;
 
+/// Largest amplifier a curve may use
+pub const MAX_AMP: u32 = 1_000_000;
+/// Largest factor the amplifier may move by over a single ramp
+pub const MAX_AMP_CHANGE: u32 = 10;
+/// Largest difference between the decimals of two mints of a pool
+pub const MAX_DECIMALS_DIFFERENCE: u8 = 9;
+/// Shortest ramp, and shortest time between the starts of two ramps, in seconds
+pub const MIN_RAMP_DURATION: i64 = 86_400;
+
 /// Returns self to the power of b
 fn checked_u8_power(a: &U256, b: u8) -> Option<U256> {
     let mut result = *a;
@@ -41,19 +51,312 @@ fn checked_u8_mul(a: &U256, b: u8) -> Option<U256> {
 /// StableCurve struct implementing CurveCalculator
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct StableCurve {
-    /// Amplifier constant
-    pub amp: u64,
+    /// Amplifier at the start of the ramp
+    pub initial_amp: u32,
+    /// Amplifier at the end of the ramp, and from then on
+    pub target_amp: u32,
+    /// Unix timestamp of the start of the ramp
+    pub start_ramp_ts: i64,
+    /// Unix timestamp of the end of the ramp
+    pub stop_ramp_ts: i64,
+    /// Power of ten bringing each token's amounts to the precision of the
+    /// finest mint, in the pool's token order
+    pub precision_exponents: [u8; MAX_TOKENS_IN_POOL],
+}
+
+impl StableCurve {
+    /// Curve with a constant amplifier, between mints of the same decimals
+    pub fn new(amp: u32) -> Self {
+        Self {
+            initial_amp: amp,
+            target_amp: amp,
+            start_ramp_ts: 0,
+            stop_ramp_ts: 0,
+            precision_exponents: [0; MAX_TOKENS_IN_POOL],
+        }
+    }
+
+    /// Curve with the precision exponents of mints with the given decimals
+    pub fn with_decimals(&self, token_decimals: &[u8]) -> Result<Self, SwapError> {
+        if token_decimals.len() > MAX_TOKENS_IN_POOL {
+            return Err(SwapError::InvalidCurve);
+        }
+        let max_decimals = token_decimals.iter().copied().max().unwrap_or(0);
+        let mut precision_exponents = [0; MAX_TOKENS_IN_POOL];
+        for (exponent, decimals) in precision_exponents.iter_mut().zip(token_decimals) {
+            *exponent = max_decimals - decimals;
+            if *exponent > MAX_DECIMALS_DIFFERENCE {
+                return Err(SwapError::InvalidCurve);
+            }
+        }
+        Ok(Self {
+            precision_exponents,
+            ..self.clone()
+        })
+    }
+
+    /// Factor bringing amounts of the token at `index` to the common precision
+    pub fn multiplier(&self, index: usize) -> Option<u128> {
+        10u128.checked_pow(self.precision_exponents.get(index).copied()?.into())
+    }
+
+    /// Balances brought to the common precision
+    fn normalize(&self, balances: &[u128]) -> Option<Vec<u128>> {
+        balances
+            .iter()
+            .enumerate()
+            .map(|(index, balance)| balance.checked_mul(self.multiplier(index)?))
+            .collect()
+    }
+
+    /// Invariant of a pool holding `balances`
+    pub fn compute_d(&self, balances: &[u128]) -> Option<u128> {
+        compute_d(self.leverage(balances.len())?, &self.normalize(balances)?)
+    }
+
+    /// Trade `source_amount` of the token at `source_index` for the token at
+    /// `destination_index` of a pool holding `balances`
+    pub fn swap_tokens(
+        &self,
+        source_index: usize,
+        destination_index: usize,
+        source_amount: u128,
+        balances: &[u128],
+    ) -> Option<SwapWithoutFeesResult> {
+        if source_index == destination_index {
+            return None;
+        }
+        let leverage = self.leverage(balances.len())?;
+        let mut amounts = self.normalize(balances)?;
+        let d = compute_d(leverage, &amounts)?;
+
+        let new_source_amount = amounts
+            .get(source_index)?
+            .checked_add(source_amount.checked_mul(self.multiplier(source_index)?)?)?;
+        amounts[source_index] = new_source_amount;
+        let new_destination_amount =
+            compute_new_destination_amount(leverage, &amounts, destination_index, d)?;
+
+        // Rounded down, in favour of the pool
+        let destination_amount_swapped = amounts
+            .get(destination_index)?
+            .checked_sub(new_destination_amount)?
+            .checked_div(self.multiplier(destination_index)?)?;
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: source_amount,
+            destination_amount_swapped,
+        })
+    }
+
+    /// Inverse of `swap_tokens`: the invariant is symmetric, so the new source
+    /// amount is solved for the same way as the new destination amount
+    pub fn swap_tokens_exact_out(
+        &self,
+        source_index: usize,
+        destination_index: usize,
+        destination_amount: u128,
+        balances: &[u128],
+    ) -> Option<SwapWithoutFeesResult> {
+        if source_index == destination_index {
+            return None;
+        }
+        let leverage = self.leverage(balances.len())?;
+        let mut amounts = self.normalize(balances)?;
+        let d = compute_d(leverage, &amounts)?;
+
+        let new_destination_amount = balances
+            .get(destination_index)?
+            .checked_sub(destination_amount)?;
+        if new_destination_amount == 0 {
+            return None;
+        }
+        amounts[destination_index] =
+            new_destination_amount.checked_mul(self.multiplier(destination_index)?)?;
+        let new_source_amount =
+            compute_new_destination_amount(leverage, &amounts, source_index, d)?;
+
+        // Rounded up, and by one more token to cover the precision of the solver
+        let source_multiplier = self.multiplier(source_index)?;
+        let source_amount_swapped = new_source_amount
+            .checked_sub(*amounts.get(source_index)?)?
+            .checked_add(source_multiplier.checked_sub(1)?)?
+            .checked_div(source_multiplier)?
+            .checked_add(1)?;
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped,
+            destination_amount_swapped: destination_amount,
+        })
+    }
+
+    /// Pool tokens worth `source_amount` of the token at `index` deposited
+    /// into a pool holding `balances`, rounded down
+    pub fn deposit_token(
+        &self,
+        index: usize,
+        source_amount: u128,
+        balances: &[u128],
+        pool_supply: u128,
+    ) -> Option<u128> {
+        if source_amount == 0 {
+            return Some(0);
+        }
+        let mut new_balances = balances.to_vec();
+        new_balances[index] = balances.get(index)?.checked_add(source_amount)?;
+        let d0 = PreciseNumber::new(self.compute_d(balances)?)?;
+        let d1 = PreciseNumber::new(self.compute_d(&new_balances)?)?;
+        let diff = d1.checked_sub(&d0)?;
+        let final_amount =
+            (diff.checked_mul(&PreciseNumber::new(pool_supply)?))?.checked_div(&d0)?;
+        final_amount.floor()?.to_imprecise()
+    }
+
+    /// Pool tokens worth `destination_amount` of the token at `index`
+    /// withdrawn from a pool holding `balances`, rounded up
+    pub fn withdraw_token_exact_out(
+        &self,
+        index: usize,
+        destination_amount: u128,
+        balances: &[u128],
+        pool_supply: u128,
+    ) -> Option<u128> {
+        if destination_amount == 0 {
+            return Some(0);
+        }
+        let mut new_balances = balances.to_vec();
+        new_balances[index] = balances.get(index)?.checked_sub(destination_amount)?;
+        let d0 = PreciseNumber::new(self.compute_d(balances)?)?;
+        let d1 = PreciseNumber::new(self.compute_d(&new_balances)?)?;
+        let diff = d0.checked_sub(&d1)?;
+        let final_amount =
+            (diff.checked_mul(&PreciseNumber::new(pool_supply)?))?.checked_div(&d0)?;
+        final_amount.ceiling()?.to_imprecise()
+    }
+
+    /// Amounts of each token of a pool holding `balances` worth `pool_tokens`,
+    /// non-zero amounts being rounded in the given direction
+    pub fn pool_tokens_to_token_amounts(
+        &self,
+        pool_tokens: u128,
+        pool_token_supply: u128,
+        balances: &[u128],
+        round_direction: RoundDirection,
+    ) -> Option<Vec<u128>> {
+        balances
+            .iter()
+            .map(|balance| {
+                let value = pool_tokens.checked_mul(*balance)?;
+                let amount = value.checked_div(pool_token_supply)?;
+                match round_direction {
+                    RoundDirection::Ceiling
+                        if amount > 0 && value.checked_rem(pool_token_supply)? > 0 =>
+                    {
+                        amount.checked_add(1)
+                    }
+                    _ => Some(amount),
+                }
+            })
+            .collect()
+    }
+
+    /// Amplifier at the given time, moving linearly from `initial_amp` to
+    /// `target_amp` over the ramp
+    pub fn amp_at(&self, unix_timestamp: i64) -> Option<u32> {
+        if unix_timestamp >= self.stop_ramp_ts {
+            return Some(self.target_amp);
+        }
+        if unix_timestamp <= self.start_ramp_ts {
+            return Some(self.initial_amp);
+        }
+        let elapsed = i128::from(unix_timestamp.checked_sub(self.start_ramp_ts)?);
+        let duration = i128::from(self.stop_ramp_ts.checked_sub(self.start_ramp_ts)?);
+        let initial_amp = i128::from(self.initial_amp);
+        let change = i128::from(self.target_amp)
+            .checked_sub(initial_amp)?
+            .checked_mul(elapsed)?
+            .checked_div(duration)?;
+        u32::try_from(initial_amp.checked_add(change)?).ok()
+    }
+
+    /// Amplifier in effect now, the clock only being read during a ramp
+    pub fn current_amp(&self) -> Option<u32> {
+        if self.initial_amp == self.target_amp {
+            return Some(self.target_amp);
+        }
+        let clock = Clock::get().ok()?;
+        self.amp_at(clock.unix_timestamp)
+    }
+
+    /// A * n, for a pool of `n_coins` tokens
+    fn leverage(&self, n_coins: usize) -> Option<u64> {
+        if !(usize::from(N_COINS)..=MAX_TOKENS_IN_POOL).contains(&n_coins) {
+            return None;
+        }
+        u64::from(self.current_amp()?).checked_mul(n_coins as u64)
+    }
+
+    /// Curve ramping from the amplifier at `now` to `target_amp` at
+    /// `stop_ramp_ts`. Ramps must last at least `MIN_RAMP_DURATION`, start
+    /// at least `MIN_RAMP_DURATION` after the previous one and move the
+    /// amplifier by at most `MAX_AMP_CHANGE` times.
+    pub fn ramp(&self, target_amp: u32, stop_ramp_ts: i64, now: i64) -> Result<Self, SwapError> {
+        let earliest_start = self
+            .start_ramp_ts
+            .checked_add(MIN_RAMP_DURATION)
+            .ok_or(SwapError::CalculationFailure)?;
+        let earliest_stop = now
+            .checked_add(MIN_RAMP_DURATION)
+            .ok_or(SwapError::CalculationFailure)?;
+        if now < earliest_start || stop_ramp_ts < earliest_stop {
+            return Err(SwapError::InvalidAmpRamp);
+        }
+        if target_amp == 0 || target_amp > MAX_AMP {
+            return Err(SwapError::InvalidAmpRamp);
+        }
+        let current_amp = self.amp_at(now).ok_or(SwapError::CalculationFailure)?;
+        let max_amp = current_amp.saturating_mul(MAX_AMP_CHANGE);
+        let min_amp = current_amp / MAX_AMP_CHANGE;
+        if target_amp > max_amp || target_amp < min_amp {
+            return Err(SwapError::InvalidAmpRamp);
+        }
+        Ok(Self {
+            initial_amp: current_amp,
+            target_amp,
+            start_ramp_ts: now,
+            stop_ramp_ts,
+            ..self.clone()
+        })
+    }
+
+    /// Curve holding the amplifier reached at `now`
+    pub fn stop_ramp(&self, now: i64) -> Result<Self, SwapError> {
+        let current_amp = self.amp_at(now).ok_or(SwapError::CalculationFailure)?;
+        Ok(Self {
+            initial_amp: current_amp,
+            target_amp: current_amp,
+            start_ramp_ts: now,
+            stop_ramp_ts: now,
+            ..self.clone()
+        })
+    }
 }
 
 /// d = (leverage * sum_x + d_product * n_coins) * initial_d / ((leverage - 1) * initial_d + (n_coins + 1) * d_product)
-fn calculate_step(initial_d: &U256, leverage: u64, sum_x: u128, d_product: &U256) -> Option<U256> {
+fn calculate_step(
+    initial_d: &U256,
+    leverage: u64,
+    sum_x: u128,
+    d_product: &U256,
+    n_coins: u8,
+) -> Option<U256> {
     let leverage_mul = U256::from(leverage).checked_mul(sum_x.into())?;
-    let d_p_mul = checked_u8_mul(d_product, N_COINS)?;
+    let d_p_mul = checked_u8_mul(d_product, n_coins)?;
 
     let l_val = leverage_mul.checked_add(d_p_mul)?.checked_mul(*initial_d)?;
 
     let leverage_sub = initial_d.checked_mul((leverage.checked_sub(1)?).into())?;
-    let n_coins_sum = checked_u8_mul(d_product, N_COINS.checked_add(1)?)?;
+    let n_coins_sum = checked_u8_mul(d_product, n_coins.checked_add(1)?)?;
 
     let r_val = leverage_sub.checked_add(n_coins_sum)?;
 
@@ -63,12 +366,15 @@ fn calculate_step(initial_d: &U256, leverage: u64, sum_x: u128, d_product: &U256
 /// Compute stable swap invariant (D)
 /// Equation:
 /// A * sum(x_i) * n**n + D = A * D * n**n + D**(n+1) / (n**n * prod(x_i))
-fn compute_d(leverage: u64, amount_a: u128, amount_b: u128) -> Option<u128> {
-    let amount_a_times_coins =
-        checked_u8_mul(&U256::from(amount_a), N_COINS)?.checked_add(U256::one())?;
-    let amount_b_times_coins =
-        checked_u8_mul(&U256::from(amount_b), N_COINS)?.checked_add(U256::one())?;
-    let sum_x = amount_a.checked_add(amount_b)?; // sum(x_i), a.k.a S
+fn compute_d(leverage: u64, amounts: &[u128]) -> Option<u128> {
+    let n_coins = u8::try_from(amounts.len()).ok()?;
+    let amounts_times_coins = amounts
+        .iter()
+        .map(|amount| checked_u8_mul(&U256::from(*amount), n_coins)?.checked_add(U256::one()))
+        .collect::<Option<Vec<U256>>>()?;
+    let sum_x = amounts
+        .iter()
+        .try_fold(0u128, |sum, amount| sum.checked_add(*amount))?; // sum(x_i), a.k.a S
     if sum_x == 0 {
         Some(0)
     } else {
@@ -78,15 +384,12 @@ fn compute_d(leverage: u64, amount_a: u128, amount_b: u128) -> Option<u128> {
         // Newton's method to approximate D
         for _ in 0..ITERATIONS {
             let mut d_product = d;
-            d_product = d_product
-                .checked_mul(d)?
-                .checked_div(amount_a_times_coins)?;
-            d_product = d_product
-                .checked_mul(d)?
-                .checked_div(amount_b_times_coins)?;
+            for amount_times_coins in amounts_times_coins.iter() {
+                d_product = d_product.checked_mul(d)?.checked_div(*amount_times_coins)?;
+            }
             d_previous = d;
             //d = (leverage * sum_x + d_p * n_coins) * d / ((leverage - 1) * d + (n_coins + 1) * d_p);
-            d = calculate_step(&d, leverage, sum_x, &d_product)?;
+            d = calculate_step(&d, leverage, sum_x, &d_product, n_coins)?;
             // Equality with the precision of 1
             if d == d_previous {
                 break;
@@ -96,27 +399,43 @@ fn compute_d(leverage: u64, amount_a: u128, amount_b: u128) -> Option<u128> {
     }
 }
 
-/// Compute swap amount `y` in proportion to `x`
+/// Compute the amount `y` of the token at `destination_index` keeping the
+/// invariant, given the amounts of the other tokens
 /// Solve for y:
 /// y**2 + y * (sum' - (A*n**n - 1) * D / (A * n**n)) = D ** (n + 1) / (n ** (2 * n) * prod' * A)
 /// y**2 + b*y = c
 fn compute_new_destination_amount(
     leverage: u64,
-    new_source_amount: u128,
+    amounts: &[u128],
+    destination_index: usize,
     d_val: u128,
 ) -> Option<u128> {
     // Upscale to U256
+    let n_coins = u8::try_from(amounts.len()).ok()?;
     let leverage: U256 = leverage.into();
-    let new_source_amount: U256 = new_source_amount.into();
     let d_val: U256 = d_val.into();
 
-    // sum' = prod' = x
+    // sum' & prod' run over the other tokens, c being divided down one token
+    // at a time to stay in range
     // c =  D ** (n + 1) / (n ** (2 * n) * prod' * A)
-    let c = checked_u8_power(&d_val, N_COINS.checked_add(1)?)?
-        .checked_div(checked_u8_mul(&new_source_amount, N_COINS_SQUARED)?.checked_mul(leverage)?)?;
+    let mut c = d_val;
+    let mut sum = U256::zero();
+    for (index, amount) in amounts.iter().enumerate() {
+        if index == destination_index {
+            continue;
+        }
+        let amount = U256::from(*amount);
+        sum = sum.checked_add(amount)?;
+        c = c
+            .checked_mul(d_val)?
+            .checked_div(checked_u8_mul(&amount, n_coins)?)?;
+    }
+    c = c
+        .checked_mul(d_val)?
+        .checked_div(checked_u8_mul(&leverage, n_coins)?)?;
 
     // b = sum' - (A*n**n - 1) * D / (A * n**n)
-    let b = new_source_amount.checked_add(d_val.checked_div(leverage)?)?;
+    let b = sum.checked_add(d_val.checked_div(leverage)?)?;
 
     // Solve for y by approximating: y**2 + b*y = c
     let mut y_prev: U256;
@@ -132,6 +451,27 @@ fn compute_new_destination_amount(
     u128::try_from(y).ok()
 }
 
+/// Indexes of the source & destination tokens of a pair trade, and the
+/// balances of the pair in token order
+fn pair_trade(
+    trade_direction: TradeDirection,
+    swap_source_amount: u128,
+    swap_destination_amount: u128,
+) -> (usize, usize, [u128; 2]) {
+    match trade_direction {
+        TradeDirection::AtoB => (0, 1, [swap_source_amount, swap_destination_amount]),
+        TradeDirection::BtoA => (1, 0, [swap_destination_amount, swap_source_amount]),
+    }
+}
+
+/// Index of the token of a single-sided deposit or withdrawal on a pair
+fn pair_index(trade_direction: TradeDirection) -> usize {
+    match trade_direction {
+        TradeDirection::AtoB => 0,
+        TradeDirection::BtoA => 1,
+    }
+}
+
 impl CurveCalculator for StableCurve {
     /// Stable curve
     fn swap_without_fees(
@@ -139,23 +479,28 @@ impl CurveCalculator for StableCurve {
         source_amount: u128,
         swap_source_amount: u128,
         swap_destination_amount: u128,
-        _trade_direction: TradeDirection,
+        trade_direction: TradeDirection,
     ) -> Option<SwapWithoutFeesResult> {
-        let leverage = self.amp.checked_mul(N_COINS as u64)?;
-
-        let new_source_amount = swap_source_amount.checked_add(source_amount)?;
-        let new_destination_amount = compute_new_destination_amount(
-            leverage,
-            new_source_amount,
-            compute_d(leverage, swap_source_amount, swap_destination_amount)?,
-        )?;
-
-        let amount_swapped = swap_destination_amount.checked_sub(new_destination_amount)?;
+        let (source_index, destination_index, balances) =
+            pair_trade(trade_direction, swap_source_amount, swap_destination_amount);
+        self.swap_tokens(source_index, destination_index, source_amount, &balances)
+    }
 
-        Some(SwapWithoutFeesResult {
-            source_amount_swapped: source_amount,
-            destination_amount_swapped: amount_swapped,
-        })
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        let (source_index, destination_index, balances) =
+            pair_trade(trade_direction, swap_source_amount, swap_destination_amount);
+        self.swap_tokens_exact_out(
+            source_index,
+            destination_index,
+            destination_amount,
+            &balances,
+        )
     }
 
     /// Re-implementation of `remove_liquidty`:
@@ -212,29 +557,12 @@ impl CurveCalculator for StableCurve {
         pool_supply: u128,
         trade_direction: TradeDirection,
     ) -> Option<u128> {
-        if source_amount == 0 {
-            return Some(0);
-        }
-        let leverage = self.amp.checked_mul(N_COINS as u64)?;
-        let d0 = PreciseNumber::new(compute_d(
-            leverage,
-            swap_token_a_amount,
-            swap_token_b_amount,
-        )?)?;
-        let (deposit_token_amount, other_token_amount) = match trade_direction {
-            TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
-            TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
-        };
-        let updated_deposit_token_amount = deposit_token_amount.checked_add(source_amount)?;
-        let d1 = PreciseNumber::new(compute_d(
-            leverage,
-            updated_deposit_token_amount,
-            other_token_amount,
-        )?)?;
-        let diff = d1.checked_sub(&d0)?;
-        let final_amount =
-            (diff.checked_mul(&PreciseNumber::new(pool_supply)?))?.checked_div(&d0)?;
-        final_amount.floor()?.to_imprecise()
+        self.deposit_token(
+            pair_index(trade_direction),
+            source_amount,
+            &[swap_token_a_amount, swap_token_b_amount],
+            pool_supply,
+        )
     }
 
     fn withdraw_single_token_type_exact_out(
@@ -245,29 +573,12 @@ impl CurveCalculator for StableCurve {
         pool_supply: u128,
         trade_direction: TradeDirection,
     ) -> Option<u128> {
-        if source_amount == 0 {
-            return Some(0);
-        }
-        let leverage = self.amp.checked_mul(N_COINS as u64)?;
-        let d0 = PreciseNumber::new(compute_d(
-            leverage,
-            swap_token_a_amount,
-            swap_token_b_amount,
-        )?)?;
-        let (withdraw_token_amount, other_token_amount) = match trade_direction {
-            TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
-            TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
-        };
-        let updated_deposit_token_amount = withdraw_token_amount.checked_sub(source_amount)?;
-        let d1 = PreciseNumber::new(compute_d(
-            leverage,
-            updated_deposit_token_amount,
-            other_token_amount,
-        )?)?;
-        let diff = d0.checked_sub(&d1)?;
-        let final_amount =
-            (diff.checked_mul(&PreciseNumber::new(pool_supply)?))?.checked_div(&d0)?;
-        final_amount.ceiling()?.to_imprecise()
+        self.withdraw_token_exact_out(
+            pair_index(trade_direction),
+            source_amount,
+            &[swap_token_a_amount, swap_token_b_amount],
+            pool_supply,
+        )
     }
 
     fn normalized_value(
@@ -277,20 +588,16 @@ impl CurveCalculator for StableCurve {
     ) -> Option<PreciseNumber> {
         #[cfg(not(any(test, feature = "fuzz")))]
         {
-            let leverage = self.amp.checked_mul(N_COINS as u64)?;
-            PreciseNumber::new(compute_d(
-                leverage,
-                swap_token_a_amount,
-                swap_token_b_amount,
-            )?)
+            PreciseNumber::new(self.compute_d(&[swap_token_a_amount, swap_token_b_amount])?)
         }
         #[cfg(any(test, feature = "fuzz"))]
         {
             use roots::{find_roots_cubic_normalized, Roots};
-            let x = swap_token_a_amount as f64;
-            let y = swap_token_b_amount as f64;
-            let c = (4.0 * (self.amp as f64)) - 1.0;
-            let d = 16.0 * (self.amp as f64) * x * y * (x + y);
+            let amp = self.current_amp()? as f64;
+            let x = swap_token_a_amount.checked_mul(self.multiplier(0)?)? as f64;
+            let y = swap_token_b_amount.checked_mul(self.multiplier(1)?)? as f64;
+            let c = (4.0 * amp) - 1.0;
+            let d = 16.0 * amp * x * y * (x + y);
             let roots = find_roots_cubic_normalized(0.0, c, d);
             let x0 = match roots {
                 Roots::No(_) => panic!("No roots found for cubic equations"),
@@ -309,8 +616,41 @@ impl CurveCalculator for StableCurve {
         }
     }
 
+    /// Distance of the reserves from an equal share of the invariant each,
+    /// over the invariant: sum(|n * x_i - D|) / (n * D)
+    fn imbalance(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let balances = [swap_token_a_amount, swap_token_b_amount];
+        let d = self.compute_d(&balances)?;
+        if d == 0 {
+            return PreciseNumber::new(0);
+        }
+        let n_coins = balances.len() as u128;
+        let deviation =
+            self.normalize(&balances)?
+                .iter()
+                .try_fold(0u128, |deviation, amount| {
+                    let share = amount.checked_mul(n_coins)?;
+                    deviation.checked_add(std::cmp::max(share, d) - std::cmp::min(share, d))
+                })?;
+        PreciseNumber::new(deviation)?.checked_div(&PreciseNumber::new(d.checked_mul(n_coins)?)?)
+    }
+
     fn validate(&self) -> Result<(), SwapError> {
-        // TODO are all amps valid?
+        let valid_amp = |amp| (1..=MAX_AMP).contains(&amp);
+        if !valid_amp(self.initial_amp)
+            || !valid_amp(self.target_amp)
+            || self.start_ramp_ts > self.stop_ramp_ts
+            || self
+                .precision_exponents
+                .iter()
+                .any(|exponent| *exponent > MAX_DECIMALS_DIFFERENCE)
+        {
+            return Err(SwapError::InvalidCurve);
+        }
         Ok(())
     }
 }
@@ -323,23 +663,264 @@ impl IsInitialized for StableCurve {
 }
 impl Sealed for StableCurve {}
 impl Pack for StableCurve {
-    const LEN: usize = 8;
+    const LEN: usize = 32;
     fn pack_into_slice(&self, output: &mut [u8]) {
         (self as &dyn DynPack).pack_into_slice(output);
     }
 
     fn unpack_from_slice(input: &[u8]) -> Result<StableCurve, ProgramError> {
-        let amp = array_ref![input, 0, 8];
+        let input = array_ref![input, 0, 32];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (initial_amp, target_amp, start_ramp_ts, stop_ramp_ts, precision_exponents, _) =
+            array_refs![input, 4, 4, 8, 8, MAX_TOKENS_IN_POOL, 4];
+        let initial_amp = u32::from_le_bytes(*initial_amp);
+        let target_amp = u32::from_le_bytes(*target_amp);
         Ok(Self {
-            amp: u64::from_le_bytes(*amp),
+            initial_amp,
+            // Curves packed before ramping only hold their amplifier, as a
+            // u64 whose high bytes read as a zero target amplifier
+            target_amp: if target_amp == 0 {
+                initial_amp
+            } else {
+                target_amp
+            },
+            start_ramp_ts: i64::from_le_bytes(*start_ramp_ts),
+            stop_ramp_ts: i64::from_le_bytes(*stop_ramp_ts),
+            precision_exponents: *precision_exponents,
         })
     }
 }
 
 impl DynPack for StableCurve {
     fn pack_into_slice(&self, output: &mut [u8]) {
-        let amp = array_mut_ref![output, 0, 8];
-        *amp = self.amp.to_le_bytes();
+        let output = array_mut_ref![output, 0, 32];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (initial_amp, target_amp, start_ramp_ts, stop_ramp_ts, precision_exponents, padding) =
+            mut_array_refs![output, 4, 4, 8, 8, MAX_TOKENS_IN_POOL, 4];
+        *initial_amp = self.initial_amp.to_le_bytes();
+        *target_amp = self.target_amp.to_le_bytes();
+        *start_ramp_ts = self.start_ramp_ts.to_le_bytes();
+        *stop_ramp_ts = self.stop_ramp_ts.to_le_bytes();
+        *precision_exponents = self.precision_exponents;
+        *padding = [0; 4];
+    }
+}
+
+#[cfg(test)]
+mod parameter_tests {
+    use super::*;
+
+    const DAY: i64 = MIN_RAMP_DURATION;
+
+    #[test]
+    fn amp_moves_linearly_over_the_ramp() {
+        let curve = StableCurve::new(100).ramp(200, 3 * DAY, DAY).unwrap();
+        assert_eq!(curve.amp_at(0), Some(100));
+        assert_eq!(curve.amp_at(DAY), Some(100));
+        assert_eq!(curve.amp_at(2 * DAY), Some(150));
+        assert_eq!(curve.amp_at(2 * DAY + DAY / 2), Some(175));
+        assert_eq!(curve.amp_at(3 * DAY), Some(200));
+        assert_eq!(curve.amp_at(10 * DAY), Some(200));
+
+        let curve = StableCurve::new(100).ramp(20, 3 * DAY, DAY).unwrap();
+        assert_eq!(curve.amp_at(2 * DAY), Some(60));
+        assert_eq!(curve.amp_at(3 * DAY), Some(20));
+    }
+
+    #[test]
+    fn ramp_limits() {
+        let curve = StableCurve::new(100);
+        curve.ramp(1_000, 2 * DAY, DAY).unwrap();
+        curve.ramp(10, 2 * DAY, DAY).unwrap();
+        assert!(curve.ramp(1_001, 2 * DAY, DAY).is_err());
+        assert!(curve.ramp(9, 2 * DAY, DAY).is_err());
+        assert!(curve.ramp(0, 2 * DAY, DAY).is_err());
+        // too short
+        assert!(curve.ramp(200, 2 * DAY - 1, DAY).is_err());
+
+        // too soon after the start of the previous ramp
+        let ramping = curve.ramp(200, 3 * DAY, DAY).unwrap();
+        assert!(ramping.ramp(300, 4 * DAY, 2 * DAY - 1).is_err());
+        // starting from the amplifier reached so far
+        let ramping = ramping.ramp(1_500, 4 * DAY, 2 * DAY).unwrap();
+        assert_eq!(ramping.initial_amp, 150);
+        assert!(ramping.validate().is_ok());
+    }
+
+    #[test]
+    fn stop_ramp_holds_current_amp() {
+        let curve = StableCurve::new(100).ramp(200, 3 * DAY, DAY).unwrap();
+        let stopped = curve.stop_ramp(2 * DAY).unwrap();
+        assert_eq!(stopped, StableCurve::new(150).stop_ramp(2 * DAY).unwrap());
+        assert_eq!(stopped.current_amp(), Some(150));
+        assert_eq!(stopped.amp_at(10 * DAY), Some(150));
+    }
+
+    #[test]
+    fn pack_curve() {
+        let curve = StableCurve::new(100).ramp(200, 3 * DAY, DAY).unwrap();
+        let mut packed = [0u8; StableCurve::LEN];
+        Pack::pack_into_slice(&curve, &mut packed[..]);
+        assert_eq!(StableCurve::unpack(&packed).unwrap(), curve);
+
+        let curve = curve.with_decimals(&[6, 8]).unwrap();
+        Pack::pack_into_slice(&curve, &mut packed[..]);
+        assert_eq!(StableCurve::unpack(&packed).unwrap(), curve);
+
+        // curves packed with a single amplifier keep it
+        let mut packed = [0u8; StableCurve::LEN];
+        packed[..8].copy_from_slice(&100u64.to_le_bytes());
+        assert_eq!(StableCurve::unpack(&packed).unwrap(), StableCurve::new(100));
+    }
+
+    #[test]
+    fn validate_amp() {
+        assert!(StableCurve::new(1).validate().is_ok());
+        assert!(StableCurve::new(MAX_AMP).validate().is_ok());
+        assert!(StableCurve::new(0).validate().is_err());
+        assert!(StableCurve::new(MAX_AMP + 1).validate().is_err());
+    }
+
+    #[test]
+    fn multipliers_from_decimals() {
+        let curve = StableCurve::new(100).with_decimals(&[6, 8]).unwrap();
+        assert_eq!(curve.multiplier(0), Some(100));
+        assert_eq!(curve.multiplier(1), Some(1));
+        let curve = curve.with_decimals(&[9, 9]).unwrap();
+        assert_eq!(curve.multiplier(0), Some(1));
+        assert_eq!(curve.multiplier(1), Some(1));
+        let curve = curve.with_decimals(&[6, 8, 9, 0]).unwrap();
+        assert_eq!(curve.precision_exponents, [3, 1, 0, 9]);
+        assert!(StableCurve::new(100).with_decimals(&[10, 0]).is_err());
+        assert!(StableCurve::new(100).with_decimals(&[6; 5]).is_err());
+    }
+
+    #[test]
+    fn swap_across_decimals() {
+        // 1M of each token, A with 6 decimals & B with 8
+        let curve = StableCurve::new(100).with_decimals(&[6, 8]).unwrap();
+        let swap_token_a_amount = 1_000_000_000_000;
+        let swap_token_b_amount = 100_000_000_000_000;
+
+        let result = curve
+            .swap_without_fees(
+                1_000_000_000,
+                swap_token_a_amount,
+                swap_token_b_amount,
+                TradeDirection::AtoB,
+            )
+            .unwrap();
+        assert!((99_990_000_000..100_000_000_000).contains(&result.destination_amount_swapped));
+
+        let result = curve
+            .swap_without_fees(
+                100_000_000_000,
+                swap_token_b_amount,
+                swap_token_a_amount,
+                TradeDirection::BtoA,
+            )
+            .unwrap();
+        assert!((999_900_000..1_000_000_000).contains(&result.destination_amount_swapped));
+
+        // Buying the same output back costs about the same input
+        let exact_out = curve
+            .swap_without_fees_exact_out(
+                result.destination_amount_swapped,
+                swap_token_b_amount,
+                swap_token_a_amount,
+                TradeDirection::BtoA,
+            )
+            .unwrap();
+        assert!(exact_out.source_amount_swapped >= result.source_amount_swapped - 100);
+        assert!(exact_out.source_amount_swapped <= result.source_amount_swapped + 100);
+    }
+
+    #[test]
+    fn balanced_pool_invariant() {
+        let curve = StableCurve::new(100);
+        for n_coins in 2..=MAX_TOKENS_IN_POOL {
+            let balances = vec![1_000_000_000_000; n_coins];
+            let d = curve.compute_d(&balances).unwrap();
+            assert_eq!(d, 1_000_000_000_000 * n_coins as u128);
+        }
+        assert!(curve
+            .compute_d(&[1_000_000; MAX_TOKENS_IN_POOL + 1])
+            .is_none());
+        assert!(curve.compute_d(&[1_000_000]).is_none());
+    }
+
+    #[test]
+    fn swap_in_tri_pool() {
+        // 1M of each token, the last one with 8 decimals & the others with 6
+        let curve = StableCurve::new(100).with_decimals(&[6, 6, 8]).unwrap();
+        let balances = [1_000_000_000_000, 1_000_000_000_000, 100_000_000_000_000];
+
+        let result = curve.swap_tokens(0, 2, 1_000_000_000, &balances).unwrap();
+        assert!((99_990_000_000..100_000_000_000).contains(&result.destination_amount_swapped));
+        let result = curve.swap_tokens(2, 1, 100_000_000_000, &balances).unwrap();
+        assert!((999_900_000..1_000_000_000).contains(&result.destination_amount_swapped));
+        assert!(curve.swap_tokens(1, 1, 1_000, &balances).is_none());
+
+        // The pool never loses value on a round trip
+        let exact_out = curve
+            .swap_tokens_exact_out(2, 1, result.destination_amount_swapped, &balances)
+            .unwrap();
+        assert!(exact_out.source_amount_swapped >= result.source_amount_swapped - 100);
+        let mut new_balances = balances;
+        new_balances[2] += exact_out.source_amount_swapped;
+        new_balances[1] -= result.destination_amount_swapped;
+        assert!(curve.compute_d(&new_balances).unwrap() >= curve.compute_d(&balances).unwrap());
+    }
+
+    #[test]
+    fn single_token_deposit_and_withdraw() {
+        let curve = StableCurve::new(100);
+        let balances = [1_000_000_000, 2_000_000_000, 1_500_000_000, 1_000_000_000];
+        let pool_supply = 5_000_000_000;
+        let deposited = curve
+            .deposit_token(3, 10_000_000, &balances, pool_supply)
+            .unwrap();
+        let withdrawn = curve
+            .withdraw_token_exact_out(3, 10_000_000, &balances, pool_supply)
+            .unwrap();
+        // Taking a deposit back out never costs fewer pool tokens than it earned
+        assert!(deposited > 0);
+        assert!(withdrawn >= deposited);
+
+        let amounts = curve
+            .pool_tokens_to_token_amounts(1, 3, &[10, 11, 0, 1], RoundDirection::Ceiling)
+            .unwrap();
+        assert_eq!(amounts, vec![4, 4, 0, 0]);
+        let amounts = curve
+            .pool_tokens_to_token_amounts(1, 3, &[10, 11, 0, 1], RoundDirection::Floor)
+            .unwrap();
+        assert_eq!(amounts, vec![3, 3, 0, 0]);
+    }
+
+    #[test]
+    fn imbalance_grows_away_from_balance() {
+        let imbalance_bps = |curve: &StableCurve, a, b| {
+            curve
+                .imbalance(a, b)
+                .unwrap()
+                .checked_mul(&PreciseNumber::new(10_000).unwrap())
+                .unwrap()
+                .to_imprecise()
+                .unwrap()
+        };
+        let curve = StableCurve::new(100);
+        assert_eq!(imbalance_bps(&curve, 1_000_000, 1_000_000), 0);
+        assert_eq!(imbalance_bps(&curve, 0, 0), 0);
+        // either way alike
+        assert_eq!(imbalance_bps(&curve, 1_100_000, 900_000), 1_000);
+        assert_eq!(imbalance_bps(&curve, 900_000, 1_100_000), 1_000);
+        assert_eq!(imbalance_bps(&curve, 1_900_000, 100_000), 9_183);
+        // a single token left is past full imbalance
+        assert!(imbalance_bps(&curve, 2_000_000, 0) > 10_000);
+
+        // amounts compared at the common precision
+        let curve = curve.with_decimals(&[6, 9]).unwrap();
+        assert_eq!(imbalance_bps(&curve, 1_000_000, 1_000_000_000), 0);
     }
 }
 
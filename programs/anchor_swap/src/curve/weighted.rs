@@ -0,0 +1,571 @@
+//! The Balancer constant weighted product invariant calculator.
+
+use {
+    crate::{
+        curve::{
+            calculator::{
+                map_zero_to_none, CurveCalculator, DynPack, RoundDirection, SwapWithoutFeesResult,
+                TradeDirection, TradingTokenResult,
+            },
+            constant_product::pool_tokens_to_trading_tokens,
+        },
+        error::SwapError,
+    },
+    arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs},
+    solana_program::{
+        program_error::ProgramError,
+        program_pack::{IsInitialized, Pack, Sealed},
+    },
+    spl_math::{precise_number::PreciseNumber, uint::U256},
+};
+
+/// Maximum sum of both weights, which bounds the exponents of the curve
+pub const MAX_TOTAL_WEIGHT: u64 = 100;
+
+/// Largest trade, deposit or withdrawal as a fraction of the pool's balance
+/// of the token, like Balancer's `MAX_IN_RATIO` and `MAX_OUT_RATIO`. This keeps
+/// the bases of `pow_near_one` close enough to 1 for its series to converge.
+const MAX_RATIO_NUMERATOR: u128 = 3;
+const MAX_RATIO_DENOMINATOR: u128 = 10;
+
+/// Maximum number of terms of the series in `pow_near_one`
+const MAX_POW_ITERATIONS: u128 = 64;
+
+/// Error bound of `pow_near_one`, both relative to the result and absolute,
+/// in units of 10^-12. Results are moved by it in the pool's favor.
+const MAX_POW_ERROR: u128 = 100;
+
+/// Bits of the exponent used by `pow_by_roots`
+const ROOT_ITERATIONS: u8 = 40;
+
+/// WeightedCurve struct implementing CurveCalculator
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WeightedCurve {
+    /// Weight of token A, ie. 80 for an 80/20 pool
+    pub token_a_weight: u64,
+    /// Weight of token B, ie. 20 for an 80/20 pool
+    pub token_b_weight: u64,
+}
+
+/// Raises `base` to the power `numerator / denominator`. The fractional part
+/// of the exponent uses the Taylor series of x ^ y around 1:
+///
+/// x ^ y = 1 + y (x - 1) + y (y - 1) / 2! (x - 1) ^ 2 + ...
+///
+/// refining each term with `t_k = t_k-1 * (y - k + 1) * (x - 1) / k`, which
+/// converges quickly for bases close to 1.
+fn pow_near_one(base: &PreciseNumber, numerator: u128, denominator: u128) -> Option<PreciseNumber> {
+    let one = PreciseNumber::new(1)?;
+    let whole = base.checked_pow(numerator.checked_div(denominator)?)?;
+    let fraction = numerator.checked_rem(denominator)?;
+    if fraction == 0 {
+        return Some(whole);
+    }
+
+    let (base_minus_one, base_below_one) = base.unsigned_sub(&one);
+    // Terms alternate in sign, so both sums are kept apart until the end
+    let mut positive_sum = one.clone();
+    let mut negative_sum = PreciseNumber::new(0)?;
+    let mut term = one;
+    let mut term_negative = false;
+    for k in 1..MAX_POW_ITERATIONS {
+        // y - k + 1 = (fraction - (k - 1) * denominator) / denominator
+        let offset = k.checked_sub(1)?.checked_mul(denominator)?;
+        let (factor, factor_negative) = if fraction >= offset {
+            (fraction.checked_sub(offset)?, false)
+        } else {
+            (offset.checked_sub(fraction)?, true)
+        };
+        term = term
+            .checked_mul(&PreciseNumber::new(factor)?)?
+            .checked_mul(&base_minus_one)?
+            .checked_div(&PreciseNumber::new(k.checked_mul(denominator)?)?)?;
+        if term.value.is_zero() {
+            break;
+        }
+        term_negative ^= factor_negative ^ base_below_one;
+        if term_negative {
+            negative_sum = negative_sum.checked_add(&term)?;
+        } else {
+            positive_sum = positive_sum.checked_add(&term)?;
+        }
+    }
+    whole.checked_mul(&positive_sum.checked_sub(&negative_sum)?)
+}
+
+/// Error bound of a result of `pow_near_one`
+fn pow_error(result: &PreciseNumber) -> Option<PreciseNumber> {
+    let max_error = PreciseNumber {
+        value: U256::from(MAX_POW_ERROR),
+    };
+    result.checked_mul(&max_error)?.checked_add(&max_error)
+}
+
+/// `pow_near_one`, rounded down by its error bound
+fn pow_down(base: &PreciseNumber, numerator: u128, denominator: u128) -> Option<PreciseNumber> {
+    let result = pow_near_one(base, numerator, denominator)?;
+    let error = pow_error(&result)?;
+    if result.less_than_or_equal(&error) {
+        PreciseNumber::new(0)
+    } else {
+        result.checked_sub(&error)
+    }
+}
+
+/// Raises `base` to the power `numerator / denominator`, for exponents below
+/// 1, as the product of the repeated square roots of `base` matching the bits
+/// of the exponent. Accurate for any base, unlike `pow_near_one`, but slower.
+fn pow_by_roots(base: &PreciseNumber, numerator: u128, denominator: u128) -> Option<PreciseNumber> {
+    let mut result = PreciseNumber::new(1)?;
+    let mut root = base.clone();
+    let mut remainder = numerator;
+    for _ in 0..ROOT_ITERATIONS {
+        if remainder == 0 {
+            break;
+        }
+        root = root.sqrt()?;
+        remainder = remainder.checked_mul(2)?;
+        if remainder >= denominator {
+            remainder = remainder.checked_sub(denominator)?;
+            result = result.checked_mul(&root)?;
+        }
+    }
+    Some(result)
+}
+
+/// Checks that `amount` is at most the maximum ratio of `balance`
+fn check_max_ratio(amount: u128, balance: u128) -> Option<()> {
+    if amount.checked_mul(MAX_RATIO_DENOMINATOR)? <= balance.checked_mul(MAX_RATIO_NUMERATOR)? {
+        Some(())
+    } else {
+        None
+    }
+}
+
+impl WeightedCurve {
+    /// Weights of the source and destination tokens of a trade
+    fn weights(&self, trade_direction: TradeDirection) -> (u128, u128) {
+        let token_a_weight = self.token_a_weight as u128;
+        let token_b_weight = self.token_b_weight as u128;
+        match trade_direction {
+            TradeDirection::AtoB => (token_a_weight, token_b_weight),
+            TradeDirection::BtoA => (token_b_weight, token_a_weight),
+        }
+    }
+
+    /// Sum of both weights
+    fn total_weight(&self) -> u128 {
+        (self.token_a_weight as u128) + (self.token_b_weight as u128)
+    }
+}
+
+impl CurveCalculator for WeightedCurve {
+    /// Weighted product swap ensures a ^ w_a * b ^ w_b = constant, giving:
+    ///
+    /// destination = swap_destination * (1 - (swap_source / (swap_source + source)) ^ (w_source / w_destination))
+    fn swap_without_fees(
+        &self,
+        source_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        check_max_ratio(source_amount, swap_source_amount)?;
+        let (source_weight, destination_weight) = self.weights(trade_direction);
+
+        let one = PreciseNumber::new(1)?;
+        let new_swap_source_amount = swap_source_amount.checked_add(source_amount)?;
+        // 1 - source / new source, so that the ratio is rounded up
+        let ratio = one.checked_sub(
+            &PreciseNumber::new(source_amount)?
+                .checked_div(&PreciseNumber::new(new_swap_source_amount)?)?,
+        )?;
+        let power = pow_near_one(&ratio, source_weight, destination_weight)?;
+        let power = power.checked_add(&pow_error(&power)?)?;
+        if power.greater_than_or_equal(&one) {
+            return None;
+        }
+        let destination_amount_swapped = PreciseNumber::new(swap_destination_amount)?
+            .checked_mul(&one.checked_sub(&power)?)?
+            .floor()?
+            .to_imprecise()?;
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: map_zero_to_none(source_amount)?,
+            destination_amount_swapped: map_zero_to_none(destination_amount_swapped)?,
+        })
+    }
+
+    /// Inverse of the weighted product swap:
+    ///
+    /// source = swap_source * ((swap_destination / (swap_destination - destination)) ^ (w_destination / w_source) - 1)
+    fn swap_without_fees_exact_out(
+        &self,
+        destination_amount: u128,
+        swap_source_amount: u128,
+        swap_destination_amount: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<SwapWithoutFeesResult> {
+        check_max_ratio(destination_amount, swap_destination_amount)?;
+        let (source_weight, destination_weight) = self.weights(trade_direction);
+
+        let one = PreciseNumber::new(1)?;
+        let new_swap_destination_amount =
+            map_zero_to_none(swap_destination_amount.checked_sub(destination_amount)?)?;
+        // 1 + destination / new destination, so that the ratio is rounded up
+        let ratio = one.checked_add(
+            &PreciseNumber::new(destination_amount)?
+                .checked_div(&PreciseNumber::new(new_swap_destination_amount)?)?,
+        )?;
+        let power = pow_near_one(&ratio, destination_weight, source_weight)?;
+        // `swap_without_fees` takes its own error bound out of the amount it
+        // pays for the source returned here, which is worth the destination
+        // weight over the source weight as much
+        let error_multiplier = destination_weight
+            .checked_add(source_weight.checked_sub(1)?)?
+            .checked_div(source_weight)?
+            .checked_add(2)?;
+        let power = power.checked_add(
+            &pow_error(&power)?.checked_mul(&PreciseNumber::new(error_multiplier)?)?,
+        )?;
+        let source_amount_swapped = PreciseNumber::new(swap_source_amount)?
+            .checked_mul(&power.checked_sub(&one)?)?
+            .ceiling()?
+            .to_imprecise()?;
+        check_max_ratio(source_amount_swapped, swap_source_amount)?;
+
+        Some(SwapWithoutFeesResult {
+            source_amount_swapped: map_zero_to_none(source_amount_swapped)?,
+            destination_amount_swapped: map_zero_to_none(destination_amount)?,
+        })
+    }
+
+    /// Both tokens are withdrawn in proportion to the pool's balances, as for
+    /// the constant product curve
+    fn pool_tokens_to_trading_tokens(
+        &self,
+        pool_tokens: u128,
+        pool_token_supply: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        round_direction: RoundDirection,
+    ) -> Option<TradingTokenResult> {
+        pool_tokens_to_trading_tokens(
+            pool_tokens,
+            pool_token_supply,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            round_direction,
+        )
+    }
+
+    /// Get the amount of pool tokens for the deposited amount of token A or B,
+    /// from the Balancer single-asset deposit formula:
+    ///
+    /// pool_tokens = pool_supply * ((1 + source / swap_source) ^ (w_source / w_total) - 1)
+    fn deposit_single_token_type(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        let swap_source_amount = match trade_direction {
+            TradeDirection::AtoB => swap_token_a_amount,
+            TradeDirection::BtoA => swap_token_b_amount,
+        };
+        check_max_ratio(source_amount, swap_source_amount)?;
+        let (source_weight, _) = self.weights(trade_direction);
+
+        let one = PreciseNumber::new(1)?;
+        let ratio = one.checked_add(
+            &PreciseNumber::new(source_amount)?
+                .checked_div(&PreciseNumber::new(swap_source_amount)?)?,
+        )?;
+        let power = pow_down(&ratio, source_weight, self.total_weight())?;
+        if power.less_than_or_equal(&one) {
+            return Some(0);
+        }
+        PreciseNumber::new(pool_supply)?
+            .checked_mul(&power.checked_sub(&one)?)?
+            .floor()?
+            .to_imprecise()
+    }
+
+    /// Get the amount of pool tokens for the withdrawn amount of token A or B,
+    /// from the Balancer single-asset withdrawal formula:
+    ///
+    /// pool_tokens = pool_supply * (1 - (1 - source / swap_source) ^ (w_source / w_total))
+    fn withdraw_single_token_type_exact_out(
+        &self,
+        source_amount: u128,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+        pool_supply: u128,
+        trade_direction: TradeDirection,
+    ) -> Option<u128> {
+        if source_amount == 0 {
+            return Some(0);
+        }
+        let swap_source_amount = match trade_direction {
+            TradeDirection::AtoB => swap_token_a_amount,
+            TradeDirection::BtoA => swap_token_b_amount,
+        };
+        check_max_ratio(source_amount, swap_source_amount)?;
+        let (source_weight, _) = self.weights(trade_direction);
+
+        let one = PreciseNumber::new(1)?;
+        let ratio = one.checked_sub(
+            &PreciseNumber::new(source_amount)?
+                .checked_div(&PreciseNumber::new(swap_source_amount)?)?,
+        )?;
+        let power = pow_down(&ratio, source_weight, self.total_weight())?;
+        PreciseNumber::new(pool_supply)?
+            .checked_mul(&one.checked_sub(&power)?)?
+            .ceiling()?
+            .to_imprecise()
+    }
+
+    fn validate(&self) -> Result<(), SwapError> {
+        if self.token_a_weight == 0
+            || self.token_b_weight == 0
+            || self.total_weight() > MAX_TOTAL_WEIGHT as u128
+        {
+            Err(SwapError::InvalidCurve)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The weighted geometric mean of the balances, a ^ (w_a / w_total) * b ^ (w_b / w_total),
+    /// which has the dimension of tokens ^ 1 since the normalized weights add
+    /// up to 1
+    fn normalized_value(
+        &self,
+        swap_token_a_amount: u128,
+        swap_token_b_amount: u128,
+    ) -> Option<PreciseNumber> {
+        let total_weight = self.total_weight();
+        pow_by_roots(
+            &PreciseNumber::new(swap_token_a_amount)?,
+            self.token_a_weight as u128,
+            total_weight,
+        )?
+        .checked_mul(&pow_by_roots(
+            &PreciseNumber::new(swap_token_b_amount)?,
+            self.token_b_weight as u128,
+            total_weight,
+        )?)
+    }
+}
+
+/// IsInitialized is required to use `Pack::pack` and `Pack::unpack`
+impl IsInitialized for WeightedCurve {
+    fn is_initialized(&self) -> bool {
+        true
+    }
+}
+impl Sealed for WeightedCurve {}
+impl Pack for WeightedCurve {
+    const LEN: usize = 16;
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        (self as &dyn DynPack).pack_into_slice(output);
+    }
+
+    fn unpack_from_slice(input: &[u8]) -> Result<WeightedCurve, ProgramError> {
+        let input = array_ref![input, 0, 16];
+        let (token_a_weight, token_b_weight) = array_refs![input, 8, 8];
+        Ok(Self {
+            token_a_weight: u64::from_le_bytes(*token_a_weight),
+            token_b_weight: u64::from_le_bytes(*token_b_weight),
+        })
+    }
+}
+
+impl DynPack for WeightedCurve {
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, 16];
+        let (token_a_weight, token_b_weight) = mut_array_refs![output, 8, 8];
+        *token_a_weight = self.token_a_weight.to_le_bytes();
+        *token_b_weight = self.token_b_weight.to_le_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn precise(value: f64) -> PreciseNumber {
+        let one = spl_math::precise_number::ONE as f64;
+        PreciseNumber {
+            value: U256::from((value * one) as u128),
+        }
+    }
+
+    fn imprecise(value: &PreciseNumber) -> f64 {
+        value.value.as_u128() as f64 / spl_math::precise_number::ONE as f64
+    }
+
+    #[test]
+    fn pow_near_one_error_bound() {
+        for base in [0.7, 0.769, 0.9, 0.999, 1.0, 1.001, 1.1, 1.3, 1.43] {
+            for (numerator, denominator) in [(1, 99), (20, 80), (1, 2), (80, 20), (99, 1), (7, 3)] {
+                let base = precise(base);
+                let expected = f64::powf(imprecise(&base), numerator as f64 / denominator as f64);
+                let result = pow_near_one(&base, numerator, denominator).unwrap();
+                let error = imprecise(&pow_error(&result).unwrap());
+                assert!(
+                    (imprecise(&result) - expected).abs() <= error,
+                    "{}^({}/{}): {} vs {}",
+                    imprecise(&base),
+                    numerator,
+                    denominator,
+                    imprecise(&result),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn swap_80_20() {
+        let curve = WeightedCurve {
+            token_a_weight: 80,
+            token_b_weight: 20,
+        };
+        // 4M A & 1M B at 80/20 weights: 1 B is worth 1 A at the spot price
+        let result = curve
+            .swap_without_fees(10_000, 1_000_000, 4_000_000, TradeDirection::BtoA)
+            .unwrap();
+        assert_eq!(result.source_amount_swapped, 10_000);
+        let expected = 4_000_000.0 * (1.0 - f64::powf(1_000_000.0 / 1_010_000.0, 0.25));
+        assert_eq!(result.destination_amount_swapped, expected as u128);
+
+        let result = curve
+            .swap_without_fees(10_000, 4_000_000, 1_000_000, TradeDirection::AtoB)
+            .unwrap();
+        let expected = 1_000_000.0 * (1.0 - f64::powf(4_000_000.0 / 4_010_000.0, 4.0));
+        assert_eq!(result.destination_amount_swapped, expected as u128);
+
+        // trades above the maximum ratio of the pool's balance are rejected
+        assert!(curve
+            .swap_without_fees(1_200_001, 4_000_000, 1_000_000, TradeDirection::AtoB)
+            .is_none());
+    }
+
+    #[test]
+    fn swap_keeps_value() {
+        let curve = WeightedCurve {
+            token_a_weight: 80,
+            token_b_weight: 20,
+        };
+        let swap_token_a_amount = 50_000_000_000u128;
+        let swap_token_b_amount = 3_000_000_000u128;
+        let value = curve
+            .normalized_value(swap_token_a_amount, swap_token_b_amount)
+            .unwrap();
+        for trade_direction in [TradeDirection::AtoB, TradeDirection::BtoA] {
+            let (swap_source_amount, swap_destination_amount) = match trade_direction {
+                TradeDirection::AtoB => (swap_token_a_amount, swap_token_b_amount),
+                TradeDirection::BtoA => (swap_token_b_amount, swap_token_a_amount),
+            };
+            for source_amount in [1_000, 1_234_567, 500_000_000] {
+                let result = curve
+                    .swap_without_fees(
+                        source_amount,
+                        swap_source_amount,
+                        swap_destination_amount,
+                        trade_direction,
+                    )
+                    .unwrap();
+                let new_swap_source_amount = swap_source_amount + result.source_amount_swapped;
+                let new_swap_destination_amount =
+                    swap_destination_amount - result.destination_amount_swapped;
+                let (new_token_a_amount, new_token_b_amount) = match trade_direction {
+                    TradeDirection::AtoB => (new_swap_source_amount, new_swap_destination_amount),
+                    TradeDirection::BtoA => (new_swap_destination_amount, new_swap_source_amount),
+                };
+                let new_value = curve
+                    .normalized_value(new_token_a_amount, new_token_b_amount)
+                    .unwrap();
+                assert!(new_value.greater_than_or_equal(&value));
+            }
+        }
+    }
+
+    #[test]
+    fn single_sided_deposit_and_withdrawal() {
+        let curve = WeightedCurve {
+            token_a_weight: 80,
+            token_b_weight: 20,
+        };
+        let pool_supply = 1_000_000_000u128;
+        let deposited = curve
+            .deposit_single_token_type(
+                100_000,
+                4_000_000,
+                1_000_000,
+                pool_supply,
+                TradeDirection::AtoB,
+            )
+            .unwrap();
+        let expected = pool_supply as f64 * (f64::powf(4_100_000.0 / 4_000_000.0, 0.8) - 1.0);
+        assert!(deposited <= expected as u128 && deposited + 2 >= expected as u128);
+
+        // withdrawing the same amount back costs more pool tokens
+        let withdrawn = curve
+            .withdraw_single_token_type_exact_out(
+                100_000,
+                4_100_000,
+                1_000_000,
+                pool_supply + deposited,
+                TradeDirection::AtoB,
+            )
+            .unwrap();
+        assert!(withdrawn >= deposited);
+        assert_eq!(
+            curve
+                .withdraw_single_token_type_exact_out(
+                    0,
+                    4_100_000,
+                    1_000_000,
+                    pool_supply,
+                    TradeDirection::AtoB,
+                )
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn pack_curve() {
+        let curve = WeightedCurve {
+            token_a_weight: 80,
+            token_b_weight: 20,
+        };
+
+        let mut packed = [0u8; WeightedCurve::LEN];
+        Pack::pack_into_slice(&curve, &mut packed[..]);
+        let unpacked = WeightedCurve::unpack(&packed).unwrap();
+        assert_eq!(curve, unpacked);
+
+        let mut packed = vec![];
+        packed.extend_from_slice(&80u64.to_le_bytes());
+        packed.extend_from_slice(&20u64.to_le_bytes());
+        let unpacked = WeightedCurve::unpack(&packed).unwrap();
+        assert_eq!(curve, unpacked);
+    }
+
+    #[test]
+    fn validate_weights() {
+        let curve = |token_a_weight, token_b_weight| WeightedCurve {
+            token_a_weight,
+            token_b_weight,
+        };
+        curve(80, 20).validate().unwrap();
+        curve(1, 99).validate().unwrap();
+        assert!(curve(0, 20).validate().is_err());
+        assert!(curve(80, 21).validate().is_err());
+    }
+}
@@ -97,4 +97,41 @@ pub enum SwapError {
     // The operation cannot be performed on the given curve
     #[msg("The operation cannot be performed on the given curve")]
     UnsupportedCurveOperation,
+    // Token A's mint does not sort before token B's mint
+    #[msg("Token A mint must sort before token B mint")]
+    InvalidMintOrder,
+    // The amm account already uses the current layout
+    #[msg("Amm account already uses the current layout")]
+    AlreadyMigrated,
+
+    // 30.
+    // The provided token mint is not one of the pool's
+    #[msg("Address of the provided token mint is incorrect")]
+    IncorrectMint,
+    // The same amm is traded more than once on a route
+    #[msg("Amm appears more than once in the swap route")]
+    RepeatedAmm,
+    // The amp ramp is out of range, too short, too steep or too soon after the last one
+    #[msg("Amp ramp is out of range, too short, too steep or too soon after the last one")]
+    InvalidAmpRamp,
+    // The number of tokens is out of the range the pool supports
+    #[msg("Number of tokens is not supported for the pool")]
+    InvalidTokenCount,
+    // The price oracle account is not the amm's or not a trading price account
+    #[msg("Price oracle account is invalid or not trading")]
+    InvalidOracle,
+
+    // 35.
+    // The oracle's price was published too long ago
+    #[msg("Oracle price is stale")]
+    StaleOraclePrice,
+    // The oracle's confidence interval is too wide for its price
+    #[msg("Oracle price confidence interval is too wide")]
+    UncertainOraclePrice,
+    // Native SOL is both the source & the destination of a route
+    #[msg("Native SOL can't be both the source and the destination of a route")]
+    NativeSolRoundTrip,
+    // The Token-2022 mint has an extension pools don't account for
+    #[msg("Mint has a Token-2022 extension the pool doesn't support")]
+    UnsupportedMintExtension,
 }
@@ -1,17 +1,29 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::program_option::COption;
-use anchor_spl::token::{self, Mint, MintTo, TokenAccount};
-// use solana_program::program::{invoke, invoke_signed};
+use anchor_lang::Discriminator;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::token::{self, Burn, Mint, MintTo, Token, TokenAccount, Transfer};
+use solana_program::program::invoke;
+use solana_program::program_pack::Pack;
 use solana_program::pubkey::Pubkey;
-// use solana_program::system_instruction;
+use solana_program::system_instruction;
 use std::convert::TryFrom;
+pub mod constraints;
 pub mod curve;
 pub mod error;
-// use crate::curve::{
-//     base::SwapCurve,
-//     calculator::{CurveCalculator, RoundDirection, TradeDirection},
-//     fees::CurveFees,
-// };
+pub mod native_sol;
+pub mod oracle;
+pub mod token_interface;
+use crate::constraints::{SwapConstraints, SWAP_CONSTRAINTS_SEED};
+use crate::curve::{
+    base::{CurveType, SwapCurve, SwapResult},
+    calculator::{RoundDirection, TradeDirection, MAX_TOKENS_IN_POOL},
+    constant_price::ConstantPriceCurve,
+    fees::{CurveFees, DynamicFee, ImbalanceFee},
+    stable::StableCurve,
+};
+use crate::native_sol::{NativeSol, WSOL_SEED};
+use crate::oracle::OraclePrice;
 // use crate::curve::{
 //     constant_price::ConstantPriceCurve, constant_product::ConstantProductCurve,
 //     offset::OffsetCurve, stable::StableCurve,
@@ -24,11 +36,14 @@ pub mod anchor_programs {
     use super::*;
     pub fn initialize(
         ctx: Context<Initialize>,
-        // fees_input: FeesInput,
-        // curve_input: CurveInput,
+        fees_input: CurveFees,
+        curve_input: CurveInput,
+        fee_tier: u16,
     ) -> Result<()> {
-        // TODO:
-        // 1. Replace the initial LP mint amt by curve calc
+        let curve_input = curve_input.with_token_decimals(&[
+            token_interface::unpack_mint(&ctx.accounts.token_a_mint)?.decimals,
+            token_interface::unpack_mint(&ctx.accounts.token_b_mint)?.decimals,
+        ])?;
 
         // Get swap_authority address (a PDA with seed of amm account's pubkey)
         let (swap_authority, bump_seed) = Pubkey::find_program_address(
@@ -38,6 +53,19 @@ pub mod anchor_programs {
 
         let _ = &ctx.accounts.validate_input_accounts(swap_authority)?;
 
+        // Build the curve from its packed input and make sure both curve & fees are sane
+        let swap_curve = curve_input.to_swap_curve()?;
+        swap_curve.calculator.validate()?;
+        fees_input.validate()?;
+        // ... and that they fit the program owner's constraints
+        ctx.accounts.swap_constraints.validate_curve(&swap_curve)?;
+        ctx.accounts.swap_constraints.validate_fees(&fees_input)?;
+        let token_a = token_interface::unpack_token_account(&ctx.accounts.token_a)?;
+        let token_b = token_interface::unpack_token_account(&ctx.accounts.token_b)?;
+        swap_curve
+            .calculator
+            .validate_supply(token_a.amount, token_b.amount)?;
+
         // concatenate swap_authority's seed & bump
         let seeds = &[
             &ctx.accounts.amm.to_account_info().key.to_bytes(),
@@ -45,7 +73,7 @@ pub mod anchor_programs {
         ];
 
         // calc initial LP mint amt
-        let initial_amount = 1 as u128;
+        let initial_amount = swap_curve.calculator.new_pool_supply();
 
         let mint_initial_amt_cpi_ctx = CpiContext::new(
             ctx.accounts.token_program.clone(),
@@ -58,125 +86,5830 @@ pub mod anchor_programs {
 
         token::mint_to(
             mint_initial_amt_cpi_ctx.with_signer(&[&seeds[..]]),
-            u64::try_from(initial_amount).unwrap(),
+            to_u64(initial_amount)?,
         )?;
 
+        let initializer_key = *ctx.accounts.payer.key;
+        let token_program_id = *ctx.accounts.token_program.key;
+        let token_a_account = *ctx.accounts.token_a.to_account_info().key;
+        let token_b_account = *ctx.accounts.token_b.to_account_info().key;
+        let pool_mint = *ctx.accounts.pool_mint.to_account_info().key;
+        let token_a_mint = *ctx.accounts.token_a_mint.key;
+        let token_b_mint = *ctx.accounts.token_b_mint.key;
+        let pool_fee_account = *ctx.accounts.fee_account.to_account_info().key;
+        let token_a_program_id = *ctx.accounts.token_a.owner;
+        let token_b_program_id = *ctx.accounts.token_b.owner;
+
+        let amm = &mut ctx.accounts.amm;
+        amm.version = AMM_VERSION;
+        amm.initializer_key = initializer_key;
+        amm.is_initialized = true;
+        amm.bump_seed = bump_seed;
+        amm.fee_tier = fee_tier;
+        amm.token_program_id = token_program_id;
+        amm.token_a_account = token_a_account;
+        amm.token_b_account = token_b_account;
+        amm.pool_mint = pool_mint;
+        amm.token_a_mint = token_a_mint;
+        amm.token_b_mint = token_b_mint;
+        amm.pool_fee_account = pool_fee_account;
+        amm.fees = fees_input;
+        amm.curve = curve_input;
+        amm.token_a_program_id = token_a_program_id;
+        amm.token_b_program_id = token_b_program_id;
+        amm.token_count = 2;
+
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    // Swap authority: A PDA (seed: amm account's pubkey) to let program manipulate swap related features for all lp pools
-    pub authority: AccountInfo<'info>,
-    #[account(signer, zero)]
-    pub amm: Account<'info, Amm>,
-    #[account(mut)]
-    pub pool_mint: Account<'info, Mint>,
-    // amm's token A account
-    #[account(mut)]
-    pub token_a: Account<'info, TokenAccount>,
-    // amm's token B account
-    #[account(mut)]
-    pub token_b: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub fee_account: Account<'info, TokenAccount>,
-    // The LP token ATA to which the initial LP token is sent (Owner MUST be authority)
-    #[account(mut)]
-    pub destination: Account<'info, TokenAccount>,
-    pub token_program: AccountInfo<'info>,
-}
+    pub fn create_pool(
+        ctx: Context<CreatePool>,
+        fees_input: CurveFees,
+        curve_input: CurveInput,
+        fee_tier: u16,
+        initial_token_a_amount: u64,
+        initial_token_b_amount: u64,
+    ) -> Result<()> {
+        token_interface::check_mint_extensions(&ctx.accounts.token_a_mint)?;
+        token_interface::check_mint_extensions(&ctx.accounts.token_b_mint)?;
+        let curve_input = curve_input.with_token_decimals(&[
+            token_interface::unpack_mint(&ctx.accounts.token_a_mint)?.decimals,
+            token_interface::unpack_mint(&ctx.accounts.token_b_mint)?.decimals,
+        ])?;
 
-impl<'info> Initialize<'info> {
-    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
-        // TODO:
-        // 1. use input curve_input to create curve object
-        // 2. Add Swap constraint
-        if self.amm.is_initialized {
-            return Err(error::SwapError::AlreadyInUse.into());
+        // Build the curve from its packed input and make sure both curve & fees are sane
+        let swap_curve = curve_input.to_swap_curve()?;
+        swap_curve.calculator.validate()?;
+        fees_input.validate()?;
+        // ... and that they fit the program owner's constraints
+        ctx.accounts.swap_constraints.validate_curve(&swap_curve)?;
+        ctx.accounts.swap_constraints.validate_fees(&fees_input)?;
+        // Only what's left after the mints' transfer fees reaches the vaults
+        let token_a_transfer_fee =
+            token_interface::transfer_fee(&ctx.accounts.token_a_mint, initial_token_a_amount)?;
+        let token_b_transfer_fee =
+            token_interface::transfer_fee(&ctx.accounts.token_b_mint, initial_token_b_amount)?;
+        swap_curve.calculator.validate_supply(
+            initial_token_a_amount
+                .checked_sub(token_a_transfer_fee)
+                .ok_or(error::SwapError::FeeCalculationFailure)?,
+            initial_token_b_amount
+                .checked_sub(token_b_transfer_fee)
+                .ok_or(error::SwapError::FeeCalculationFailure)?,
+        )?;
+
+        // Create the vaults under the token program owning each mint
+        let amm_key = ctx.accounts.amm.key();
+        let token_a_bump = *ctx.bumps.get("token_a").unwrap();
+        token_interface::create_vault(
+            ctx.accounts.payer.to_account_info(),
+            ctx.accounts.token_a.clone(),
+            ctx.accounts.token_a_mint.clone(),
+            ctx.accounts.authority.clone(),
+            ctx.accounts.token_a_program.clone(),
+            ctx.accounts.system_program.to_account_info(),
+            &[
+                VAULT_SEED,
+                amm_key.as_ref(),
+                ctx.accounts.token_a_mint.key.as_ref(),
+                &[token_a_bump],
+            ],
+        )?;
+        let token_b_bump = *ctx.bumps.get("token_b").unwrap();
+        token_interface::create_vault(
+            ctx.accounts.payer.to_account_info(),
+            ctx.accounts.token_b.clone(),
+            ctx.accounts.token_b_mint.clone(),
+            ctx.accounts.authority.clone(),
+            ctx.accounts.token_b_program.clone(),
+            ctx.accounts.system_program.to_account_info(),
+            &[
+                VAULT_SEED,
+                amm_key.as_ref(),
+                ctx.accounts.token_b_mint.key.as_ref(),
+                &[token_b_bump],
+            ],
+        )?;
+
+        // Fund the freshly created vaults with the creator's initial liquidity
+        token_interface::transfer(
+            ctx.accounts.token_a_program.clone(),
+            ctx.accounts.source_a.clone(),
+            ctx.accounts.token_a_mint.clone(),
+            ctx.accounts.token_a.clone(),
+            ctx.accounts.payer.to_account_info(),
+            &[],
+            initial_token_a_amount,
+        )?;
+        token_interface::transfer(
+            ctx.accounts.token_b_program.clone(),
+            ctx.accounts.source_b.clone(),
+            ctx.accounts.token_b_mint.clone(),
+            ctx.accounts.token_b.clone(),
+            ctx.accounts.payer.to_account_info(),
+            &[],
+            initial_token_b_amount,
+        )?;
+
+        // concatenate swap_authority's seed & bump
+        let bump_seed = *ctx.bumps.get("authority").unwrap();
+        let seeds = &[
+            &ctx.accounts.amm.to_account_info().key.to_bytes(),
+            &[bump_seed][..],
+        ];
+
+        // calc initial LP mint amt
+        let initial_amount = swap_curve.calculator.new_pool_supply();
+
+        token::mint_to(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            )
+            .with_signer(&[&seeds[..]]),
+            to_u64(initial_amount)?,
+        )?;
+
+        let initializer_key = *ctx.accounts.payer.key;
+        let token_program_id = *ctx.accounts.token_program.key;
+        let token_a_account = *ctx.accounts.token_a.to_account_info().key;
+        let token_b_account = *ctx.accounts.token_b.to_account_info().key;
+        let pool_mint = *ctx.accounts.pool_mint.to_account_info().key;
+        let token_a_mint = *ctx.accounts.token_a_mint.key;
+        let token_b_mint = *ctx.accounts.token_b_mint.key;
+        let pool_fee_account = *ctx.accounts.fee_account.to_account_info().key;
+        let token_a_program_id = *ctx.accounts.token_a_program.key;
+        let token_b_program_id = *ctx.accounts.token_b_program.key;
+
+        let amm = &mut ctx.accounts.amm;
+        amm.version = AMM_VERSION;
+        amm.initializer_key = initializer_key;
+        amm.is_initialized = true;
+        amm.bump_seed = bump_seed;
+        amm.fee_tier = fee_tier;
+        amm.token_program_id = token_program_id;
+        amm.token_a_account = token_a_account;
+        amm.token_b_account = token_b_account;
+        amm.pool_mint = pool_mint;
+        amm.token_a_mint = token_a_mint;
+        amm.token_b_mint = token_b_mint;
+        amm.pool_fee_account = pool_fee_account;
+        amm.fees = fees_input;
+        amm.curve = curve_input;
+        amm.token_a_program_id = token_a_program_id;
+        amm.token_b_program_id = token_b_program_id;
+        amm.token_count = 2;
+
+        Ok(())
+    }
+
+    pub fn swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+
+        let trade_direction =
+            if *ctx.accounts.swap_source.to_account_info().key == amm.token_a_account {
+                TradeDirection::AtoB
+            } else {
+                TradeDirection::BtoA
+            };
+
+        let swap_source = token_interface::unpack_token_account(&ctx.accounts.swap_source)?;
+        let swap_destination =
+            token_interface::unpack_token_account(&ctx.accounts.swap_destination)?;
+
+        // Only what's left after the source mint's transfer fee reaches the vault & gets traded
+        let source_transfer_fee =
+            token_interface::transfer_fee(&ctx.accounts.source_mint, amount_in)?;
+        let actual_amount_in = amount_in
+            .checked_sub(source_transfer_fee)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+        let (swap_curve, host_fee_accounts) = amm.priced_pair_curve(ctx.remaining_accounts)?;
+        let now = Clock::get()?.unix_timestamp;
+        let fees = amm.effective_fees(now)?;
+        let result = swap_curve
+            .swap(
+                u128::from(actual_amount_in),
+                u128::from(swap_source.amount),
+                u128::from(swap_destination.amount),
+                trade_direction,
+                &fees,
+                &amm.imbalance_fee,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+
+        // ... and the user receives what's left after the destination mint's transfer fee
+        let destination_amount_swapped = to_u64(result.destination_amount_swapped)?;
+        let destination_transfer_fee = token_interface::transfer_fee(
+            &ctx.accounts.destination_mint,
+            destination_amount_swapped,
+        )?;
+        let amount_received = destination_amount_swapped
+            .checked_sub(destination_transfer_fee)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if amount_received < minimum_amount_out {
+            return Err(error::SwapError::ExceededSlippage.into());
         }
-        // Verify if input authority pubkey is valid
-        if *self.authority.key != swap_authority {
-            return Err(error::SwapError::InvalidProgramAddress.into());
+
+        // Send the whole amount in, unless the curve only took part of it
+        let source_amount_swapped = to_u64(result.source_amount_swapped)?;
+        let source_transfer_amount = if source_amount_swapped == actual_amount_in {
+            amount_in
+        } else {
+            source_amount_swapped
+                .checked_add(token_interface::inverse_transfer_fee(
+                    &ctx.accounts.source_mint,
+                    source_amount_swapped,
+                )?)
+                .ok_or(error::SwapError::FeeCalculationFailure)?
+        };
+
+        execute_swap(
+            &ctx,
+            &swap_curve,
+            &fees,
+            &result,
+            trade_direction,
+            source_transfer_amount,
+            host_fee_accounts.first(),
+        )?;
+        record_volatility(
+            &mut ctx.accounts.amm,
+            &swap_curve,
+            &result,
+            trade_direction,
+            now,
+        )
+    }
+
+    pub fn swap_exact_out<'info>(
+        ctx: Context<'_, '_, '_, 'info, Swap<'info>>,
+        amount_out: u64,
+        maximum_amount_in: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+
+        let trade_direction =
+            if *ctx.accounts.swap_source.to_account_info().key == amm.token_a_account {
+                TradeDirection::AtoB
+            } else {
+                TradeDirection::BtoA
+            };
+
+        let swap_source = token_interface::unpack_token_account(&ctx.accounts.swap_source)?;
+        let swap_destination =
+            token_interface::unpack_token_account(&ctx.accounts.swap_destination)?;
+
+        // The pool sends enough for the user to receive `amount_out` net of
+        // the destination mint's transfer fee
+        let destination_amount = amount_out
+            .checked_add(token_interface::inverse_transfer_fee(
+                &ctx.accounts.destination_mint,
+                amount_out,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+        let (swap_curve, host_fee_accounts) = amm.priced_pair_curve(ctx.remaining_accounts)?;
+        let now = Clock::get()?.unix_timestamp;
+        let fees = amm.effective_fees(now)?;
+        let result = swap_curve
+            .swap_exact_out(
+                u128::from(destination_amount),
+                u128::from(swap_source.amount),
+                u128::from(swap_destination.amount),
+                trade_direction,
+                &fees,
+                &amm.imbalance_fee,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+
+        // ... and the user sends enough for the vault to receive the source
+        // amount net of the source mint's transfer fee
+        let source_amount_swapped = to_u64(result.source_amount_swapped)?;
+        let source_transfer_amount = source_amount_swapped
+            .checked_add(token_interface::inverse_transfer_fee(
+                &ctx.accounts.source_mint,
+                source_amount_swapped,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if source_transfer_amount > maximum_amount_in {
+            return Err(error::SwapError::ExceededSlippage.into());
         }
-        if *self.authority.key != self.token_a.owner || *self.authority.key != self.token_b.owner {
-            return Err(error::SwapError::InvalidOwner.into());
+
+        execute_swap(
+            &ctx,
+            &swap_curve,
+            &fees,
+            &result,
+            trade_direction,
+            source_transfer_amount,
+            host_fee_accounts.first(),
+        )?;
+        record_volatility(
+            &mut ctx.accounts.amm,
+            &swap_curve,
+            &result,
+            trade_direction,
+            now,
+        )
+    }
+
+    pub fn route_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, RouteSwap<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        let mut hops = ctx
+            .accounts
+            .validate_route(ctx.program_id, ctx.remaining_accounts)?;
+        let now = Clock::get()?.unix_timestamp;
+
+        let native_sol = NativeSol {
+            user: ctx.accounts.user_transfer_authority.to_account_info(),
+            wsol_account: ctx.accounts.wsol_account.clone(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            bump: *ctx.bumps.get("wsol_account").unwrap(),
+        };
+        let source_info = native_sol.wrap(
+            ctx.accounts.source_info.clone(),
+            &ctx.accounts.source_mint,
+            &ctx.accounts.source_token_program,
+            amount_in,
+        )?;
+
+        // Each hop trades what the previous one paid out, which goes straight
+        // from the previous amm's destination vault into the next source vault
+        let mut amount = amount_in;
+        let mut swaps = Vec::with_capacity(hops.len());
+        let mut previous_hop: Option<&RouteHop> = None;
+        for hop in hops.iter() {
+            let (source_mint, source_token_program) = match previous_hop {
+                Some(previous_hop) => (
+                    &previous_hop.destination_mint,
+                    &previous_hop.destination_token_program,
+                ),
+                None => (
+                    &ctx.accounts.source_mint,
+                    &ctx.accounts.source_token_program,
+                ),
+            };
+            let trade_direction = if *hop.swap_source.key == hop.amm.token_a_account {
+                TradeDirection::AtoB
+            } else {
+                TradeDirection::BtoA
+            };
+
+            let swap_source = token_interface::unpack_token_account(&hop.swap_source)?;
+            let swap_destination = token_interface::unpack_token_account(&hop.swap_destination)?;
+
+            let source_transfer_fee = token_interface::transfer_fee(source_mint, amount)?;
+            let actual_amount_in = amount
+                .checked_sub(source_transfer_fee)
+                .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+            let swap_curve = hop.amm.pair_curve()?;
+            let fees = hop.amm.effective_fees(now)?;
+            let result = swap_curve
+                .swap(
+                    u128::from(actual_amount_in),
+                    u128::from(swap_source.amount),
+                    u128::from(swap_destination.amount),
+                    trade_direction,
+                    &fees,
+                    &hop.amm.imbalance_fee,
+                )
+                .ok_or(error::SwapError::ZeroTradingTokens)?;
+
+            // Send the whole amount in, unless the curve only took part of it
+            let source_amount_swapped = to_u64(result.source_amount_swapped)?;
+            let source_transfer_amount = if source_amount_swapped == actual_amount_in {
+                amount
+            } else {
+                source_amount_swapped
+                    .checked_add(token_interface::inverse_transfer_fee(
+                        source_mint,
+                        source_amount_swapped,
+                    )?)
+                    .ok_or(error::SwapError::FeeCalculationFailure)?
+            };
+            match previous_hop {
+                Some(previous_hop) => {
+                    previous_hop.transfer_out(hop.swap_source.clone(), source_transfer_amount)?
+                }
+                None => token_interface::transfer(
+                    source_token_program.clone(),
+                    source_info.clone(),
+                    source_mint.clone(),
+                    hop.swap_source.clone(),
+                    ctx.accounts.user_transfer_authority.to_account_info(),
+                    &[],
+                    source_transfer_amount,
+                )?,
+            }
+
+            // owner fee is paid out as the equivalent amount of LP tokens
+            let pool_token_amount = owner_fee_pool_tokens(
+                &fees,
+                &swap_curve,
+                &result,
+                trade_direction,
+                hop.pool_mint.supply,
+            )?;
+            if pool_token_amount > 0 {
+                let amm_key = hop.amm.key();
+                let seeds = &[&amm_key.to_bytes(), &[hop.amm.bump_seed][..]];
+                token::mint_to(
+                    CpiContext::new(
+                        ctx.accounts.token_program.clone(),
+                        MintTo {
+                            mint: hop.pool_mint.to_account_info(),
+                            to: hop.pool_fee_account.clone(),
+                            authority: hop.authority.clone(),
+                        },
+                    )
+                    .with_signer(&[&seeds[..]]),
+                    to_u64(pool_token_amount)?,
+                )?;
+            }
+
+            emit_swap_event(hop.amm.key(), &result)?;
+            amount = to_u64(result.destination_amount_swapped)?;
+            swaps.push((swap_curve, result, trade_direction));
+            previous_hop = Some(hop);
         }
-        // TODO: What is destination??
-        if *self.authority.key == self.fee_account.owner
-            && *self.authority.key == self.destination.owner
+        native_sol.unwrap(&source_info, &ctx.accounts.source_token_program)?;
+
+        // One slippage check for the whole route, on what the user receives
+        let last_hop = previous_hop.ok_or(error::SwapError::InvalidInstruction)?;
+        let destination_transfer_fee =
+            token_interface::transfer_fee(&last_hop.destination_mint, amount)?;
+        let amount_received = amount
+            .checked_sub(destination_transfer_fee)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if amount_received < minimum_amount_out {
+            return Err(error::SwapError::ExceededSlippage.into());
+        }
+
+        // last amm's destination vault pays out the swapped tokens to the user
+        let destination = native_sol.wrap(
+            ctx.accounts.destination.clone(),
+            &last_hop.destination_mint,
+            &last_hop.destination_token_program,
+            0,
+        )?;
+        last_hop.transfer_out(destination.clone(), amount)?;
+        native_sol.unwrap(&destination, &last_hop.destination_token_program)?;
+
+        // Hops are read from the remaining accounts, so Anchor doesn't write
+        // their amms back itself
+        for (hop, (swap_curve, result, trade_direction)) in hops.iter_mut().zip(swaps) {
+            if hop.amm.dynamic_fee.is_enabled() {
+                record_volatility(&mut hop.amm, &swap_curve, &result, trade_direction, now)?;
+                hop.amm.exit(ctx.program_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn deposit_all_token_types<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositAllTokenTypes<'info>>,
+        pool_token_amount: u64,
+        maximum_token_a_amount: u64,
+        maximum_token_b_amount: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+
+        let native_sol = NativeSol {
+            user: ctx.accounts.user_transfer_authority.to_account_info(),
+            wsol_account: ctx.accounts.wsol_account.clone(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            bump: *ctx.bumps.get("wsol_account").unwrap(),
+        };
+
+        let (swap_curve, _) = amm.priced_pair_curve(ctx.remaining_accounts)?;
+        let calculator = &swap_curve.calculator;
+        if !calculator.allows_deposits() {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+
+        let current_pool_mint_supply = u128::from(ctx.accounts.pool_mint.supply);
+        let (pool_token_amount, pool_mint_supply) = if current_pool_mint_supply > 0 {
+            (u128::from(pool_token_amount), current_pool_mint_supply)
+        } else {
+            (calculator.new_pool_supply(), calculator.new_pool_supply())
+        };
+
+        let token_a = token_interface::unpack_token_account(&ctx.accounts.token_a)?;
+        let token_b = token_interface::unpack_token_account(&ctx.accounts.token_b)?;
+
+        // Round up so the depositor always pays for the pool tokens in full
+        let results = calculator
+            .pool_tokens_to_trading_tokens(
+                pool_token_amount,
+                pool_mint_supply,
+                u128::from(token_a.amount),
+                u128::from(token_b.amount),
+                RoundDirection::Ceiling,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+        // Gross up by the mints' transfer fees so the vaults receive the full amounts
+        let token_a_amount = to_u64(results.token_a_amount)?;
+        let token_a_amount = token_a_amount
+            .checked_add(token_interface::inverse_transfer_fee(
+                &ctx.accounts.token_a_mint,
+                token_a_amount,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if token_a_amount > maximum_token_a_amount {
+            return Err(error::SwapError::ExceededSlippage.into());
+        }
+        // A side may only be empty for a curve at the bound of its range
+        if token_a_amount == 0 && token_a.amount != 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
+        }
+        let token_b_amount = to_u64(results.token_b_amount)?;
+        let token_b_amount = token_b_amount
+            .checked_add(token_interface::inverse_transfer_fee(
+                &ctx.accounts.token_b_mint,
+                token_b_amount,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if token_b_amount > maximum_token_b_amount {
+            return Err(error::SwapError::ExceededSlippage.into());
+        }
+        // A side may only be empty for a curve at the bound of its range
+        if token_b_amount == 0 && token_b.amount != 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
+        }
+        if token_a_amount == 0 && token_b_amount == 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
+        }
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        let source_a = native_sol.wrap(
+            ctx.accounts.source_a.clone(),
+            &ctx.accounts.token_a_mint,
+            &ctx.accounts.token_a_program,
+            token_a_amount,
+        )?;
+        token_interface::transfer(
+            ctx.accounts.token_a_program.clone(),
+            source_a.clone(),
+            ctx.accounts.token_a_mint.clone(),
+            ctx.accounts.token_a.clone(),
+            ctx.accounts.user_transfer_authority.to_account_info(),
+            &[],
+            token_a_amount,
+        )?;
+        native_sol.unwrap(&source_a, &ctx.accounts.token_a_program)?;
+        let source_b = native_sol.wrap(
+            ctx.accounts.source_b.clone(),
+            &ctx.accounts.token_b_mint,
+            &ctx.accounts.token_b_program,
+            token_b_amount,
+        )?;
+        token_interface::transfer(
+            ctx.accounts.token_b_program.clone(),
+            source_b.clone(),
+            ctx.accounts.token_b_mint.clone(),
+            ctx.accounts.token_b.clone(),
+            ctx.accounts.user_transfer_authority.to_account_info(),
+            &[],
+            token_b_amount,
+        )?;
+        native_sol.unwrap(&source_b, &ctx.accounts.token_b_program)?;
+        token::mint_to(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.destination.to_account_info().clone(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            )
+            .with_signer(&[&seeds[..]]),
+            to_u64(pool_token_amount)?,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn withdraw_all_token_types<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawAllTokenTypes<'info>>,
+        pool_token_amount: u64,
+        minimum_token_a_amount: u64,
+        minimum_token_b_amount: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+
+        let native_sol = NativeSol {
+            user: ctx.accounts.user_transfer_authority.to_account_info(),
+            wsol_account: ctx.accounts.wsol_account.clone(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            bump: *ctx.bumps.get("wsol_account").unwrap(),
+        };
+
+        let (swap_curve, _) = amm.priced_pair_curve(ctx.remaining_accounts)?;
+        let calculator = &swap_curve.calculator;
+
+        let withdraw_fee = if *ctx.accounts.pool_fee_account.to_account_info().key
+            == *ctx.accounts.source.to_account_info().key
         {
-            return Err(error::SwapError::InvalidOutputOwner.into());
+            // withdrawing from the fee account, don't assess withdraw fee
+            0
+        } else {
+            amm.fees
+                .owner_withdraw_fee(u128::from(pool_token_amount))
+                .ok_or(error::SwapError::FeeCalculationFailure)?
+        };
+        let pool_token_amount = u128::from(pool_token_amount)
+            .checked_sub(withdraw_fee)
+            .ok_or(error::SwapError::CalculationFailure)?;
+
+        let token_a = token_interface::unpack_token_account(&ctx.accounts.token_a)?;
+        let token_b = token_interface::unpack_token_account(&ctx.accounts.token_b)?;
+
+        // Round down so the withdrawer never takes more than their share
+        let results = calculator
+            .pool_tokens_to_trading_tokens(
+                pool_token_amount,
+                u128::from(ctx.accounts.pool_mint.supply),
+                u128::from(token_a.amount),
+                u128::from(token_b.amount),
+                RoundDirection::Floor,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+        // Slippage is checked on what's left after the mints' transfer fees
+        let token_a_amount = to_u64(results.token_a_amount)?;
+        let token_a_amount = std::cmp::min(token_a.amount, token_a_amount);
+        let token_a_received = token_a_amount
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_a_mint,
+                token_a_amount,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if token_a_received < minimum_token_a_amount {
+            return Err(error::SwapError::ExceededSlippage.into());
         }
-        if COption::Some(*self.authority.key) != self.pool_mint.mint_authority {
-            return Err(error::SwapError::InvalidOwner.into());
+        if token_a_amount == 0 && token_a.amount != 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
         }
-        if self.token_a.mint == self.token_b.mint {
-            return Err(error::SwapError::RepeatedMint.into());
+        let token_b_amount = to_u64(results.token_b_amount)?;
+        let token_b_amount = std::cmp::min(token_b.amount, token_b_amount);
+        let token_b_received = token_b_amount
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.token_b_mint,
+                token_b_amount,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if token_b_received < minimum_token_b_amount {
+            return Err(error::SwapError::ExceededSlippage.into());
         }
-        // Amm's A token accounts MUST NOT have any delegation
-        if self.token_a.delegate.is_some() || self.token_b.delegate.is_some() {
-            return Err(error::SwapError::InvalidDelegate.into());
+        if token_b_amount == 0 && token_b.amount != 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
         }
-        // Amm's B token accounts MUST NOT have Close Authority
-        if self.token_a.close_authority.is_some() || self.token_b.close_authority.is_some() {
-            return Err(error::SwapError::InvalidCloseAuthority.into());
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        if withdraw_fee > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.clone(),
+                    Transfer {
+                        from: ctx.accounts.source.to_account_info().clone(),
+                        to: ctx.accounts.pool_fee_account.to_account_info().clone(),
+                        authority: ctx
+                            .accounts
+                            .user_transfer_authority
+                            .to_account_info()
+                            .clone(),
+                    },
+                ),
+                to_u64(withdraw_fee)?,
+            )?;
         }
-        // Amm's LP mint supply MUST be 0
-        if self.pool_mint.supply != 0 {
-            return Err(error::SwapError::InvalidSupply.into());
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                Burn {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.source.to_account_info().clone(),
+                    authority: ctx
+                        .accounts
+                        .user_transfer_authority
+                        .to_account_info()
+                        .clone(),
+                },
+            ),
+            to_u64(pool_token_amount)?,
+        )?;
+
+        if token_a_amount > 0 {
+            let destination_a = native_sol.wrap(
+                ctx.accounts.destination_a.clone(),
+                &ctx.accounts.token_a_mint,
+                &ctx.accounts.token_a_program,
+                0,
+            )?;
+            token_interface::transfer(
+                ctx.accounts.token_a_program.clone(),
+                ctx.accounts.token_a.clone(),
+                ctx.accounts.token_a_mint.clone(),
+                destination_a.clone(),
+                ctx.accounts.authority.clone(),
+                &[&seeds[..]],
+                token_a_amount,
+            )?;
+            native_sol.unwrap(&destination_a, &ctx.accounts.token_a_program)?;
         }
-        // Amm's LP mint MUST NOT have Freeze Authority
-        if self.pool_mint.freeze_authority.is_some() {
-            return Err(error::SwapError::InvalidFreezeAuthority.into());
+        if token_b_amount > 0 {
+            let destination_b = native_sol.wrap(
+                ctx.accounts.destination_b.clone(),
+                &ctx.accounts.token_b_mint,
+                &ctx.accounts.token_b_program,
+                0,
+            )?;
+            token_interface::transfer(
+                ctx.accounts.token_b_program.clone(),
+                ctx.accounts.token_b.clone(),
+                ctx.accounts.token_b_mint.clone(),
+                destination_b.clone(),
+                ctx.accounts.authority.clone(),
+                &[&seeds[..]],
+                token_b_amount,
+            )?;
+            native_sol.unwrap(&destination_b, &ctx.accounts.token_b_program)?;
         }
-        // Amm's LP mint pubkey MUST be == input Fee Account's mint
-        if *self.pool_mint.to_account_info().key != self.fee_account.mint {
-            return Err(error::SwapError::IncorrectPoolMint.into());
+
+        Ok(())
+    }
+
+    pub fn deposit_single_token_type_exact_amount_in<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositSingleTokenType<'info>>,
+        source_token_amount: u64,
+        minimum_pool_token_amount: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+
+        let native_sol = NativeSol {
+            user: ctx.accounts.user_transfer_authority.to_account_info(),
+            wsol_account: ctx.accounts.wsol_account.clone(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            bump: *ctx.bumps.get("wsol_account").unwrap(),
+        };
+
+        let trade_direction = if *ctx.accounts.source_mint.key == amm.token_a_mint {
+            TradeDirection::AtoB
+        } else {
+            TradeDirection::BtoA
+        };
+
+        let (swap_curve, _) = amm.priced_pair_curve(ctx.remaining_accounts)?;
+        if !swap_curve.calculator.allows_deposits() {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+
+        let swap_token_a = token_interface::unpack_token_account(&ctx.accounts.swap_token_a)?;
+        let swap_token_b = token_interface::unpack_token_account(&ctx.accounts.swap_token_b)?;
+
+        // Only what's left after the source mint's transfer fee reaches the vault
+        let actual_source_token_amount = source_token_amount
+            .checked_sub(token_interface::transfer_fee(
+                &ctx.accounts.source_mint,
+                source_token_amount,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+        let pool_mint_supply = u128::from(ctx.accounts.pool_mint.supply);
+        let pool_token_amount = if pool_mint_supply > 0 {
+            swap_curve
+                .deposit_single_token_type(
+                    u128::from(actual_source_token_amount),
+                    u128::from(swap_token_a.amount),
+                    u128::from(swap_token_b.amount),
+                    pool_mint_supply,
+                    trade_direction,
+                    &amm.effective_fees(Clock::get()?.unix_timestamp)?,
+                    &amm.imbalance_fee,
+                )
+                .ok_or(error::SwapError::ZeroTradingTokens)?
+        } else {
+            swap_curve.calculator.new_pool_supply()
+        };
+
+        let pool_token_amount = to_u64(pool_token_amount)?;
+        if pool_token_amount < minimum_pool_token_amount {
+            return Err(error::SwapError::ExceededSlippage.into());
         }
+        if pool_token_amount == 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
+        }
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        let swap_token = match trade_direction {
+            TradeDirection::AtoB => ctx.accounts.swap_token_a.clone(),
+            TradeDirection::BtoA => ctx.accounts.swap_token_b.clone(),
+        };
+        let source = native_sol.wrap(
+            ctx.accounts.source.clone(),
+            &ctx.accounts.source_mint,
+            &ctx.accounts.source_token_program,
+            source_token_amount,
+        )?;
+        token_interface::transfer(
+            ctx.accounts.source_token_program.clone(),
+            source.clone(),
+            ctx.accounts.source_mint.clone(),
+            swap_token,
+            ctx.accounts.user_transfer_authority.to_account_info(),
+            &[],
+            source_token_amount,
+        )?;
+        native_sol.unwrap(&source, &ctx.accounts.source_token_program)?;
+        token::mint_to(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.destination.to_account_info().clone(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            )
+            .with_signer(&[&seeds[..]]),
+            pool_token_amount,
+        )?;
+
         Ok(())
     }
-}
 
-#[account]
-pub struct Amm {
-    // LP creator's address
-    pub initializer_key: Pubkey,
-    pub initializer_deposit_token_account: Pubkey,
-    pub initializer_receive_token_account: Pubkey,
-    pub initializer_amount: u64,
-    pub taker_amount: u64,
-    /// Is the swap initialized, with data written to it
-    pub is_initialized: bool,
-    /// Bump seed used to generate the program address / authority
-    pub bump_seed: u8,
-    /// Token program ID associated with the swap
-    pub token_program_id: Pubkey,
-    /// Address of token A liquidity account
-    pub token_a_account: Pubkey,
-    /// Address of token B liquidity account
-    pub token_b_account: Pubkey,
-    /// Address of pool token mint
-    pub pool_mint: Pubkey,
-    /// Address of token A mint
-    pub token_a_mint: Pubkey,
-    /// Address of token B mint
-    pub token_b_mint: Pubkey,
-    /// Address of pool fee account
-    pub pool_fee_account: Pubkey,
-    /// Fees associated with swap
-    pub fees: FeesInput,
-    // pub curve: CurveInput,
-}
+    pub fn withdraw_single_token_type_exact_amount_out<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawSingleTokenType<'info>>,
+        destination_token_amount: u64,
+        maximum_pool_token_amount: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
-pub struct FeesInput {
-    pub trade_fee_numerator: u64,
-    pub trade_fee_denominator: u64,
-    pub owner_trade_fee_numerator: u64,
-    pub owner_trade_fee_denominator: u64,
-    pub owner_withdraw_fee_numerator: u64,
-    pub owner_withdraw_fee_denominator: u64,
-    pub host_fee_numerator: u64,
-    pub host_fee_denominator: u64,
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+
+        let native_sol = NativeSol {
+            user: ctx.accounts.user_transfer_authority.to_account_info(),
+            wsol_account: ctx.accounts.wsol_account.clone(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+            bump: *ctx.bumps.get("wsol_account").unwrap(),
+        };
+
+        let trade_direction = if *ctx.accounts.destination_mint.key == amm.token_a_mint {
+            TradeDirection::AtoB
+        } else {
+            TradeDirection::BtoA
+        };
+
+        let swap_token_a = token_interface::unpack_token_account(&ctx.accounts.swap_token_a)?;
+        let swap_token_b = token_interface::unpack_token_account(&ctx.accounts.swap_token_b)?;
+
+        // Gross up by the destination mint's transfer fee so the user receives the full amount
+        let destination_transfer_amount = destination_token_amount
+            .checked_add(token_interface::inverse_transfer_fee(
+                &ctx.accounts.destination_mint,
+                destination_token_amount,
+            )?)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+        let (swap_curve, _) = amm.priced_pair_curve(ctx.remaining_accounts)?;
+        let burn_pool_token_amount = swap_curve
+            .withdraw_single_token_type_exact_out(
+                u128::from(destination_transfer_amount),
+                u128::from(swap_token_a.amount),
+                u128::from(swap_token_b.amount),
+                u128::from(ctx.accounts.pool_mint.supply),
+                trade_direction,
+                &amm.effective_fees(Clock::get()?.unix_timestamp)?,
+                &amm.imbalance_fee,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+
+        let withdraw_fee = if *ctx.accounts.pool_fee_account.to_account_info().key
+            == *ctx.accounts.source.to_account_info().key
+        {
+            // withdrawing from the fee account, don't assess withdraw fee
+            0
+        } else {
+            amm.fees
+                .owner_withdraw_fee(burn_pool_token_amount)
+                .ok_or(error::SwapError::FeeCalculationFailure)?
+        };
+        let pool_token_amount = burn_pool_token_amount
+            .checked_add(withdraw_fee)
+            .ok_or(error::SwapError::CalculationFailure)?;
+
+        if to_u64(pool_token_amount)? > maximum_pool_token_amount {
+            return Err(error::SwapError::ExceededSlippage.into());
+        }
+        if pool_token_amount == 0 {
+            return Err(error::SwapError::ZeroTradingTokens.into());
+        }
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        if withdraw_fee > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.clone(),
+                    Transfer {
+                        from: ctx.accounts.source.to_account_info().clone(),
+                        to: ctx.accounts.pool_fee_account.to_account_info().clone(),
+                        authority: ctx
+                            .accounts
+                            .user_transfer_authority
+                            .to_account_info()
+                            .clone(),
+                    },
+                ),
+                to_u64(withdraw_fee)?,
+            )?;
+        }
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                Burn {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.source.to_account_info().clone(),
+                    authority: ctx
+                        .accounts
+                        .user_transfer_authority
+                        .to_account_info()
+                        .clone(),
+                },
+            ),
+            to_u64(burn_pool_token_amount)?,
+        )?;
+
+        let swap_token = match trade_direction {
+            TradeDirection::AtoB => ctx.accounts.swap_token_a.clone(),
+            TradeDirection::BtoA => ctx.accounts.swap_token_b.clone(),
+        };
+        let destination = native_sol.wrap(
+            ctx.accounts.destination.clone(),
+            &ctx.accounts.destination_mint,
+            &ctx.accounts.destination_token_program,
+            0,
+        )?;
+        token_interface::transfer(
+            ctx.accounts.destination_token_program.clone(),
+            swap_token,
+            ctx.accounts.destination_mint.clone(),
+            destination.clone(),
+            ctx.accounts.authority.clone(),
+            &[&seeds[..]],
+            destination_transfer_amount,
+        )?;
+        native_sol.unwrap(&destination, &ctx.accounts.destination_token_program)?;
+
+        Ok(())
+    }
+
+    pub fn create_multi_pool<'info>(
+        ctx: Context<'_, '_, '_, 'info, CreateMultiPool<'info>>,
+        fees_input: CurveFees,
+        curve_input: CurveInput,
+        fee_tier: u16,
+        token_mints: Vec<Pubkey>,
+        initial_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        let token_count = token_mints.len();
+        if !(3..=MAX_TOKENS_IN_POOL).contains(&token_count)
+            || initial_token_amounts.len() != token_count
+        {
+            return Err(error::SwapError::InvalidTokenCount.into());
+        }
+        // Mints MUST be sorted so each set of tokens has a single pool per tier
+        if token_mints.windows(2).any(|mints| mints[0] >= mints[1]) {
+            return Err(error::SwapError::InvalidMintOrder.into());
+        }
+
+        // Vaults are PDAs (seed: amm account's pubkey & the token's mint), created
+        // under the token program owning each mint
+        let amm_key = ctx.accounts.amm.key();
+        let token_accounts = split_pool_token_accounts(ctx.remaining_accounts, token_count)?;
+        let mut tokens = Vec::with_capacity(token_count);
+        let mut vault_bumps = Vec::with_capacity(token_count);
+        for (accounts, mint) in token_accounts.iter().zip(&token_mints) {
+            let (account, bump) = Pubkey::find_program_address(
+                &[VAULT_SEED, amm_key.as_ref(), mint.as_ref()],
+                ctx.program_id,
+            );
+            let token = PoolToken {
+                account,
+                mint: *mint,
+                program_id: *accounts.mint.owner,
+            };
+            accounts.validate(&token)?;
+            token_interface::check_mint_extensions(&accounts.mint)?;
+            tokens.push(token);
+            vault_bumps.push(bump);
+        }
+
+        let token_decimals = token_accounts
+            .iter()
+            .map(|accounts| Ok(token_interface::unpack_mint(&accounts.mint)?.decimals))
+            .collect::<Result<Vec<u8>>>()?;
+        let curve_input = curve_input.with_token_decimals(&token_decimals)?;
+
+        // Only stable pools hold more than two tokens
+        curve_input.to_stable_curve()?;
+        // Build the curve from its packed input and make sure both curve & fees are sane
+        let swap_curve = curve_input.to_swap_curve()?;
+        swap_curve.calculator.validate()?;
+        fees_input.validate()?;
+        // ... and that they fit the program owner's constraints
+        ctx.accounts.swap_constraints.validate_curve(&swap_curve)?;
+        ctx.accounts.swap_constraints.validate_fees(&fees_input)?;
+
+        for ((accounts, token), (vault_bump, initial_amount)) in token_accounts
+            .iter()
+            .zip(&tokens)
+            .zip(vault_bumps.iter().zip(&initial_token_amounts))
+        {
+            // Only what's left after the mint's transfer fee reaches the vault
+            let transfer_fee = token_interface::transfer_fee(&accounts.mint, *initial_amount)?;
+            if *initial_amount <= transfer_fee {
+                return Err(error::SwapError::EmptySupply.into());
+            }
+            token_interface::create_vault(
+                ctx.accounts.payer.to_account_info(),
+                accounts.vault.clone(),
+                accounts.mint.clone(),
+                ctx.accounts.authority.clone(),
+                accounts.token_program.clone(),
+                ctx.accounts.system_program.to_account_info(),
+                &[
+                    VAULT_SEED,
+                    amm_key.as_ref(),
+                    token.mint.as_ref(),
+                    &[*vault_bump],
+                ],
+            )?;
+            token_interface::transfer(
+                accounts.token_program.clone(),
+                accounts.user_account.clone(),
+                accounts.mint.clone(),
+                accounts.vault.clone(),
+                ctx.accounts.payer.to_account_info(),
+                &[],
+                *initial_amount,
+            )?;
+        }
+
+        // concatenate swap_authority's seed & bump
+        let bump_seed = *ctx.bumps.get("authority").unwrap();
+        let seeds = &[&amm_key.to_bytes(), &[bump_seed][..]];
+
+        // calc initial LP mint amt
+        let initial_amount = swap_curve.calculator.new_pool_supply();
+
+        token::mint_to(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info(),
+                    to: ctx.accounts.destination.to_account_info(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            )
+            .with_signer(&[&seeds[..]]),
+            to_u64(initial_amount)?,
+        )?;
+
+        let initializer_key = *ctx.accounts.payer.key;
+        let token_program_id = *ctx.accounts.token_program.key;
+        let pool_mint = *ctx.accounts.pool_mint.to_account_info().key;
+        let pool_fee_account = *ctx.accounts.fee_account.to_account_info().key;
+
+        let amm = &mut ctx.accounts.amm;
+        amm.version = AMM_VERSION;
+        amm.initializer_key = initializer_key;
+        amm.is_initialized = true;
+        amm.bump_seed = bump_seed;
+        amm.fee_tier = fee_tier;
+        amm.token_program_id = token_program_id;
+        amm.token_a_account = tokens[0].account;
+        amm.token_b_account = tokens[1].account;
+        amm.pool_mint = pool_mint;
+        amm.token_a_mint = tokens[0].mint;
+        amm.token_b_mint = tokens[1].mint;
+        amm.pool_fee_account = pool_fee_account;
+        amm.fees = fees_input;
+        amm.curve = curve_input;
+        amm.token_a_program_id = tokens[0].program_id;
+        amm.token_b_program_id = tokens[1].program_id;
+        amm.token_count = token_count as u8;
+        amm.extra_tokens[..token_count - 2].copy_from_slice(&tokens[2..]);
+
+        Ok(())
+    }
+
+    pub fn swap_multi<'info>(
+        ctx: Context<'_, '_, '_, 'info, SwapMulti<'info>>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        let vaults = ctx.remaining_accounts;
+        let (source_index, destination_index) = ctx
+            .accounts
+            .validate_input_accounts(swap_authority, vaults)?;
+        let balances = vaults
+            .iter()
+            .map(|vault| {
+                Ok(u128::from(
+                    token_interface::unpack_token_account(vault)?.amount,
+                ))
+            })
+            .collect::<Result<Vec<u128>>>()?;
+
+        // Only what's left after the source mint's transfer fee reaches the vault & gets traded
+        let source_transfer_fee =
+            token_interface::transfer_fee(&ctx.accounts.source_mint, amount_in)?;
+        let actual_amount_in = u128::from(
+            amount_in
+                .checked_sub(source_transfer_fee)
+                .ok_or(error::SwapError::FeeCalculationFailure)?,
+        );
+
+        // Debit the fees from the source amount, as `SwapCurve::swap` does
+        let trade_fee = amm
+            .fees
+            .trading_fee(actual_amount_in)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        let owner_fee = amm
+            .fees
+            .owner_trading_fee(actual_amount_in)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        let source_amount_less_fees = actual_amount_in
+            .checked_sub(trade_fee)
+            .and_then(|amount| amount.checked_sub(owner_fee))
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+        let stable_curve = amm.multi_curve()?;
+        let result = stable_curve
+            .swap_tokens(
+                source_index,
+                destination_index,
+                source_amount_less_fees,
+                &balances,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+
+        // ... and the user receives what's left after the destination mint's transfer fee
+        let destination_amount_swapped = to_u64(result.destination_amount_swapped)?;
+        let destination_transfer_fee = token_interface::transfer_fee(
+            &ctx.accounts.destination_mint,
+            destination_amount_swapped,
+        )?;
+        let amount_received = destination_amount_swapped
+            .checked_sub(destination_transfer_fee)
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+        if amount_received < minimum_amount_out {
+            return Err(error::SwapError::ExceededSlippage.into());
+        }
+
+        // owner fee is paid out as the LP tokens withdrawing it would burn
+        let mut new_balances = balances.clone();
+        new_balances[source_index] = balances[source_index]
+            .checked_add(actual_amount_in)
+            .ok_or(error::SwapError::CalculationFailure)?;
+        new_balances[destination_index] = balances[destination_index]
+            .checked_sub(result.destination_amount_swapped)
+            .ok_or(error::SwapError::CalculationFailure)?;
+        let pool_token_amount = stable_curve
+            .withdraw_token_exact_out(
+                source_index,
+                owner_fee,
+                &new_balances,
+                u128::from(ctx.accounts.pool_mint.supply),
+            )
+            .ok_or(error::SwapError::FeeCalculationFailure)?;
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        token_interface::transfer(
+            ctx.accounts.source_token_program.clone(),
+            ctx.accounts.source_info.clone(),
+            ctx.accounts.source_mint.clone(),
+            vaults[source_index].clone(),
+            ctx.accounts.user_transfer_authority.to_account_info(),
+            &[],
+            amount_in,
+        )?;
+        if pool_token_amount > 0 {
+            token::mint_to(
+                CpiContext::new(
+                    ctx.accounts.token_program.clone(),
+                    MintTo {
+                        mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                        to: ctx.accounts.pool_fee_account.to_account_info().clone(),
+                        authority: ctx.accounts.authority.clone(),
+                    },
+                )
+                .with_signer(&[&seeds[..]]),
+                to_u64(pool_token_amount)?,
+            )?;
+        }
+        token_interface::transfer(
+            ctx.accounts.destination_token_program.clone(),
+            vaults[destination_index].clone(),
+            ctx.accounts.destination_mint.clone(),
+            ctx.accounts.destination.clone(),
+            ctx.accounts.authority.clone(),
+            &[&seeds[..]],
+            destination_amount_swapped,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn deposit_all_token_types_multi<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositAllTokenTypesMulti<'info>>,
+        pool_token_amount: u64,
+        maximum_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+        let tokens = amm.tokens();
+        if maximum_token_amounts.len() != tokens.len() {
+            return Err(error::SwapError::InvalidTokenCount.into());
+        }
+        let token_accounts = split_pool_token_accounts(ctx.remaining_accounts, tokens.len())?;
+        for (accounts, token) in token_accounts.iter().zip(&tokens) {
+            accounts.validate(token)?;
+        }
+
+        let stable_curve = amm.multi_curve()?;
+        let balances = token_accounts
+            .iter()
+            .map(|accounts| {
+                Ok(u128::from(
+                    token_interface::unpack_token_account(&accounts.vault)?.amount,
+                ))
+            })
+            .collect::<Result<Vec<u128>>>()?;
+
+        // Round up so the depositor always pays for the pool tokens in full
+        let results = stable_curve
+            .pool_tokens_to_token_amounts(
+                u128::from(pool_token_amount),
+                u128::from(ctx.accounts.pool_mint.supply),
+                &balances,
+                RoundDirection::Ceiling,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+        let mut token_amounts = Vec::with_capacity(tokens.len());
+        for ((accounts, result), maximum_amount) in token_accounts
+            .iter()
+            .zip(results)
+            .zip(&maximum_token_amounts)
+        {
+            // Gross up by the mint's transfer fee so the vault receives the full amount
+            let token_amount = to_u64(result)?;
+            let token_amount = token_amount
+                .checked_add(token_interface::inverse_transfer_fee(
+                    &accounts.mint,
+                    token_amount,
+                )?)
+                .ok_or(error::SwapError::FeeCalculationFailure)?;
+            if token_amount > *maximum_amount {
+                return Err(error::SwapError::ExceededSlippage.into());
+            }
+            if token_amount == 0 {
+                return Err(error::SwapError::ZeroTradingTokens.into());
+            }
+            token_amounts.push(token_amount);
+        }
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        for (accounts, token_amount) in token_accounts.iter().zip(token_amounts) {
+            token_interface::transfer(
+                accounts.token_program.clone(),
+                accounts.user_account.clone(),
+                accounts.mint.clone(),
+                accounts.vault.clone(),
+                ctx.accounts.user_transfer_authority.to_account_info(),
+                &[],
+                token_amount,
+            )?;
+        }
+        token::mint_to(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.destination.to_account_info().clone(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            )
+            .with_signer(&[&seeds[..]]),
+            pool_token_amount,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn withdraw_all_token_types_multi<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawAllTokenTypesMulti<'info>>,
+        pool_token_amount: u64,
+        minimum_token_amounts: Vec<u64>,
+    ) -> Result<()> {
+        let amm = &ctx.accounts.amm;
+
+        // Rebuild swap_authority address from the amm's pubkey & stored bump seed
+        let bump_seed = amm.bump_seed;
+        let swap_authority = authority_id(ctx.program_id, amm.to_account_info().key, bump_seed)?;
+
+        ctx.accounts.validate_input_accounts(swap_authority)?;
+        let tokens = amm.tokens();
+        if minimum_token_amounts.len() != tokens.len() {
+            return Err(error::SwapError::InvalidTokenCount.into());
+        }
+        let token_accounts = split_pool_token_accounts(ctx.remaining_accounts, tokens.len())?;
+        for (accounts, token) in token_accounts.iter().zip(&tokens) {
+            accounts.validate(token)?;
+        }
+
+        let stable_curve = amm.multi_curve()?;
+
+        let withdraw_fee = if *ctx.accounts.pool_fee_account.to_account_info().key
+            == *ctx.accounts.source.to_account_info().key
+        {
+            // withdrawing from the fee account, don't assess withdraw fee
+            0
+        } else {
+            amm.fees
+                .owner_withdraw_fee(u128::from(pool_token_amount))
+                .ok_or(error::SwapError::FeeCalculationFailure)?
+        };
+        let pool_token_amount = u128::from(pool_token_amount)
+            .checked_sub(withdraw_fee)
+            .ok_or(error::SwapError::CalculationFailure)?;
+
+        let balances = token_accounts
+            .iter()
+            .map(|accounts| {
+                Ok(u128::from(
+                    token_interface::unpack_token_account(&accounts.vault)?.amount,
+                ))
+            })
+            .collect::<Result<Vec<u128>>>()?;
+
+        // Round down so the withdrawer never takes more than their share
+        let results = stable_curve
+            .pool_tokens_to_token_amounts(
+                pool_token_amount,
+                u128::from(ctx.accounts.pool_mint.supply),
+                &balances,
+                RoundDirection::Floor,
+            )
+            .ok_or(error::SwapError::ZeroTradingTokens)?;
+        let mut token_amounts = Vec::with_capacity(tokens.len());
+        for (((accounts, result), balance), minimum_amount) in token_accounts
+            .iter()
+            .zip(results)
+            .zip(&balances)
+            .zip(&minimum_token_amounts)
+        {
+            // Slippage is checked on what's left after the mint's transfer fee
+            let token_amount = std::cmp::min(*balance, result);
+            let token_amount = to_u64(token_amount)?;
+            let token_received = token_amount
+                .checked_sub(token_interface::transfer_fee(&accounts.mint, token_amount)?)
+                .ok_or(error::SwapError::FeeCalculationFailure)?;
+            if token_received < *minimum_amount {
+                return Err(error::SwapError::ExceededSlippage.into());
+            }
+            if token_amount == 0 && *balance != 0 {
+                return Err(error::SwapError::ZeroTradingTokens.into());
+            }
+            token_amounts.push(token_amount);
+        }
+
+        // concatenate swap_authority's seed & bump
+        let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+        if withdraw_fee > 0 {
+            token::transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.clone(),
+                    Transfer {
+                        from: ctx.accounts.source.to_account_info().clone(),
+                        to: ctx.accounts.pool_fee_account.to_account_info().clone(),
+                        authority: ctx
+                            .accounts
+                            .user_transfer_authority
+                            .to_account_info()
+                            .clone(),
+                    },
+                ),
+                to_u64(withdraw_fee)?,
+            )?;
+        }
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                Burn {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.source.to_account_info().clone(),
+                    authority: ctx
+                        .accounts
+                        .user_transfer_authority
+                        .to_account_info()
+                        .clone(),
+                },
+            ),
+            to_u64(pool_token_amount)?,
+        )?;
+
+        for (accounts, token_amount) in token_accounts.iter().zip(token_amounts) {
+            if token_amount > 0 {
+                token_interface::transfer(
+                    accounts.token_program.clone(),
+                    accounts.vault.clone(),
+                    accounts.mint.clone(),
+                    accounts.user_account.clone(),
+                    ctx.accounts.authority.clone(),
+                    &[&seeds[..]],
+                    token_amount,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn initialize_swap_constraints(
+        ctx: Context<InitializeSwapConstraints>,
+        owner_key: Pubkey,
+        valid_curve_types: Vec<u8>,
+        fees: CurveFees,
+    ) -> Result<()> {
+        SwapConstraints::validate_curve_types(&valid_curve_types)?;
+        fees.validate()?;
+
+        let swap_constraints = &mut ctx.accounts.swap_constraints;
+        swap_constraints.owner_key = owner_key;
+        swap_constraints.valid_curve_types = valid_curve_types;
+        swap_constraints.fees = fees;
+        swap_constraints.bump_seed = *ctx.bumps.get("swap_constraints").unwrap();
+
+        Ok(())
+    }
+
+    pub fn update_swap_constraints(
+        ctx: Context<UpdateSwapConstraints>,
+        owner_key: Pubkey,
+        valid_curve_types: Vec<u8>,
+        fees: CurveFees,
+    ) -> Result<()> {
+        SwapConstraints::validate_curve_types(&valid_curve_types)?;
+        fees.validate()?;
+
+        let swap_constraints = &mut ctx.accounts.swap_constraints;
+        swap_constraints.owner_key = owner_key;
+        swap_constraints.valid_curve_types = valid_curve_types;
+        swap_constraints.fees = fees;
+
+        Ok(())
+    }
+
+    pub fn ramp_amp(ctx: Context<RampAmp>, target_amp: u32, stop_ramp_ts: i64) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+        let now = Clock::get()?.unix_timestamp;
+        let stable_curve = amm
+            .curve
+            .to_stable_curve()?
+            .ramp(target_amp, stop_ramp_ts, now)?;
+        Pack::pack_into_slice(&stable_curve, &mut amm.curve.curve_parameters);
+
+        Ok(())
+    }
+
+    pub fn stop_ramp_amp(ctx: Context<RampAmp>) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+        let now = Clock::get()?.unix_timestamp;
+        let stable_curve = amm.curve.to_stable_curve()?.stop_ramp(now)?;
+        Pack::pack_into_slice(&stable_curve, &mut amm.curve.curve_parameters);
+
+        Ok(())
+    }
+
+    pub fn set_price_oracle(ctx: Context<SetPriceOracle>) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+        if amm.curve.curve_type != CurveType::ConstantPrice as u8 || amm.token_count > 2 {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+
+        // The oracle MUST already publish a usable price
+        let price_decimals_offset = i8::try_from(
+            i16::from(token_interface::unpack_mint(&ctx.accounts.token_a_mint)?.decimals)
+                - i16::from(token_interface::unpack_mint(&ctx.accounts.token_b_mint)?.decimals),
+        )
+        .map_err(|_| error::SwapError::ConversionFailure)?;
+        OraclePrice::load(&ctx.accounts.price_oracle, Clock::get()?.unix_timestamp)?
+            .token_b_price(price_decimals_offset)
+            .ok_or(error::SwapError::InvalidOracle)?;
+
+        amm.price_oracle = ctx.accounts.price_oracle.key();
+        amm.price_decimals_offset = price_decimals_offset;
+
+        Ok(())
+    }
+
+    pub fn set_dynamic_fee(
+        ctx: Context<SetDynamicFee>,
+        base_fee_numerator: u32,
+        max_fee_numerator: u32,
+        decay_period: u32,
+    ) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+        // Multi-token pools only charge their static trade fee
+        if amm.token_count > 2 {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+        // A zero maximum fee turns the mode off
+        let dynamic_fee = if max_fee_numerator == 0 {
+            DynamicFee::default()
+        } else {
+            DynamicFee::new(base_fee_numerator, max_fee_numerator, decay_period)?
+        };
+        ctx.accounts
+            .swap_constraints
+            .validate_dynamic_fee(&dynamic_fee)?;
+        // The imbalance fee raises whichever trade fee is charged
+        amm.imbalance_fee
+            .validate(&dynamic_fee.max_fees(&amm.fees))?;
+        amm.dynamic_fee = dynamic_fee;
+
+        Ok(())
+    }
+
+    pub fn set_imbalance_fee(ctx: Context<SetImbalanceFee>, multiplier: u16) -> Result<()> {
+        let amm = &mut ctx.accounts.amm;
+        // Only stable pools of two tokens price trades around a balance
+        if amm.curve.curve_type != CurveType::Stable as u8 || amm.token_count > 2 {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+        // A zero multiplier turns the mode off
+        let imbalance_fee = ImbalanceFee::new(multiplier)?;
+        // ... up to the highest trade fee of the dynamic fee, if enabled
+        imbalance_fee.validate(&amm.dynamic_fee.max_fees(&amm.fees))?;
+        amm.imbalance_fee = imbalance_fee;
+
+        Ok(())
+    }
+
+    pub fn migrate_amm(ctx: Context<MigrateAmm>) -> Result<()> {
+        let amm_info = ctx.accounts.amm.to_account_info();
+
+        // Read the amm back from whichever layout it's in
+        let amm = {
+            let data = amm_info.try_borrow_data()?;
+            if data.len() < 8 || data[..8] != Amm::discriminator() {
+                return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+            }
+            // Versioned layouts only grow into their zeroed reserved space or
+            // past their end, so older ones read back zero-extended & dispatch
+            // on `version`
+            if [Amm::LEN, Amm::V3_LEN, Amm::V2_LEN].contains(&data.len()) {
+                let mut data = data.to_vec();
+                data.resize(Amm::LEN, 0);
+                let mut amm = Amm::try_deserialize(&mut &data[..])?;
+                if amm.version >= AMM_VERSION {
+                    return Err(error::SwapError::AlreadyMigrated.into());
+                }
+                // Version 1 predates Token-2022 support, both sides hold Token mints
+                if amm.version < 2 {
+                    amm.token_a_program_id = amm.token_program_id;
+                    amm.token_b_program_id = amm.token_program_id;
+                }
+                // Versions 1 & 2 predate pools of more than two tokens
+                if amm.version < 3 {
+                    amm.token_count = 2;
+                }
+                amm.version = AMM_VERSION;
+                amm
+            } else {
+                Amm::from(AmmV0::unpack(&data[8..])?)
+            }
+        };
+
+        // Top up rent for the larger layout
+        let lamports = Rent::get()?
+            .minimum_balance(Amm::LEN)
+            .saturating_sub(amm_info.lamports());
+        if lamports > 0 {
+            invoke(
+                &system_instruction::transfer(ctx.accounts.payer.key, amm_info.key, lamports),
+                &[
+                    ctx.accounts.payer.to_account_info(),
+                    amm_info.clone(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+
+        amm_info.realloc(Amm::LEN, false)?;
+        let mut data = amm_info.try_borrow_mut_data()?;
+        amm.try_serialize(&mut &mut data[..])?;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(fees_input: CurveFees, curve_input: CurveInput, fee_tier: u16)]
+pub struct Initialize<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey) to let program manipulate swap related features for all lp pools
+    pub authority: AccountInfo<'info>,
+    // token A's mint, owned by the Token or Token-2022 program
+    pub token_a_mint: AccountInfo<'info>,
+    // token B's mint, MUST sort after token A's so each pair has a single pool per tier
+    #[account(constraint = token_a_mint.key() < token_b_mint.key() @ error::SwapError::InvalidMintOrder)]
+    pub token_b_mint: AccountInfo<'info>,
+    // amm's token A account, owned by the same token program as its mint
+    #[account(mut)]
+    pub token_a: AccountInfo<'info>,
+    // amm's token B account, owned by the same token program as its mint
+    #[account(mut)]
+    pub token_b: AccountInfo<'info>,
+    // amm: A PDA (seed: both mints, curve type & fee tier)
+    #[account(
+        init,
+        payer = payer,
+        space = Amm::LEN,
+        seeds = [
+            AMM_SEED,
+            token_a_mint.key().as_ref(),
+            token_b_mint.key().as_ref(),
+            &[curve_input.curve_type],
+            &fee_tier.to_le_bytes()
+        ],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    #[account(mut)]
+    pub fee_account: Account<'info, TokenAccount>,
+    // The LP token ATA to which the initial LP token is sent (Owner MUST be authority)
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_program: AccountInfo<'info>,
+    #[account(seeds = [SWAP_CONSTRAINTS_SEED], bump = swap_constraints.bump_seed)]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+    // Pays for the amm account
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> Initialize<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if self.amm.is_initialized {
+            return Err(error::SwapError::AlreadyInUse.into());
+        }
+        // Verify if input authority pubkey is valid
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != token::ID {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        let token_a = token_interface::unpack_token_account(&self.token_a)?;
+        let token_b = token_interface::unpack_token_account(&self.token_b)?;
+        if token_a.mint != *self.token_a_mint.key || token_b.mint != *self.token_b_mint.key {
+            return Err(error::SwapError::IncorrectMint.into());
+        }
+        token_interface::check_mint_extensions(&self.token_a_mint)?;
+        token_interface::check_mint_extensions(&self.token_b_mint)?;
+        if *self.authority.key != token_a.owner || *self.authority.key != token_b.owner {
+            return Err(error::SwapError::InvalidOwner.into());
+        }
+        // Pool fees MUST go to the program owner
+        if self.fee_account.owner != self.swap_constraints.owner_key {
+            return Err(error::SwapError::InvalidOwner.into());
+        }
+        // TODO: What is destination??
+        if *self.authority.key == self.fee_account.owner
+            && *self.authority.key == self.destination.owner
+        {
+            return Err(error::SwapError::InvalidOutputOwner.into());
+        }
+        if COption::Some(*self.authority.key) != self.pool_mint.mint_authority {
+            return Err(error::SwapError::InvalidOwner.into());
+        }
+        if token_a.mint == token_b.mint {
+            return Err(error::SwapError::RepeatedMint.into());
+        }
+        // Amm's A token accounts MUST NOT have any delegation
+        if token_a.delegate.is_some() || token_b.delegate.is_some() {
+            return Err(error::SwapError::InvalidDelegate.into());
+        }
+        // Amm's B token accounts MUST NOT have Close Authority
+        if token_a.close_authority.is_some() || token_b.close_authority.is_some() {
+            return Err(error::SwapError::InvalidCloseAuthority.into());
+        }
+        // Amm's LP mint supply MUST be 0
+        if self.pool_mint.supply != 0 {
+            return Err(error::SwapError::InvalidSupply.into());
+        }
+        // Amm's LP mint MUST NOT have Freeze Authority
+        if self.pool_mint.freeze_authority.is_some() {
+            return Err(error::SwapError::InvalidFreezeAuthority.into());
+        }
+        // Amm's LP mint pubkey MUST be == input Fee Account's mint
+        if *self.pool_mint.to_account_info().key != self.fee_account.mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(fees_input: CurveFees, curve_input: CurveInput, fee_tier: u16)]
+pub struct CreatePool<'info> {
+    // Pays for every account created & provides the initial liquidity
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    // token A's mint, owned by the Token or Token-2022 program
+    pub token_a_mint: AccountInfo<'info>,
+    // token B's mint, MUST sort after token A's so each pair has a single pool per tier
+    #[account(constraint = token_a_mint.key() < token_b_mint.key() @ error::SwapError::InvalidMintOrder)]
+    pub token_b_mint: AccountInfo<'info>,
+    // amm: A PDA (seed: both mints, curve type & fee tier)
+    #[account(
+        init,
+        payer = payer,
+        space = Amm::LEN,
+        seeds = [
+            AMM_SEED,
+            token_a_mint.key().as_ref(),
+            token_b_mint.key().as_ref(),
+            &[curve_input.curve_type],
+            &fee_tier.to_le_bytes()
+        ],
+        bump
+    )]
+    pub amm: Box<Account<'info, Amm>>,
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    #[account(seeds = [amm.key().as_ref()], bump)]
+    pub authority: AccountInfo<'info>,
+    // amm's token A account: A PDA (seed: amm account's pubkey & token A mint), created
+    // under token A's token program
+    #[account(mut, seeds = [VAULT_SEED, amm.key().as_ref(), token_a_mint.key().as_ref()], bump)]
+    pub token_a: AccountInfo<'info>,
+    // amm's token B account: A PDA (seed: amm account's pubkey & token B mint), created
+    // under token B's token program
+    #[account(mut, seeds = [VAULT_SEED, amm.key().as_ref(), token_b_mint.key().as_ref()], bump)]
+    pub token_b: AccountInfo<'info>,
+    // amm's LP mint: A PDA (seed: amm account's pubkey)
+    #[account(
+        init,
+        payer = payer,
+        seeds = [POOL_MINT_SEED, amm.key().as_ref()],
+        bump,
+        mint::decimals = POOL_MINT_DECIMALS,
+        mint::authority = authority
+    )]
+    pub pool_mint: Box<Account<'info, Mint>>,
+    #[account(seeds = [SWAP_CONSTRAINTS_SEED], bump = swap_constraints.bump_seed)]
+    pub swap_constraints: Box<Account<'info, SwapConstraints>>,
+    // Program owner, receiving the pool fees
+    #[account(address = swap_constraints.owner_key @ error::SwapError::InvalidOwner)]
+    pub fee_owner: AccountInfo<'info>,
+    // amm's pool fee account: A PDA (seed: amm account's pubkey) owned by the program owner
+    #[account(
+        init,
+        payer = payer,
+        seeds = [FEE_ACCOUNT_SEED, amm.key().as_ref()],
+        bump,
+        token::mint = pool_mint,
+        token::authority = fee_owner
+    )]
+    pub fee_account: Box<Account<'info, TokenAccount>>,
+    // payer's LP token ATA to which the initial LP token is sent
+    #[account(
+        init,
+        payer = payer,
+        associated_token::mint = pool_mint,
+        associated_token::authority = payer
+    )]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    // payer's token A account funding the pool
+    #[account(mut)]
+    pub source_a: AccountInfo<'info>,
+    // payer's token B account funding the pool
+    #[account(mut)]
+    pub source_b: AccountInfo<'info>,
+    // token program owning token A's mint
+    #[account(address = *token_a_mint.owner @ error::SwapError::IncorrectTokenProgramId)]
+    pub token_a_program: AccountInfo<'info>,
+    // token program owning token B's mint
+    #[account(address = *token_b_mint.owner @ error::SwapError::IncorrectTokenProgramId)]
+    pub token_b_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    // amm, recording the swap's price movement when it has a dynamic fee
+    #[account(mut)]
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source_info, signing for the transfer into the amm
+    pub user_transfer_authority: Signer<'info>,
+    // user's token account to swap from
+    #[account(mut)]
+    pub source_info: AccountInfo<'info>,
+    // amm's token account receiving the source token
+    #[account(mut)]
+    pub swap_source: AccountInfo<'info>,
+    // amm's token account paying out the destination token
+    #[account(mut)]
+    pub swap_destination: AccountInfo<'info>,
+    // user's token account receiving the destination token
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // LP token account receiving the owner trade fee
+    #[account(mut)]
+    pub pool_fee_account: Account<'info, TokenAccount>,
+    // mint of the source token
+    pub source_mint: AccountInfo<'info>,
+    // mint of the destination token
+    pub destination_mint: AccountInfo<'info>,
+    // token program of the source token
+    pub source_token_program: AccountInfo<'info>,
+    // token program of the destination token
+    pub destination_token_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Temporary wSOL account: A PDA (seed: user transfer authority's pubkey), only used when
+    // the user's wallet stands in for a native SOL source or destination
+    #[account(mut, seeds = [WSOL_SEED, user_transfer_authority.key().as_ref()], bump)]
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    // Remaining accounts: the amm's price oracle if it has one, then optionally the host LP
+    // token account receiving the host fee
+}
+
+impl<'info> Swap<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        let swap_source = *self.swap_source.to_account_info().key;
+        let swap_destination = *self.swap_destination.to_account_info().key;
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        // Swap vaults MUST be the amm's A & B token accounts, one each
+        if !(swap_source == self.amm.token_a_account
+            && swap_destination == self.amm.token_b_account
+            || swap_source == self.amm.token_b_account
+                && swap_destination == self.amm.token_a_account)
+        {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        // Mints & token programs MUST be the ones of the vaults' sides
+        let ((source_mint, source_token_program), (destination_mint, destination_token_program)) =
+            if swap_source == self.amm.token_a_account {
+                (
+                    (self.amm.token_a_mint, self.amm.token_a_program_id),
+                    (self.amm.token_b_mint, self.amm.token_b_program_id),
+                )
+            } else {
+                (
+                    (self.amm.token_b_mint, self.amm.token_b_program_id),
+                    (self.amm.token_a_mint, self.amm.token_a_program_id),
+                )
+            };
+        if *self.source_mint.key != source_mint || *self.destination_mint.key != destination_mint {
+            return Err(error::SwapError::IncorrectMint.into());
+        }
+        if *self.source_token_program.key != source_token_program
+            || *self.destination_token_program.key != destination_token_program
+        {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        // User's accounts MUST NOT be the amm's vaults
+        if *self.source_info.to_account_info().key == swap_source {
+            return Err(error::SwapError::InvalidInput.into());
+        }
+        if *self.destination.to_account_info().key == swap_destination {
+            return Err(error::SwapError::InvalidOutput.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        if *self.pool_fee_account.to_account_info().key != self.amm.pool_fee_account {
+            return Err(error::SwapError::IncorrectFeeAccount.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct RouteSwap<'info> {
+    // Owner or delegate of source_info, signing for the transfer into the first amm
+    pub user_transfer_authority: Signer<'info>,
+    // user's token account to swap from
+    #[account(mut)]
+    pub source_info: AccountInfo<'info>,
+    // user's token account receiving the last amm's destination token
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+    // mint of the source token
+    pub source_mint: AccountInfo<'info>,
+    // token program of the source token
+    pub source_token_program: AccountInfo<'info>,
+    // token program of the pool tokens of every amm on the route
+    pub token_program: AccountInfo<'info>,
+    // Temporary wSOL account: A PDA (seed: user transfer authority's pubkey), only used when
+    // the user's wallet stands in for a native SOL source or destination
+    #[account(mut, seeds = [WSOL_SEED, user_transfer_authority.key().as_ref()], bump)]
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    // Remaining accounts: `ROUTE_HOP_ACCOUNTS` per amm on the route, in order, see `RouteHop`
+}
+
+/// Number of remaining accounts describing each hop of a routed swap
+pub const ROUTE_HOP_ACCOUNTS: usize = 8;
+
+/// Accounts of one amm on a swap route, in their order in the remaining
+/// accounts. The hop's source mint & token program are the previous hop's
+/// destination ones, or the route's source ones for the first hop.
+pub struct RouteHop<'info> {
+    /// Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    /// Amm, writable when it has a dynamic fee
+    pub amm: Account<'info, Amm>,
+    /// Writable amm's token account receiving the source token
+    pub swap_source: AccountInfo<'info>,
+    /// Writable amm's token account paying out the destination token
+    pub swap_destination: AccountInfo<'info>,
+    /// Writable pool token mint
+    pub pool_mint: Account<'info, Mint>,
+    /// Writable LP token account receiving the owner trade fee
+    pub pool_fee_account: AccountInfo<'info>,
+    /// Mint of the destination token
+    pub destination_mint: AccountInfo<'info>,
+    /// Token program of the destination token
+    pub destination_token_program: AccountInfo<'info>,
+}
+
+impl<'info> RouteHop<'info> {
+    /// Pays `amount` out of the amm's destination vault, signed by its authority
+    fn transfer_out(&self, destination: AccountInfo<'info>, amount: u64) -> Result<()> {
+        let amm_key = self.amm.key();
+        let seeds = &[&amm_key.to_bytes(), &[self.amm.bump_seed][..]];
+        token_interface::transfer(
+            self.destination_token_program.clone(),
+            self.swap_destination.clone(),
+            self.destination_mint.clone(),
+            destination,
+            self.authority.clone(),
+            &[&seeds[..]],
+            amount,
+        )
+    }
+}
+
+impl<'info> RouteSwap<'info> {
+    /// Reads the hops from the remaining accounts, checking each against its
+    /// amm the way `Swap` does & that every hop trades the previous one's output
+    fn validate_route(
+        &self,
+        program_id: &Pubkey,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<Vec<RouteHop<'info>>> {
+        let hop_accounts = remaining_accounts.chunks_exact(ROUTE_HOP_ACCOUNTS);
+        if remaining_accounts.is_empty() || !hop_accounts.remainder().is_empty() {
+            return Err(error::SwapError::InvalidInstruction.into());
+        }
+        // The temporary wSOL account is closed once the first hop is paid, so
+        // it can't be opened again for the route's output
+        let user = self.user_transfer_authority.key();
+        if *self.source_info.key == user && *self.destination.key == user {
+            return Err(error::SwapError::NativeSolRoundTrip.into());
+        }
+
+        let mut hops: Vec<RouteHop<'info>> = Vec::new();
+        for accounts in hop_accounts {
+            let hop = RouteHop {
+                authority: accounts[0].clone(),
+                amm: Account::try_from(&accounts[1])?,
+                swap_source: accounts[2].clone(),
+                swap_destination: accounts[3].clone(),
+                pool_mint: Account::try_from(&accounts[4])?,
+                pool_fee_account: accounts[5].clone(),
+                destination_mint: accounts[6].clone(),
+                destination_token_program: accounts[7].clone(),
+            };
+            let amm = &hop.amm;
+
+            // Vault balances are read once per hop, so an amm can only be traded once
+            if hops
+                .iter()
+                .any(|previous_hop| previous_hop.amm.key() == amm.key())
+            {
+                return Err(error::SwapError::RepeatedAmm.into());
+            }
+            if *hop.authority.key != authority_id(program_id, &amm.key(), amm.bump_seed)? {
+                return Err(error::SwapError::InvalidProgramAddress.into());
+            }
+            if *self.token_program.key != amm.token_program_id {
+                return Err(error::SwapError::IncorrectTokenProgramId.into());
+            }
+            // Swap vaults MUST be the amm's A & B token accounts, one each
+            let swap_source = *hop.swap_source.key;
+            let swap_destination = *hop.swap_destination.key;
+            if !(swap_source == amm.token_a_account && swap_destination == amm.token_b_account
+                || swap_source == amm.token_b_account && swap_destination == amm.token_a_account)
+            {
+                return Err(error::SwapError::IncorrectSwapAccount.into());
+            }
+            // Mints & token programs MUST be the ones of the vaults' sides, the source
+            // side being the previous hop's destination
+            let (
+                (source_mint, source_token_program),
+                (destination_mint, destination_token_program),
+            ) = if swap_source == amm.token_a_account {
+                (
+                    (amm.token_a_mint, amm.token_a_program_id),
+                    (amm.token_b_mint, amm.token_b_program_id),
+                )
+            } else {
+                (
+                    (amm.token_b_mint, amm.token_b_program_id),
+                    (amm.token_a_mint, amm.token_a_program_id),
+                )
+            };
+            let (hop_source_mint, hop_source_token_program) = match hops.last() {
+                Some(previous_hop) => (
+                    *previous_hop.destination_mint.key,
+                    *previous_hop.destination_token_program.key,
+                ),
+                None => (*self.source_mint.key, *self.source_token_program.key),
+            };
+            if hop_source_mint != source_mint || *hop.destination_mint.key != destination_mint {
+                return Err(error::SwapError::IncorrectMint.into());
+            }
+            if hop_source_token_program != source_token_program
+                || *hop.destination_token_program.key != destination_token_program
+            {
+                return Err(error::SwapError::IncorrectTokenProgramId.into());
+            }
+            if *hop.pool_mint.to_account_info().key != amm.pool_mint {
+                return Err(error::SwapError::IncorrectPoolMint.into());
+            }
+            if *hop.pool_fee_account.key != amm.pool_fee_account {
+                return Err(error::SwapError::IncorrectFeeAccount.into());
+            }
+            hops.push(hop);
+        }
+
+        // User's accounts MUST NOT be the first & last amms' vaults
+        if *self.source_info.key == *hops[0].swap_source.key {
+            return Err(error::SwapError::InvalidInput.into());
+        }
+        if *self.destination.key == *hops[hops.len() - 1].swap_destination.key {
+            return Err(error::SwapError::InvalidOutput.into());
+        }
+        Ok(hops)
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositAllTokenTypes<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source_a & source_b
+    pub user_transfer_authority: Signer<'info>,
+    // user's token A account to deposit from
+    #[account(mut)]
+    pub source_a: AccountInfo<'info>,
+    // user's token B account to deposit from
+    #[account(mut)]
+    pub source_b: AccountInfo<'info>,
+    // amm's token A account
+    #[account(mut)]
+    pub token_a: AccountInfo<'info>,
+    // amm's token B account
+    #[account(mut)]
+    pub token_b: AccountInfo<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // user's LP token account receiving the minted pool tokens
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_a_mint: AccountInfo<'info>,
+    pub token_b_mint: AccountInfo<'info>,
+    // token program of token A
+    pub token_a_program: AccountInfo<'info>,
+    // token program of token B
+    pub token_b_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Temporary wSOL account: A PDA (seed: user transfer authority's pubkey), only used when
+    // the user's wallet stands in for a native SOL source or destination
+    #[account(mut, seeds = [WSOL_SEED, user_transfer_authority.key().as_ref()], bump)]
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    // Remaining account: the amm's price oracle if it has one
+}
+
+impl<'info> DepositAllTokenTypes<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.token_a.to_account_info().key != self.amm.token_a_account
+            || *self.token_b.to_account_info().key != self.amm.token_b_account
+        {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        if *self.token_a_mint.key != self.amm.token_a_mint
+            || *self.token_b_mint.key != self.amm.token_b_mint
+        {
+            return Err(error::SwapError::IncorrectMint.into());
+        }
+        if *self.token_a_program.key != self.amm.token_a_program_id
+            || *self.token_b_program.key != self.amm.token_b_program_id
+        {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        // User's accounts MUST NOT be the amm's vaults
+        if *self.source_a.to_account_info().key == self.amm.token_a_account
+            || *self.source_b.to_account_info().key == self.amm.token_b_account
+        {
+            return Err(error::SwapError::InvalidInput.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawAllTokenTypes<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source
+    pub user_transfer_authority: Signer<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // user's LP token account to burn from
+    #[account(mut)]
+    pub source: Account<'info, TokenAccount>,
+    // amm's token A account
+    #[account(mut)]
+    pub token_a: AccountInfo<'info>,
+    // amm's token B account
+    #[account(mut)]
+    pub token_b: AccountInfo<'info>,
+    // user's token A account receiving the withdrawal
+    #[account(mut)]
+    pub destination_a: AccountInfo<'info>,
+    // user's token B account receiving the withdrawal
+    #[account(mut)]
+    pub destination_b: AccountInfo<'info>,
+    // LP token account receiving the owner withdraw fee
+    #[account(mut)]
+    pub pool_fee_account: Account<'info, TokenAccount>,
+    pub token_a_mint: AccountInfo<'info>,
+    pub token_b_mint: AccountInfo<'info>,
+    // token program of token A
+    pub token_a_program: AccountInfo<'info>,
+    // token program of token B
+    pub token_b_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Temporary wSOL account: A PDA (seed: user transfer authority's pubkey), only used when
+    // the user's wallet stands in for a native SOL source or destination
+    #[account(mut, seeds = [WSOL_SEED, user_transfer_authority.key().as_ref()], bump)]
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    // Remaining account: the amm's price oracle if it has one
+}
+
+impl<'info> WithdrawAllTokenTypes<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.token_a.to_account_info().key != self.amm.token_a_account
+            || *self.token_b.to_account_info().key != self.amm.token_b_account
+        {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        if *self.token_a_mint.key != self.amm.token_a_mint
+            || *self.token_b_mint.key != self.amm.token_b_mint
+        {
+            return Err(error::SwapError::IncorrectMint.into());
+        }
+        if *self.token_a_program.key != self.amm.token_a_program_id
+            || *self.token_b_program.key != self.amm.token_b_program_id
+        {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        if *self.pool_fee_account.to_account_info().key != self.amm.pool_fee_account {
+            return Err(error::SwapError::IncorrectFeeAccount.into());
+        }
+        // User's accounts MUST NOT be the amm's vaults
+        if *self.destination_a.to_account_info().key == self.amm.token_a_account
+            || *self.destination_b.to_account_info().key == self.amm.token_b_account
+        {
+            return Err(error::SwapError::InvalidOutput.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositSingleTokenType<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source
+    pub user_transfer_authority: Signer<'info>,
+    // user's token A or B account to deposit from
+    #[account(mut)]
+    pub source: AccountInfo<'info>,
+    // amm's token A account
+    #[account(mut)]
+    pub swap_token_a: AccountInfo<'info>,
+    // amm's token B account
+    #[account(mut)]
+    pub swap_token_b: AccountInfo<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // user's LP token account receiving the minted pool tokens
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    // mint of the deposited token, picks the side of the pool
+    pub source_mint: AccountInfo<'info>,
+    // token program of the deposited token
+    pub source_token_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Temporary wSOL account: A PDA (seed: user transfer authority's pubkey), only used when
+    // the user's wallet stands in for a native SOL source or destination
+    #[account(mut, seeds = [WSOL_SEED, user_transfer_authority.key().as_ref()], bump)]
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    // Remaining account: the amm's price oracle if it has one
+}
+
+impl<'info> DepositSingleTokenType<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.swap_token_a.to_account_info().key != self.amm.token_a_account
+            || *self.swap_token_b.to_account_info().key != self.amm.token_b_account
+        {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        // Source mint picks the side, its token program MUST be that side's
+        let source_token_program = if *self.source_mint.key == self.amm.token_a_mint {
+            self.amm.token_a_program_id
+        } else if *self.source_mint.key == self.amm.token_b_mint {
+            self.amm.token_b_program_id
+        } else {
+            return Err(error::SwapError::IncorrectMint.into());
+        };
+        if *self.source_token_program.key != source_token_program {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        // User's account MUST NOT be one of the amm's vaults
+        let source = *self.source.to_account_info().key;
+        if source == self.amm.token_a_account || source == self.amm.token_b_account {
+            return Err(error::SwapError::InvalidInput.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawSingleTokenType<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source
+    pub user_transfer_authority: Signer<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // user's LP token account to burn from
+    #[account(mut)]
+    pub source: Account<'info, TokenAccount>,
+    // amm's token A account
+    #[account(mut)]
+    pub swap_token_a: AccountInfo<'info>,
+    // amm's token B account
+    #[account(mut)]
+    pub swap_token_b: AccountInfo<'info>,
+    // user's token A or B account receiving the withdrawal
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+    // LP token account receiving the owner withdraw fee
+    #[account(mut)]
+    pub pool_fee_account: Account<'info, TokenAccount>,
+    // mint of the withdrawn token, picks the side of the pool
+    pub destination_mint: AccountInfo<'info>,
+    // token program of the withdrawn token
+    pub destination_token_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Temporary wSOL account: A PDA (seed: user transfer authority's pubkey), only used when
+    // the user's wallet stands in for a native SOL source or destination
+    #[account(mut, seeds = [WSOL_SEED, user_transfer_authority.key().as_ref()], bump)]
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+    // Remaining account: the amm's price oracle if it has one
+}
+
+impl<'info> WithdrawSingleTokenType<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.swap_token_a.to_account_info().key != self.amm.token_a_account
+            || *self.swap_token_b.to_account_info().key != self.amm.token_b_account
+        {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        if *self.pool_fee_account.to_account_info().key != self.amm.pool_fee_account {
+            return Err(error::SwapError::IncorrectFeeAccount.into());
+        }
+        // Destination mint picks the side, its token program MUST be that side's
+        let destination_token_program = if *self.destination_mint.key == self.amm.token_a_mint {
+            self.amm.token_a_program_id
+        } else if *self.destination_mint.key == self.amm.token_b_mint {
+            self.amm.token_b_program_id
+        } else {
+            return Err(error::SwapError::IncorrectMint.into());
+        };
+        if *self.destination_token_program.key != destination_token_program {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        // User's account MUST NOT be one of the amm's vaults
+        let destination = *self.destination.to_account_info().key;
+        if destination == self.amm.token_a_account || destination == self.amm.token_b_account {
+            return Err(error::SwapError::InvalidOutput.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(fees_input: CurveFees, curve_input: CurveInput, fee_tier: u16, token_mints: Vec<Pubkey>)]
+pub struct CreateMultiPool<'info> {
+    // Pays for every account created & provides the initial liquidity
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    // amm: A PDA (seed: hash of the sorted mints, curve type & fee tier)
+    #[account(
+        init,
+        payer = payer,
+        space = Amm::LEN,
+        seeds = [
+            AMM_SEED,
+            multi_pool_seed(&token_mints).as_ref(),
+            &[curve_input.curve_type],
+            &fee_tier.to_le_bytes()
+        ],
+        bump
+    )]
+    pub amm: Box<Account<'info, Amm>>,
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    #[account(seeds = [amm.key().as_ref()], bump)]
+    pub authority: AccountInfo<'info>,
+    // amm's LP mint: A PDA (seed: amm account's pubkey)
+    #[account(
+        init,
+        payer = payer,
+        seeds = [POOL_MINT_SEED, amm.key().as_ref()],
+        bump,
+        mint::decimals = POOL_MINT_DECIMALS,
+        mint::authority = authority
+    )]
+    pub pool_mint: Box<Account<'info, Mint>>,
+    #[account(seeds = [SWAP_CONSTRAINTS_SEED], bump = swap_constraints.bump_seed)]
+    pub swap_constraints: Box<Account<'info, SwapConstraints>>,
+    // Program owner, receiving the pool fees
+    #[account(address = swap_constraints.owner_key @ error::SwapError::InvalidOwner)]
+    pub fee_owner: AccountInfo<'info>,
+    // amm's pool fee account: A PDA (seed: amm account's pubkey) owned by the program owner
+    #[account(
+        init,
+        payer = payer,
+        seeds = [FEE_ACCOUNT_SEED, amm.key().as_ref()],
+        bump,
+        token::mint = pool_mint,
+        token::authority = fee_owner
+    )]
+    pub fee_account: Box<Account<'info, TokenAccount>>,
+    // payer's LP token ATA to which the initial LP token is sent
+    #[account(
+        init,
+        payer = payer,
+        associated_token::mint = pool_mint,
+        associated_token::authority = payer
+    )]
+    pub destination: Box<Account<'info, TokenAccount>>,
+    // token program of the pool token
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    // Remaining accounts: `POOL_TOKEN_ACCOUNTS` per token, in mint order, the vault being
+    // created & the user's account funding the pool
+}
+
+#[derive(Accounts)]
+pub struct SwapMulti<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source_info, signing for the transfer into the amm
+    pub user_transfer_authority: Signer<'info>,
+    // user's token account to swap from
+    #[account(mut)]
+    pub source_info: AccountInfo<'info>,
+    // user's token account receiving the destination token
+    #[account(mut)]
+    pub destination: AccountInfo<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // LP token account receiving the owner trade fee
+    #[account(mut)]
+    pub pool_fee_account: Account<'info, TokenAccount>,
+    // mint of the source token
+    pub source_mint: AccountInfo<'info>,
+    // mint of the destination token
+    pub destination_mint: AccountInfo<'info>,
+    // token program of the source token
+    pub source_token_program: AccountInfo<'info>,
+    // token program of the destination token
+    pub destination_token_program: AccountInfo<'info>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Remaining accounts: every vault of the amm in token order, the source & destination
+    // ones writable
+}
+
+impl<'info> SwapMulti<'info> {
+    /// Checks the accounts against the amm, returning the indices of the
+    /// source & destination tokens
+    fn validate_input_accounts(
+        &self,
+        swap_authority: Pubkey,
+        vaults: &[AccountInfo],
+    ) -> Result<(usize, usize)> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        let tokens = self.amm.tokens();
+        if vaults.len() != tokens.len()
+            || vaults
+                .iter()
+                .zip(&tokens)
+                .any(|(vault, token)| *vault.key != token.account)
+        {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        // Mints MUST be two different tokens of the amm
+        let source_index = tokens
+            .iter()
+            .position(|token| token.mint == *self.source_mint.key)
+            .ok_or(error::SwapError::IncorrectMint)?;
+        let destination_index = tokens
+            .iter()
+            .position(|token| token.mint == *self.destination_mint.key)
+            .ok_or(error::SwapError::IncorrectMint)?;
+        if source_index == destination_index {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        if *self.source_token_program.key != tokens[source_index].program_id
+            || *self.destination_token_program.key != tokens[destination_index].program_id
+        {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        // User's accounts MUST NOT be the amm's vaults
+        if *self.source_info.key == tokens[source_index].account {
+            return Err(error::SwapError::InvalidInput.into());
+        }
+        if *self.destination.key == tokens[destination_index].account {
+            return Err(error::SwapError::InvalidOutput.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        if *self.pool_fee_account.to_account_info().key != self.amm.pool_fee_account {
+            return Err(error::SwapError::IncorrectFeeAccount.into());
+        }
+        Ok((source_index, destination_index))
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositAllTokenTypesMulti<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of the user's token accounts
+    pub user_transfer_authority: Signer<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // user's LP token account receiving the minted pool tokens
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Remaining accounts: `POOL_TOKEN_ACCOUNTS` per token, in token order, the user's
+    // account depositing into the vault
+}
+
+impl<'info> DepositAllTokenTypesMulti<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawAllTokenTypesMulti<'info> {
+    // Swap authority: A PDA (seed: amm account's pubkey)
+    pub authority: AccountInfo<'info>,
+    pub amm: Account<'info, Amm>,
+    // Owner or delegate of source
+    pub user_transfer_authority: Signer<'info>,
+    #[account(mut)]
+    pub pool_mint: Account<'info, Mint>,
+    // user's LP token account to burn from
+    #[account(mut)]
+    pub source: Account<'info, TokenAccount>,
+    // LP token account receiving the owner withdraw fee
+    #[account(mut)]
+    pub pool_fee_account: Account<'info, TokenAccount>,
+    // token program of the pool token
+    pub token_program: AccountInfo<'info>,
+    // Remaining accounts: `POOL_TOKEN_ACCOUNTS` per token, in token order, the user's
+    // account receiving the withdrawal from the vault
+}
+
+impl<'info> WithdrawAllTokenTypesMulti<'info> {
+    fn validate_input_accounts(&self, swap_authority: Pubkey) -> Result<()> {
+        if *self.authority.key != swap_authority {
+            return Err(error::SwapError::InvalidProgramAddress.into());
+        }
+        if *self.token_program.key != self.amm.token_program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        if *self.pool_mint.to_account_info().key != self.amm.pool_mint {
+            return Err(error::SwapError::IncorrectPoolMint.into());
+        }
+        if *self.pool_fee_account.to_account_info().key != self.amm.pool_fee_account {
+            return Err(error::SwapError::IncorrectFeeAccount.into());
+        }
+        Ok(())
+    }
+}
+
+/// Number of remaining accounts passed for each token of a multi-token pool
+pub const POOL_TOKEN_ACCOUNTS: usize = 4;
+
+/// Remaining accounts of one token of a multi-token pool, in this order
+pub struct PoolTokenAccounts<'info> {
+    /// amm's vault of the token
+    pub vault: AccountInfo<'info>,
+    /// user's token account, moving the token in or out of the vault
+    pub user_account: AccountInfo<'info>,
+    /// mint of the token
+    pub mint: AccountInfo<'info>,
+    /// token program owning the mint
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> PoolTokenAccounts<'info> {
+    /// Checks the accounts are the ones of `token`
+    fn validate(&self, token: &PoolToken) -> Result<()> {
+        if *self.vault.key != token.account {
+            return Err(error::SwapError::IncorrectSwapAccount.into());
+        }
+        if *self.mint.key != token.mint {
+            return Err(error::SwapError::IncorrectMint.into());
+        }
+        if *self.token_program.key != token.program_id {
+            return Err(error::SwapError::IncorrectTokenProgramId.into());
+        }
+        // User's account MUST NOT be the amm's vault
+        if *self.user_account.key == token.account {
+            return Err(error::SwapError::InvalidInput.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeSwapConstraints<'info> {
+    // Upgrade authority of this program, the only one allowed to set up the constraints
+    #[account(mut)]
+    pub upgrade_authority: Signer<'info>,
+    #[account(
+        init,
+        payer = upgrade_authority,
+        space = SwapConstraints::LEN,
+        seeds = [SWAP_CONSTRAINTS_SEED],
+        bump
+    )]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+    #[account(constraint = program.programdata_address() == Some(program_data.key()))]
+    pub program: Program<'info, program::AnchorPrograms>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(upgrade_authority.key())
+            @ error::SwapError::InvalidOwner
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateSwapConstraints<'info> {
+    // Current owner of the constraints
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [SWAP_CONSTRAINTS_SEED],
+        bump = swap_constraints.bump_seed,
+        constraint = swap_constraints.owner_key == owner.key() @ error::SwapError::InvalidOwner
+    )]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+}
+
+#[derive(Accounts)]
+pub struct RampAmp<'info> {
+    // Owner of the constraints, the admin of every pool
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [SWAP_CONSTRAINTS_SEED],
+        bump = swap_constraints.bump_seed,
+        constraint = swap_constraints.owner_key == owner.key() @ error::SwapError::InvalidOwner
+    )]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+    // amm on a stable curve, whose amplifier is ramped
+    #[account(mut)]
+    pub amm: Account<'info, Amm>,
+}
+
+/// Seed prefix of the amm PDA, followed by both mints (or `multi_pool_seed` of all of
+/// them), the curve type & the fee tier
+pub const AMM_SEED: &[u8] = b"amm";
+/// Seed prefix of the amm's token vaults, followed by the amm pubkey & the vault's mint
+pub const VAULT_SEED: &[u8] = b"vault";
+/// Seed prefix of the amm's LP mint, followed by the amm pubkey
+pub const POOL_MINT_SEED: &[u8] = b"pool_mint";
+/// Seed prefix of the amm's pool fee account, followed by the amm pubkey
+pub const FEE_ACCOUNT_SEED: &[u8] = b"fee_account";
+/// Decimals of the LP mints created by `create_pool`
+pub const POOL_MINT_DECIMALS: u8 = 6;
+
+#[derive(Accounts)]
+pub struct SetPriceOracle<'info> {
+    // Owner of the constraints, the admin of every pool
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [SWAP_CONSTRAINTS_SEED],
+        bump = swap_constraints.bump_seed,
+        constraint = swap_constraints.owner_key == owner.key() @ error::SwapError::InvalidOwner
+    )]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+    // amm on a constant price curve, whose price is pegged to the oracle
+    #[account(mut)]
+    pub amm: Account<'info, Amm>,
+    #[account(address = amm.token_a_mint @ error::SwapError::IncorrectMint)]
+    pub token_a_mint: AccountInfo<'info>,
+    #[account(address = amm.token_b_mint @ error::SwapError::IncorrectMint)]
+    pub token_b_mint: AccountInfo<'info>,
+    // Price account pricing token B in token A, with the Pyth price account layout
+    pub price_oracle: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetDynamicFee<'info> {
+    // Owner of the constraints, the admin of every pool
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [SWAP_CONSTRAINTS_SEED],
+        bump = swap_constraints.bump_seed,
+        constraint = swap_constraints.owner_key == owner.key() @ error::SwapError::InvalidOwner
+    )]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+    // amm of two tokens, whose trade fee follows its volatility
+    #[account(mut)]
+    pub amm: Account<'info, Amm>,
+}
+
+#[derive(Accounts)]
+pub struct SetImbalanceFee<'info> {
+    // Owner of the constraints, the admin of every pool
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [SWAP_CONSTRAINTS_SEED],
+        bump = swap_constraints.bump_seed,
+        constraint = swap_constraints.owner_key == owner.key() @ error::SwapError::InvalidOwner
+    )]
+    pub swap_constraints: Account<'info, SwapConstraints>,
+    // amm of two tokens on a stable curve, whose trade fee follows its imbalance
+    #[account(mut)]
+    pub amm: Account<'info, Amm>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateAmm<'info> {
+    // Pays the rent of the larger layout
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    // amm account in an older layout
+    #[account(mut, owner = crate::ID)]
+    pub amm: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[account]
+pub struct Amm {
+    /// Layout version of the account, see `AMM_VERSION`
+    pub version: u8,
+    // LP creator's address
+    pub initializer_key: Pubkey,
+    /// Is the swap initialized, with data written to it
+    pub is_initialized: bool,
+    /// Bump seed used to generate the program address / authority
+    pub bump_seed: u8,
+    /// Token program of the pool token mint
+    pub token_program_id: Pubkey,
+    /// Address of token A liquidity account
+    pub token_a_account: Pubkey,
+    /// Address of token B liquidity account
+    pub token_b_account: Pubkey,
+    /// Address of pool token mint
+    pub pool_mint: Pubkey,
+    /// Address of token A mint
+    pub token_a_mint: Pubkey,
+    /// Address of token B mint
+    pub token_b_mint: Pubkey,
+    /// Address of pool fee account
+    pub pool_fee_account: Pubkey,
+    /// Fees associated with swap
+    pub fees: CurveFees,
+    /// Curve type & parameters, packed as in `SwapCurve`
+    pub curve: CurveInput,
+    /// Fee tier the amm was created for, part of its address seeds
+    pub fee_tier: u16,
+    /// Token program of token A, either Token or Token-2022
+    pub token_a_program_id: Pubkey,
+    /// Token program of token B, either Token or Token-2022
+    pub token_b_program_id: Pubkey,
+    /// Number of tokens the pool holds, more than two only for stable pools
+    pub token_count: u8,
+    /// Tokens past A & B, in mint order, the unused ones left default
+    pub extra_tokens: [PoolToken; MAX_TOKENS_IN_POOL - 2],
+    /// Price oracle pegging a constant price curve, default while the price is fixed
+    pub price_oracle: Pubkey,
+    /// Decimals of token A less decimals of token B, scaling the oracle's price
+    pub price_decimals_offset: i8,
+    /// Trade fee following recent price movement, replacing the static one while enabled
+    pub dynamic_fee: DynamicFee,
+    /// Trade fee raise for trades leaving a stable pool imbalanced, off by default
+    pub imbalance_fee: ImbalanceFee,
+    /// Reserved space, so later fields can be added without resizing the account
+    pub reserved: [u8; AMM_RESERVED_LEN],
+}
+
+/// Current layout version of `Amm`
+pub const AMM_VERSION: u8 = 4;
+
+/// Bytes of `Amm` kept free for future fields
+pub const AMM_RESERVED_LEN: usize = 64;
+
+impl Amm {
+    /// Space of the amm account, discriminator included
+    pub const LEN: usize = 8
+        + 1
+        + 32
+        + 1
+        + 1
+        + 32 * 7
+        + CurveFees::LEN
+        + SwapCurve::LEN
+        + 2
+        + 32 * 2
+        + 1
+        + PoolToken::LEN * (MAX_TOKENS_IN_POOL - 2)
+        + 32
+        + 1
+        + DynamicFee::LEN
+        + ImbalanceFee::LEN
+        + AMM_RESERVED_LEN;
+
+    /// Space of the version 3 layout, whose fields used up all but one byte
+    /// of the reserved space
+    pub const V3_LEN: usize = Self::LEN - AMM_RESERVED_LEN + 1;
+
+    /// Space of the version 1 & 2 layouts, before the extra tokens
+    pub const V2_LEN: usize = Self::V3_LEN - 1 - PoolToken::LEN * (MAX_TOKENS_IN_POOL - 2);
+
+    /// Vault, mint & token program of every token of the pool, in mint order
+    pub fn tokens(&self) -> Vec<PoolToken> {
+        let mut tokens = vec![
+            PoolToken {
+                account: self.token_a_account,
+                mint: self.token_a_mint,
+                program_id: self.token_a_program_id,
+            },
+            PoolToken {
+                account: self.token_b_account,
+                mint: self.token_b_mint,
+                program_id: self.token_b_program_id,
+            },
+        ];
+        let extra_token_count = usize::from(self.token_count).saturating_sub(2);
+        tokens.extend_from_slice(&self.extra_tokens[..extra_token_count]);
+        tokens
+    }
+
+    /// Curve of a pool of two tokens, the only pools the pair instructions trade.
+    /// Pools pegged to an oracle are only priced by `priced_pair_curve`.
+    pub fn pair_curve(&self) -> Result<SwapCurve> {
+        if self.token_count > 2 || self.price_oracle != Pubkey::default() {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+        self.curve.to_swap_curve()
+    }
+
+    /// Stable curve of a pool of more than two tokens, the only pools the
+    /// multi-token instructions trade. Pools of two tokens go through the pair
+    /// instructions, which charge their dynamic & imbalance fees.
+    pub fn multi_curve(&self) -> Result<StableCurve> {
+        if self.token_count <= 2 {
+            return Err(error::SwapError::InvalidTokenCount.into());
+        }
+        self.curve.to_stable_curve()
+    }
+
+    /// Fees charged at `unix_timestamp`, the trade fee being the dynamic one
+    /// when enabled
+    pub fn effective_fees(&self, unix_timestamp: i64) -> Result<CurveFees> {
+        self.dynamic_fee
+            .apply(&self.fees, unix_timestamp)
+            .ok_or_else(|| error::SwapError::FeeCalculationFailure.into())
+    }
+
+    /// Curve of a pool of two tokens, at the current price of its oracle when
+    /// it has one. The oracle is then expected first in `remaining_accounts`,
+    /// which are returned without it.
+    pub fn priced_pair_curve<'a, 'info>(
+        &self,
+        remaining_accounts: &'a [AccountInfo<'info>],
+    ) -> Result<(SwapCurve, &'a [AccountInfo<'info>])> {
+        if self.price_oracle == Pubkey::default() {
+            return Ok((self.pair_curve()?, remaining_accounts));
+        }
+        let (oracle, remaining_accounts) = remaining_accounts
+            .split_first()
+            .ok_or(error::SwapError::InvalidOracle)?;
+        if *oracle.key != self.price_oracle {
+            return Err(error::SwapError::InvalidOracle.into());
+        }
+        let (token_b_price, token_b_price_denominator) =
+            OraclePrice::load(oracle, Clock::get()?.unix_timestamp)?
+                .token_b_price(self.price_decimals_offset)
+                .ok_or(error::SwapError::InvalidOracle)?;
+        let swap_curve = SwapCurve {
+            curve_type: CurveType::ConstantPrice,
+            calculator: Box::new(ConstantPriceCurve {
+                token_b_price,
+                token_b_price_denominator,
+            }),
+        };
+        Ok((swap_curve, remaining_accounts))
+    }
+}
+
+/// Emitted for every swap, and every hop of a route, reporting the trade fee
+/// charged, which follows the amm's volatility when it has a dynamic fee
+#[event]
+pub struct SwapEvent {
+    /// Amm traded on
+    pub amm: Pubkey,
+    /// Amount of source token taken in, fees included
+    pub source_amount_swapped: u64,
+    /// Amount of destination token paid out
+    pub destination_amount_swapped: u64,
+    /// Amount of source token going to pool holders
+    pub trade_fee: u64,
+    /// Trade fee charged, numerator
+    pub trade_fee_numerator: u64,
+    /// Trade fee charged, denominator
+    pub trade_fee_denominator: u64,
+}
+
+/// Vault, mint & token program of one of the pool's tokens
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PoolToken {
+    /// Address of the token's liquidity account
+    pub account: Pubkey,
+    /// Address of the token's mint
+    pub mint: Pubkey,
+    /// Token program of the mint, either Token or Token-2022
+    pub program_id: Pubkey,
+}
+
+impl PoolToken {
+    /// Space of the serialized token
+    pub const LEN: usize = 32 * 3;
+}
+
+/// Pre-versioning layout of `Amm`, still carrying the fields of the escrow
+/// template it started from and no curve. Only read back by `migrate_amm`.
+pub struct AmmV0 {
+    pub initializer_key: Pubkey,
+    pub initializer_deposit_token_account: Pubkey,
+    pub initializer_receive_token_account: Pubkey,
+    pub initializer_amount: u64,
+    pub taker_amount: u64,
+    pub is_initialized: bool,
+    pub bump_seed: u8,
+    pub token_program_id: Pubkey,
+    pub token_a_account: Pubkey,
+    pub token_b_account: Pubkey,
+    pub pool_mint: Pubkey,
+    pub token_a_mint: Pubkey,
+    pub token_b_mint: Pubkey,
+    pub pool_fee_account: Pubkey,
+    /// Same 64-byte layout as the `FeesInput` it was stored as
+    pub fees: CurveFees,
+}
+
+impl AmmV0 {
+    /// Deserialize the old layout, discriminator excluded
+    pub fn unpack(mut data: &[u8]) -> Result<Self> {
+        Ok(Self {
+            initializer_key: AnchorDeserialize::deserialize(&mut data)?,
+            initializer_deposit_token_account: AnchorDeserialize::deserialize(&mut data)?,
+            initializer_receive_token_account: AnchorDeserialize::deserialize(&mut data)?,
+            initializer_amount: AnchorDeserialize::deserialize(&mut data)?,
+            taker_amount: AnchorDeserialize::deserialize(&mut data)?,
+            is_initialized: AnchorDeserialize::deserialize(&mut data)?,
+            bump_seed: AnchorDeserialize::deserialize(&mut data)?,
+            token_program_id: AnchorDeserialize::deserialize(&mut data)?,
+            token_a_account: AnchorDeserialize::deserialize(&mut data)?,
+            token_b_account: AnchorDeserialize::deserialize(&mut data)?,
+            pool_mint: AnchorDeserialize::deserialize(&mut data)?,
+            token_a_mint: AnchorDeserialize::deserialize(&mut data)?,
+            token_b_mint: AnchorDeserialize::deserialize(&mut data)?,
+            pool_fee_account: AnchorDeserialize::deserialize(&mut data)?,
+            fees: AnchorDeserialize::deserialize(&mut data)?,
+        })
+    }
+}
+
+impl From<AmmV0> for Amm {
+    fn from(amm: AmmV0) -> Self {
+        Self {
+            version: AMM_VERSION,
+            initializer_key: amm.initializer_key,
+            is_initialized: amm.is_initialized,
+            bump_seed: amm.bump_seed,
+            token_program_id: amm.token_program_id,
+            token_a_account: amm.token_a_account,
+            token_b_account: amm.token_b_account,
+            pool_mint: amm.pool_mint,
+            token_a_mint: amm.token_a_mint,
+            token_b_mint: amm.token_b_mint,
+            pool_fee_account: amm.pool_fee_account,
+            fees: amm.fees,
+            // The layout predates stored curves, its pools were constant product
+            curve: CurveInput {
+                curve_type: CurveType::ConstantProduct as u8,
+                curve_parameters: [0u8; 32],
+            },
+            fee_tier: 0,
+            // Pools predating Token-2022 support only hold Token mints
+            token_a_program_id: amm.token_program_id,
+            token_b_program_id: amm.token_program_id,
+            token_count: 2,
+            extra_tokens: Default::default(),
+            price_oracle: Pubkey::default(),
+            price_decimals_offset: 0,
+            dynamic_fee: DynamicFee::default(),
+            imbalance_fee: ImbalanceFee::default(),
+            reserved: [0u8; AMM_RESERVED_LEN],
+        }
+    }
+}
+
+/// Curve descriptor following the 33-byte packed layout of `SwapCurve`:
+/// 1 byte for the curve type and 32 bytes for the calculator parameters.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct CurveInput {
+    /// Curve type, see `CurveType`
+    pub curve_type: u8,
+    /// Calculator parameters, packed as the calculator of `curve_type` expects
+    pub curve_parameters: [u8; 32],
+}
+
+impl CurveInput {
+    /// Unpack the descriptor into a `SwapCurve`
+    pub fn to_swap_curve(&self) -> Result<SwapCurve> {
+        if CurveType::try_from(self.curve_type).is_err() {
+            return Err(error::SwapError::UnsupportedCurveType.into());
+        }
+        let mut packed = [0u8; SwapCurve::LEN];
+        packed[0] = self.curve_type;
+        packed[1..].copy_from_slice(&self.curve_parameters);
+        SwapCurve::unpack_from_slice(&packed).map_err(|_| error::SwapError::InvalidCurve.into())
+    }
+
+    /// Set the precision multipliers of a stable curve from the decimals of
+    /// its mints, in token order, other curves being left as they are
+    pub fn with_token_decimals(mut self, token_decimals: &[u8]) -> Result<Self> {
+        if self.curve_type == CurveType::Stable as u8 {
+            let stable_curve = self.to_stable_curve()?.with_decimals(token_decimals)?;
+            Pack::pack_into_slice(&stable_curve, &mut self.curve_parameters);
+        }
+        Ok(self)
+    }
+
+    /// Unpack the parameters of a stable curve
+    pub fn to_stable_curve(&self) -> Result<StableCurve> {
+        if self.curve_type != CurveType::Stable as u8 {
+            return Err(error::SwapError::UnsupportedCurveOperation.into());
+        }
+        StableCurve::unpack_from_slice(&self.curve_parameters)
+            .map_err(|_| error::SwapError::InvalidCurve.into())
+    }
+}
+
+/// Moves the tokens of a priced swap: the source tokens into the amm, the
+/// owner fee out as pool tokens and the destination tokens out to the user
+fn execute_swap<'info>(
+    ctx: &Context<'_, '_, '_, 'info, Swap<'info>>,
+    swap_curve: &SwapCurve,
+    fees: &CurveFees,
+    result: &SwapResult,
+    trade_direction: TradeDirection,
+    source_transfer_amount: u64,
+    host_fee_account_info: Option<&AccountInfo<'info>>,
+) -> Result<()> {
+    let amm = &ctx.accounts.amm;
+    let bump_seed = amm.bump_seed;
+    let destination_amount_swapped = to_u64(result.destination_amount_swapped)?;
+
+    let native_sol = NativeSol {
+        user: ctx.accounts.user_transfer_authority.to_account_info(),
+        wsol_account: ctx.accounts.wsol_account.clone(),
+        system_program: ctx.accounts.system_program.to_account_info(),
+        bump: *ctx.bumps.get("wsol_account").unwrap(),
+    };
+
+    // concatenate swap_authority's seed & bump
+    let seeds = &[&amm.to_account_info().key.to_bytes(), &[bump_seed][..]];
+
+    // user's source tokens go into the amm's source vault
+    let source_info = native_sol.wrap(
+        ctx.accounts.source_info.clone(),
+        &ctx.accounts.source_mint,
+        &ctx.accounts.source_token_program,
+        source_transfer_amount,
+    )?;
+    token_interface::transfer(
+        ctx.accounts.source_token_program.clone(),
+        source_info.clone(),
+        ctx.accounts.source_mint.clone(),
+        ctx.accounts.swap_source.clone(),
+        ctx.accounts.user_transfer_authority.to_account_info(),
+        &[],
+        source_transfer_amount,
+    )?;
+    native_sol.unwrap(&source_info, &ctx.accounts.source_token_program)?;
+
+    // owner fee is paid out as the equivalent amount of LP tokens
+    let mut pool_token_amount = owner_fee_pool_tokens(
+        fees,
+        swap_curve,
+        result,
+        trade_direction,
+        ctx.accounts.pool_mint.supply,
+    )?;
+
+    if pool_token_amount > 0 {
+        // Optional trailing host LP account takes its cut of the owner fee
+        if let Some(host_fee_account_info) = host_fee_account_info {
+            let host_fee_account = Account::<TokenAccount>::try_from(host_fee_account_info)?;
+            if *ctx.accounts.pool_mint.to_account_info().key != host_fee_account.mint {
+                return Err(error::SwapError::IncorrectPoolMint.into());
+            }
+            let host_fee = amm
+                .fees
+                .host_fee(pool_token_amount)
+                .ok_or(error::SwapError::FeeCalculationFailure)?;
+            if host_fee > 0 {
+                pool_token_amount = pool_token_amount
+                    .checked_sub(host_fee)
+                    .ok_or(error::SwapError::FeeCalculationFailure)?;
+                token::mint_to(
+                    CpiContext::new(
+                        ctx.accounts.token_program.clone(),
+                        MintTo {
+                            mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                            to: host_fee_account_info.clone(),
+                            authority: ctx.accounts.authority.clone(),
+                        },
+                    )
+                    .with_signer(&[&seeds[..]]),
+                    to_u64(host_fee)?,
+                )?;
+            }
+        }
+        token::mint_to(
+            CpiContext::new(
+                ctx.accounts.token_program.clone(),
+                MintTo {
+                    mint: ctx.accounts.pool_mint.to_account_info().clone(),
+                    to: ctx.accounts.pool_fee_account.to_account_info().clone(),
+                    authority: ctx.accounts.authority.clone(),
+                },
+            )
+            .with_signer(&[&seeds[..]]),
+            to_u64(pool_token_amount)?,
+        )?;
+    }
+
+    // amm's destination vault pays out the swapped tokens to the user
+    let destination = native_sol.wrap(
+        ctx.accounts.destination.clone(),
+        &ctx.accounts.destination_mint,
+        &ctx.accounts.destination_token_program,
+        0,
+    )?;
+    token_interface::transfer(
+        ctx.accounts.destination_token_program.clone(),
+        ctx.accounts.swap_destination.clone(),
+        ctx.accounts.destination_mint.clone(),
+        destination.clone(),
+        ctx.accounts.authority.clone(),
+        &[&seeds[..]],
+        destination_amount_swapped,
+    )?;
+    native_sol.unwrap(&destination, &ctx.accounts.destination_token_program)?;
+
+    emit_swap_event(amm.key(), result)
+}
+
+/// Reports a swap on `amm` to clients, with the trade fee it was charged
+fn emit_swap_event(amm: Pubkey, result: &SwapResult) -> Result<()> {
+    emit!(SwapEvent {
+        amm,
+        source_amount_swapped: to_u64(result.source_amount_swapped)?,
+        destination_amount_swapped: to_u64(result.destination_amount_swapped)?,
+        trade_fee: to_u64(result.trade_fee)?,
+        trade_fee_numerator: result.trade_fee_numerator,
+        trade_fee_denominator: result.trade_fee_denominator,
+    });
+    Ok(())
+}
+
+/// Adds the price movement of a swap to the amm's dynamic fee volatility
+fn record_volatility(
+    amm: &mut Amm,
+    swap_curve: &SwapCurve,
+    result: &SwapResult,
+    trade_direction: TradeDirection,
+    unix_timestamp: i64,
+) -> Result<()> {
+    if !amm.dynamic_fee.is_enabled() {
+        return Ok(());
+    }
+    let swap_source_amount = result
+        .new_swap_source_amount
+        .checked_sub(result.source_amount_swapped)
+        .ok_or(error::SwapError::CalculationFailure)?;
+    let swap_destination_amount = result
+        .new_swap_destination_amount
+        .checked_add(result.destination_amount_swapped)
+        .ok_or(error::SwapError::CalculationFailure)?;
+    let (before, after) = match trade_direction {
+        TradeDirection::AtoB => (
+            (swap_source_amount, swap_destination_amount),
+            (
+                result.new_swap_source_amount,
+                result.new_swap_destination_amount,
+            ),
+        ),
+        TradeDirection::BtoA => (
+            (swap_destination_amount, swap_source_amount),
+            (
+                result.new_swap_destination_amount,
+                result.new_swap_source_amount,
+            ),
+        ),
+    };
+    // Curves without a quotable spot price around the trade leave the volatility be
+    if let (Some(price_before), Some(price_after)) = (
+        swap_curve.spot_price(before.0, before.1),
+        swap_curve.spot_price(after.0, after.1),
+    ) {
+        amm.dynamic_fee
+            .record_price_movement(&price_before, &price_after, unix_timestamp)
+            .ok_or(error::SwapError::CalculationFailure)?;
+    }
+    Ok(())
+}
+
+/// Amount of pool tokens equivalent to the owner fee of a swap
+fn owner_fee_pool_tokens(
+    fees: &CurveFees,
+    swap_curve: &SwapCurve,
+    result: &SwapResult,
+    trade_direction: TradeDirection,
+    pool_token_supply: u64,
+) -> Result<u128> {
+    let (swap_token_a_amount, swap_token_b_amount) = match trade_direction {
+        TradeDirection::AtoB => (
+            result.new_swap_source_amount,
+            result.new_swap_destination_amount,
+        ),
+        TradeDirection::BtoA => (
+            result.new_swap_destination_amount,
+            result.new_swap_source_amount,
+        ),
+    };
+    // The owner fee is converted at the unraised trade fee, the imbalance fee
+    // being the pool's
+    swap_curve
+        .withdraw_single_token_type_exact_out(
+            result.owner_fee,
+            swap_token_a_amount,
+            swap_token_b_amount,
+            u128::from(pool_token_supply),
+            trade_direction,
+            fees,
+            &ImbalanceFee::default(),
+        )
+        .ok_or_else(|| error::SwapError::FeeCalculationFailure.into())
+}
+
+/// Split the remaining accounts of an instruction on a multi-token pool into
+/// the accounts of each of its `token_count` tokens
+fn split_pool_token_accounts<'info>(
+    remaining_accounts: &[AccountInfo<'info>],
+    token_count: usize,
+) -> Result<Vec<PoolTokenAccounts<'info>>> {
+    if remaining_accounts.len() != token_count * POOL_TOKEN_ACCOUNTS {
+        return Err(error::SwapError::InvalidTokenCount.into());
+    }
+    Ok(remaining_accounts
+        .chunks_exact(POOL_TOKEN_ACCOUNTS)
+        .map(|accounts| PoolTokenAccounts {
+            vault: accounts[0].clone(),
+            user_account: accounts[1].clone(),
+            mint: accounts[2].clone(),
+            token_program: accounts[3].clone(),
+        })
+        .collect())
+}
+
+/// Seed standing for the sorted mints of a multi-token pool in its address
+pub fn multi_pool_seed(token_mints: &[Pubkey]) -> [u8; 32] {
+    let mints = token_mints
+        .iter()
+        .map(|mint| mint.as_ref())
+        .collect::<Vec<&[u8]>>();
+    solana_program::hash::hashv(&mints).to_bytes()
+}
+
+/// Get the swap authority of an amm from its stored bump seed
+fn authority_id(program_id: &Pubkey, amm: &Pubkey, bump_seed: u8) -> Result<Pubkey> {
+    Pubkey::create_program_address(&[&amm.to_bytes()[..32], &[bump_seed]], program_id)
+        .map_err(|_| error::SwapError::InvalidProgramAddress.into())
+}
+
+/// Convert a curve result back into a token amount
+fn to_u64(val: u128) -> Result<u64> {
+    u64::try_from(val).map_err(|_| error::SwapError::ConversionFailure.into())
+}
+
+#[cfg(test)]
+mod swap_tests {
+    use super::*;
+    use crate::curve::fees::DYNAMIC_FEE_DENOMINATOR;
+    use anchor_lang::{InstructionData, ToAccountMetas};
+    use solana_program::entrypoint::{
+        deserialize, ProgramResult, BPF_ALIGN_OF_U128, MAX_PERMITTED_DATA_INCREASE, NON_DUP_MARKER,
+        SUCCESS,
+    };
+    use solana_program::instruction::Instruction;
+    use solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use solana_program::program_utils::limited_deserialize;
+    use solana_program::system_instruction::SystemInstruction;
+    use spl_token_2022::extension::transfer_fee::{
+        TransferFee, TransferFeeAmount, TransferFeeConfig,
+    };
+    use spl_token_2022::extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions};
+    use std::cell::RefCell;
+    use std::sync::{Mutex, Once};
+
+    const NOW: i64 = 1_650_000_000;
+
+    /// Stands in for the runtime: the clock reads `NOW`, invocations of the
+    /// Token, Token-2022, Associated Token & System programs run through their
+    /// processors, PDAs signing with their seeds, & logs are kept in `LOGS`
+    struct TestSyscalls;
+
+    static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    thread_local! {
+        /// Return data of the last invoked program to set any
+        static RETURN_DATA: RefCell<Option<(Pubkey, Vec<u8>)>> = const { RefCell::new(None) };
+    }
+
+    impl SyscallStubs for TestSyscalls {
+        fn sol_log(&self, message: &str) {
+            LOGS.lock().unwrap().push(message.to_string());
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock {
+                unix_timestamp: NOW,
+                ..Clock::default()
+            };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            SUCCESS
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+
+        fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+            RETURN_DATA.with(|return_data| return_data.borrow().clone())
+        }
+
+        // the invoking stub fills in the program setting the data
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| {
+                *return_data.borrow_mut() = Some((Pubkey::default(), data.to_vec()))
+            });
+        }
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            let accounts = instruction
+                .accounts
+                .iter()
+                .map(|meta| {
+                    let mut info = account_infos
+                        .iter()
+                        .find(|info| *info.key == meta.pubkey)
+                        .unwrap()
+                        .clone();
+                    // the invoking program is either this one or the Associated Token one
+                    info.is_signer |= signers_seeds.iter().any(|seeds| {
+                        [crate::ID, spl_associated_token_account::ID]
+                            .iter()
+                            .any(|program_id| {
+                                Pubkey::create_program_address(seeds, program_id) == Ok(meta.pubkey)
+                            })
+                    });
+                    info
+                })
+                .collect::<Vec<_>>();
+            let program_id = &instruction.program_id;
+            let data = &instruction.data;
+            let result = if *program_id == System::id() {
+                process_system_instruction(&accounts, data)
+            } else if *program_id == token::ID {
+                spl_token::processor::Processor::process(program_id, &accounts, data)
+            } else if *program_id == spl_token_2022::ID {
+                spl_token_2022::processor::Processor::process(program_id, &accounts, data)
+            } else if *program_id == spl_associated_token_account::ID {
+                spl_associated_token_account::processor::process_instruction(
+                    program_id, &accounts, data,
+                )
+            } else {
+                panic!("unexpected invocation of {}", program_id)
+            };
+            RETURN_DATA.with(|return_data| {
+                if let Some((setter, _)) = return_data.borrow_mut().as_mut() {
+                    if *setter == Pubkey::default() {
+                        *setter = *program_id;
+                    }
+                }
+            });
+            result
+        }
+    }
+
+    /// The System program instructions the program invokes, refused like the
+    /// runtime does when an account they debit or create hasn't signed
+    fn process_system_instruction(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+        let instruction =
+            limited_deserialize(data, 1_232).map_err(|_| ProgramError::InvalidInstructionData)?;
+        let signed = |count: usize| accounts[..count].iter().all(|account| account.is_signer);
+        match instruction {
+            SystemInstruction::Transfer { lamports } => {
+                if !signed(1) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                **accounts[0].try_borrow_mut_lamports()? -= lamports;
+                **accounts[1].try_borrow_mut_lamports()? += lamports;
+            }
+            SystemInstruction::CreateAccount {
+                lamports,
+                space,
+                owner,
+            } => {
+                if !signed(2) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                if !accounts[1].data_is_empty() || accounts[1].lamports() > 0 {
+                    return Err(ProgramError::AccountAlreadyInitialized);
+                }
+                **accounts[0].try_borrow_mut_lamports()? -= lamports;
+                **accounts[1].try_borrow_mut_lamports()? += lamports;
+                accounts[1].realloc(space as usize, true)?;
+                accounts[1].assign(&owner);
+            }
+            SystemInstruction::Allocate { space } => {
+                if !signed(1) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                assert!(accounts[0].data_is_empty());
+                accounts[0].realloc(space as usize, true)?;
+            }
+            SystemInstruction::Assign { owner } => {
+                if !signed(1) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                accounts[0].assign(&owner);
+            }
+            _ => unimplemented!(),
+        }
+        Ok(())
+    }
+
+    fn set_test_syscalls() {
+        static ONCE: Once = Once::new();
+        ONCE.call_once(|| {
+            set_syscall_stubs(Box::new(TestSyscalls));
+        });
+    }
+
+    /// Account as the runtime holds it, before it is lent to the program
+    #[derive(Clone)]
+    struct TestAccount {
+        key: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+        executable: bool,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, data: Vec<u8>, owner: Pubkey) -> Self {
+            Self {
+                key,
+                lamports: 1_000_000_000,
+                data,
+                owner,
+                executable: false,
+            }
+        }
+
+        /// Account the System program has yet to create
+        fn empty(key: Pubkey) -> Self {
+            Self {
+                lamports: 0,
+                ..Self::new(key, vec![], System::id())
+            }
+        }
+
+        fn program(key: Pubkey) -> Self {
+            Self {
+                executable: true,
+                ..Self::new(key, vec![], solana_program::bpf_loader::ID)
+            }
+        }
+
+        fn mint(key: Pubkey, mint_authority: Pubkey, supply: u64) -> Self {
+            let mut data = vec![0u8; spl_token::state::Mint::LEN];
+            spl_token::state::Mint {
+                mint_authority: COption::Some(mint_authority),
+                supply,
+                decimals: 6,
+                is_initialized: true,
+                freeze_authority: COption::None,
+            }
+            .pack_into_slice(&mut data);
+            Self::new(key, data, token::ID)
+        }
+
+        /// Token-2022 mint carrying the raw `extensions`, as type & value
+        fn mint_2022(key: Pubkey, mint_authority: Pubkey, extensions: &[(u16, Vec<u8>)]) -> Self {
+            let mut data = Self::mint(key, mint_authority, 0).data;
+            data.resize(spl_token_2022::state::Account::LEN, 0);
+            data.push(spl_token_2022::extension::AccountType::Mint as u8);
+            for (extension_type, value) in extensions {
+                data.extend_from_slice(&extension_type.to_le_bytes());
+                data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+                data.extend_from_slice(value);
+            }
+            Self::new(key, data, spl_token_2022::ID)
+        }
+
+        fn token_account(key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) -> Self {
+            let mut data = vec![0u8; spl_token::state::Account::LEN];
+            spl_token::state::Account {
+                mint,
+                owner,
+                amount,
+                state: spl_token::state::AccountState::Initialized,
+                ..spl_token::state::Account::default()
+            }
+            .pack_into_slice(&mut data);
+            Self::new(key, data, token::ID)
+        }
+
+        /// wSOL account holding `amount` lamports above its rent reserve
+        fn native_token_account(key: Pubkey, owner: Pubkey, amount: u64) -> Self {
+            let rent_exempt_reserve =
+                Rent::default().minimum_balance(spl_token::state::Account::LEN);
+            let mut data = vec![0u8; spl_token::state::Account::LEN];
+            spl_token::state::Account {
+                mint: spl_token::native_mint::ID,
+                owner,
+                amount,
+                state: spl_token::state::AccountState::Initialized,
+                is_native: COption::Some(rent_exempt_reserve),
+                ..spl_token::state::Account::default()
+            }
+            .pack_into_slice(&mut data);
+            Self {
+                lamports: rent_exempt_reserve + amount,
+                ..Self::new(key, data, token::ID)
+            }
+        }
+
+        /// Token-2022 account with room for the transfer fees it withholds
+        fn token_account_2022(key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) -> Self {
+            let mut data = Self::token_account(key, mint, owner, amount).data;
+            data.push(spl_token_2022::extension::AccountType::Account as u8);
+            data.extend_from_slice(&u16::from(ExtensionType::TransferFeeAmount).to_le_bytes());
+            data.extend_from_slice(&8u16.to_le_bytes());
+            data.extend_from_slice(&0u64.to_le_bytes());
+            Self::new(key, data, spl_token_2022::ID)
+        }
+
+        fn token_amount(&self) -> u64 {
+            StateWithExtensions::<spl_token_2022::state::Account>::unpack(&self.data)
+                .unwrap()
+                .base
+                .amount
+        }
+
+        /// Transfer fees withheld by a Token-2022 account
+        fn withheld_amount(&self) -> u64 {
+            StateWithExtensions::<spl_token_2022::state::Account>::unpack(&self.data)
+                .unwrap()
+                .get_extension::<TransferFeeAmount>()
+                .unwrap()
+                .withheld_amount
+                .into()
+        }
+    }
+
+    /// Keys of a pool of tokens A & B and the amm holding them, tests
+    /// tweaking `amm` before lending its account
+    struct TestPool {
+        amm_key: Pubkey,
+        authority: Pubkey,
+        user: Pubkey,
+        token_a_mint: Pubkey,
+        token_b_mint: Pubkey,
+        token_a_account: Pubkey,
+        token_b_account: Pubkey,
+        pool_mint: Pubkey,
+        pool_fee_account: Pubkey,
+        amm: Amm,
+    }
+
+    impl TestPool {
+        /// Constant product pool charging a 0.25% trade fee
+        fn new() -> Self {
+            let amm_key = Pubkey::new_unique();
+            let (authority, bump_seed) =
+                Pubkey::find_program_address(&[amm_key.as_ref()], &crate::ID);
+            let user = Pubkey::new_unique();
+            let token_a_mint = Pubkey::new_unique();
+            let token_b_mint = Pubkey::new_unique();
+            let token_a_account = Pubkey::new_unique();
+            let token_b_account = Pubkey::new_unique();
+            let pool_mint = Pubkey::new_unique();
+            let pool_fee_account = Pubkey::new_unique();
+            let amm = Amm {
+                version: AMM_VERSION,
+                initializer_key: user,
+                is_initialized: true,
+                bump_seed,
+                token_program_id: token::ID,
+                token_a_account,
+                token_b_account,
+                pool_mint,
+                token_a_mint,
+                token_b_mint,
+                pool_fee_account,
+                fees: CurveFees {
+                    trade_fee_numerator: 25,
+                    trade_fee_denominator: 10_000,
+                    ..CurveFees::default()
+                },
+                curve: CurveInput {
+                    curve_type: CurveType::ConstantProduct as u8,
+                    curve_parameters: [0u8; 32],
+                },
+                fee_tier: 0,
+                token_a_program_id: token::ID,
+                token_b_program_id: token::ID,
+                token_count: 2,
+                extra_tokens: Default::default(),
+                price_oracle: Pubkey::default(),
+                price_decimals_offset: 0,
+                dynamic_fee: DynamicFee::default(),
+                imbalance_fee: ImbalanceFee::default(),
+                reserved: [0u8; AMM_RESERVED_LEN],
+            };
+            Self {
+                amm_key,
+                authority,
+                user,
+                token_a_mint,
+                token_b_mint,
+                token_a_account,
+                token_b_account,
+                pool_mint,
+                pool_fee_account,
+                amm,
+            }
+        }
+
+        /// Stable curve input of amplifier `amp`
+        fn stable_curve(amp: u32) -> CurveInput {
+            let mut curve_parameters = [0u8; 32];
+            curve_parameters[..4].copy_from_slice(&amp.to_le_bytes());
+            CurveInput {
+                curve_type: CurveType::Stable as u8,
+                curve_parameters,
+            }
+        }
+
+        fn amm_account(&self) -> TestAccount {
+            let mut data = vec![0u8; Amm::LEN];
+            self.amm.try_serialize(&mut &mut data[..]).unwrap();
+            TestAccount::new(self.amm_key, data, crate::ID)
+        }
+    }
+
+    /// Accounts of a swap of token A for token B, in the order of
+    /// `accounts::Swap`
+    fn swap_accounts(pool: &TestPool) -> (crate::accounts::Swap, Vec<TestAccount>) {
+        let user = pool.user;
+        let (wsol_account, _) =
+            Pubkey::find_program_address(&[WSOL_SEED, user.as_ref()], &crate::ID);
+        let source = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        let accounts = crate::accounts::Swap {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            source_info: source,
+            swap_source: pool.token_a_account,
+            swap_destination: pool.token_b_account,
+            destination,
+            pool_mint: pool.pool_mint,
+            pool_fee_account: pool.pool_fee_account,
+            source_mint: pool.token_a_mint,
+            destination_mint: pool.token_b_mint,
+            source_token_program: token::ID,
+            destination_token_program: token::ID,
+            token_program: token::ID,
+            wsol_account,
+            system_program: System::id(),
+        };
+        let test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::token_account(source, pool.token_a_mint, user, 1_000_000),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(destination, pool.token_b_mint, user, 0),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, user, 0),
+            TestAccount::mint(pool.token_a_mint, user, 11_000_000),
+            TestAccount::mint(pool.token_b_mint, user, 10_000_000),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::empty(wsol_account),
+            TestAccount::program(System::id()),
+        ];
+        (accounts, test_accounts)
+    }
+
+    /// Remaining accounts of a hop of a route trading token A for token B
+    /// on `pool`, in the order of `RouteHop`
+    fn route_hop(pool: &TestPool) -> (Vec<AccountMeta>, Vec<TestAccount>) {
+        let metas = vec![
+            AccountMeta::new_readonly(pool.authority, false),
+            AccountMeta::new(pool.amm_key, false),
+            AccountMeta::new(pool.token_a_account, false),
+            AccountMeta::new(pool.token_b_account, false),
+            AccountMeta::new(pool.pool_mint, false),
+            AccountMeta::new(pool.pool_fee_account, false),
+            AccountMeta::new_readonly(pool.token_b_mint, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ];
+        let test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, pool.user, 0),
+            TestAccount::mint(pool.token_b_mint, pool.user, 10_000_000),
+            TestAccount::program(token::ID),
+        ];
+        (metas, test_accounts)
+    }
+
+    /// Accounts of a route from `source` to `destination` through `hops`, the
+    /// route's accounts in the order of `accounts::RouteSwap`
+    fn route_accounts(
+        user: Pubkey,
+        source: TestAccount,
+        destination: TestAccount,
+        source_mint: TestAccount,
+        hops: &[&TestPool],
+    ) -> (Vec<AccountMeta>, Vec<TestAccount>) {
+        let (wsol_account, _) =
+            Pubkey::find_program_address(&[WSOL_SEED, user.as_ref()], &crate::ID);
+        let mut metas = crate::accounts::RouteSwap {
+            user_transfer_authority: user,
+            source_info: source.key,
+            destination: destination.key,
+            source_mint: source_mint.key,
+            source_token_program: token::ID,
+            token_program: token::ID,
+            wsol_account,
+            system_program: System::id(),
+        }
+        .to_account_metas(None);
+        let mut test_accounts = vec![
+            TestAccount::new(user, vec![], System::id()),
+            source,
+            destination,
+            source_mint,
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::empty(wsol_account),
+            TestAccount::program(System::id()),
+        ];
+        for pool in hops {
+            let (hop_metas, hop_accounts) = route_hop(pool);
+            metas.extend(hop_metas);
+            test_accounts.extend(hop_accounts);
+        }
+        (metas, test_accounts)
+    }
+
+    /// Swap constraints PDA owned by `owner`, allowing every curve type with
+    /// at least `fees`
+    fn swap_constraints_account(owner: Pubkey, fees: CurveFees) -> TestAccount {
+        let (key, bump_seed) = Pubkey::find_program_address(&[SWAP_CONSTRAINTS_SEED], &crate::ID);
+        let swap_constraints = SwapConstraints {
+            owner_key: owner,
+            valid_curve_types: (0..=CurveType::Concentrated as u8).collect(),
+            fees,
+            bump_seed,
+        };
+        let mut data = vec![0u8; SwapConstraints::LEN];
+        swap_constraints.try_serialize(&mut &mut data[..]).unwrap();
+        TestAccount::new(key, data, crate::ID)
+    }
+
+    /// Runs the instruction through the program's entrypoint with the
+    /// accounts serialized as the runtime lends them in `metas`, failing like
+    /// the runtime would if an account not lent as writable is modified. An
+    /// account in several metas is lent once, with the privileges of all.
+    fn process(
+        metas: &[AccountMeta],
+        test_accounts: &mut [TestAccount],
+        data: &[u8],
+    ) -> ProgramResult {
+        let mut input = (metas.len() as u64).to_le_bytes().to_vec();
+        let mut data_offsets = vec![];
+        let mut duplicates = vec![];
+        let lent_as = |key: &Pubkey, privilege: fn(&AccountMeta) -> bool| {
+            metas
+                .iter()
+                .any(|meta| meta.pubkey == *key && privilege(meta))
+        };
+        for (index, (account, meta)) in test_accounts.iter().zip(metas).enumerate() {
+            assert_eq!(account.key, meta.pubkey);
+            if let Some(first) = metas[..index]
+                .iter()
+                .position(|other| other.pubkey == meta.pubkey)
+            {
+                input.push(first as u8);
+                input.extend_from_slice(&[0u8; 7]);
+                data_offsets.push(data_offsets[first]);
+                duplicates.push((index, first));
+                continue;
+            }
+            input.extend_from_slice(&[
+                NON_DUP_MARKER,
+                lent_as(&account.key, |meta| meta.is_signer) as u8,
+                lent_as(&account.key, |meta| meta.is_writable) as u8,
+                account.executable as u8,
+            ]);
+            input.extend_from_slice(&[0u8; 4]);
+            input.extend_from_slice(account.key.as_ref());
+            input.extend_from_slice(account.owner.as_ref());
+            input.extend_from_slice(&account.lamports.to_le_bytes());
+            input.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+            data_offsets.push(input.len());
+            input.extend_from_slice(&account.data);
+            input.resize(input.len() + MAX_PERMITTED_DATA_INCREASE, 0);
+            input.resize(input.len().next_multiple_of(BPF_ALIGN_OF_U128), 0);
+            input.extend_from_slice(&0u64.to_le_bytes());
+        }
+        input.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        input.extend_from_slice(data);
+        input.extend_from_slice(crate::ID.as_ref());
+
+        // the runtime's input region is aligned like a u64
+        let mut buffer = vec![0u64; input.len().div_ceil(8)];
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 8)
+        };
+        bytes[..input.len()].copy_from_slice(&input);
+        let result = {
+            let (program_id, account_infos, instruction_data) =
+                unsafe { deserialize(bytes.as_mut_ptr()) };
+            entry(program_id, &account_infos, instruction_data)
+        };
+
+        for (index, (account, offset)) in test_accounts.iter_mut().zip(data_offsets).enumerate() {
+            if duplicates.iter().any(|(duplicate, _)| *duplicate == index) {
+                continue;
+            }
+            let read_u64 =
+                |at: usize| u64::from_le_bytes(<[u8; 8]>::try_from(&bytes[at..at + 8]).unwrap());
+            let lamports = read_u64(offset - 16);
+            let data = bytes[offset..offset + read_u64(offset - 8) as usize].to_vec();
+            let owner = Pubkey::try_from(&bytes[offset - 48..offset - 16]).unwrap();
+            if !lent_as(&account.key, |meta| meta.is_writable) {
+                assert!(
+                    data == account.data && lamports == account.lamports,
+                    "read-only account {} modified",
+                    account.key
+                );
+            }
+            account.lamports = lamports;
+            account.data = data;
+            account.owner = owner;
+        }
+        for (duplicate, first) in duplicates {
+            test_accounts[duplicate] = test_accounts[first].clone();
+        }
+        result
+    }
+
+    #[test]
+    fn swap_records_volatility_and_reports_dynamic_fee() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.dynamic_fee = DynamicFee::new(1_000, 50_000, 300).unwrap();
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        let metas = accounts.to_account_metas(None);
+        // Clients built from the IDL lend the amm as writable
+        assert!(metas[1].is_writable);
+
+        let data = crate::instruction::Swap {
+            amount_in: 10_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+
+        // the base dynamic fee of 0.1% is left in the pool
+        assert_eq!(test_accounts[3].token_amount(), 990_000);
+        assert_eq!(test_accounts[6].token_amount(), 9_980);
+
+        let amm = Amm::try_deserialize(&mut &test_accounts[1].data[..]).unwrap();
+        assert!(amm.dynamic_fee.volatility_accumulator > 0);
+        assert_eq!(amm.dynamic_fee.last_update_ts, NOW);
+
+        // the fee charged is reported to clients
+        let event = last_swap_event(accounts.amm);
+        assert_eq!(event.source_amount_swapped, 10_000);
+        assert_eq!(event.destination_amount_swapped, 9_980);
+        assert_eq!(event.trade_fee, 10);
+        assert_eq!(event.trade_fee_numerator, 1_000);
+        assert_eq!(event.trade_fee_denominator, DYNAMIC_FEE_DENOMINATOR);
+    }
+
+    /// Last `SwapEvent` logged for `amm`
+    fn last_swap_event(amm: Pubkey) -> SwapEvent {
+        LOGS.lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter_map(|log| anchor_lang::__private::base64::decode(log).ok())
+            .filter(|data| data.starts_with(&SwapEvent::discriminator()))
+            .map(|data| SwapEvent::try_from_slice(&data[8..]).unwrap())
+            .find(|event| event.amm == amm)
+            .unwrap()
+    }
+
+    #[track_caller]
+    fn assert_swap_error(result: ProgramResult, expected: error::SwapError) {
+        assert_eq!(result, Err(ProgramError::Custom(expected.into())));
+    }
+
+    #[test]
+    fn multi_instructions_reject_two_token_pools() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.curve = TestPool::stable_curve(100);
+        let user = pool.user;
+        let source = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        let accounts = crate::accounts::SwapMulti {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            source_info: source,
+            destination,
+            pool_mint: pool.pool_mint,
+            pool_fee_account: pool.pool_fee_account,
+            source_mint: pool.token_a_mint,
+            destination_mint: pool.token_b_mint,
+            source_token_program: token::ID,
+            destination_token_program: token::ID,
+            token_program: token::ID,
+        };
+        let mut metas = accounts.to_account_metas(None);
+        metas.push(AccountMeta::new(pool.token_a_account, false));
+        metas.push(AccountMeta::new(pool.token_b_account, false));
+        let mut test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::token_account(source, pool.token_a_mint, user, 1_000_000),
+            TestAccount::token_account(destination, pool.token_b_mint, user, 0),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, user, 0),
+            TestAccount::mint(pool.token_a_mint, user, 11_000_000),
+            TestAccount::mint(pool.token_b_mint, user, 10_000_000),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+        ];
+        let data = crate::instruction::SwapMulti {
+            amount_in: 10_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        // the pair instructions, charging the dynamic & imbalance fees, trade it
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::InvalidTokenCount,
+        );
+        assert_eq!(test_accounts[3].token_amount(), 1_000_000);
+
+        // each token's vault, user account, mint & token program
+        let mut remaining_metas = vec![];
+        let mut remaining_accounts = vec![];
+        for (mint, vault, user_account) in [
+            (pool.token_a_mint, pool.token_a_account, source),
+            (pool.token_b_mint, pool.token_b_account, destination),
+        ] {
+            remaining_metas.extend([
+                AccountMeta::new(vault, false),
+                AccountMeta::new(user_account, false),
+                AccountMeta::new_readonly(mint, false),
+                AccountMeta::new_readonly(token::ID, false),
+            ]);
+            remaining_accounts.extend([
+                TestAccount::token_account(vault, mint, pool.authority, 10_000_000),
+                TestAccount::token_account(user_account, mint, user, 1_000_000),
+                TestAccount::mint(mint, user, 11_000_000),
+                TestAccount::program(token::ID),
+            ]);
+        }
+        let lp_account = Pubkey::new_unique();
+
+        let accounts = crate::accounts::DepositAllTokenTypesMulti {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            pool_mint: pool.pool_mint,
+            destination: lp_account,
+            token_program: token::ID,
+        };
+        let mut metas = accounts.to_account_metas(None);
+        metas.extend_from_slice(&remaining_metas);
+        let mut test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(lp_account, pool.pool_mint, user, 0),
+            TestAccount::program(token::ID),
+        ];
+        test_accounts.extend_from_slice(&remaining_accounts);
+        let data = crate::instruction::DepositAllTokenTypesMulti {
+            pool_token_amount: 1_000,
+            maximum_token_amounts: vec![u64::MAX, u64::MAX],
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::InvalidTokenCount,
+        );
+
+        let accounts = crate::accounts::WithdrawAllTokenTypesMulti {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            pool_mint: pool.pool_mint,
+            source: lp_account,
+            pool_fee_account: pool.pool_fee_account,
+            token_program: token::ID,
+        };
+        let mut metas = accounts.to_account_metas(None);
+        metas.extend_from_slice(&remaining_metas);
+        let mut test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(lp_account, pool.pool_mint, user, 1_000),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, user, 0),
+            TestAccount::program(token::ID),
+        ];
+        test_accounts.extend_from_slice(&remaining_accounts);
+        let data = crate::instruction::WithdrawAllTokenTypesMulti {
+            pool_token_amount: 1_000,
+            minimum_token_amounts: vec![0, 0],
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::InvalidTokenCount,
+        );
+    }
+
+    /// `Amm` as the baseline program stored it, before its layout was versioned
+    #[derive(AnchorSerialize)]
+    struct BaselineAmm {
+        initializer_key: Pubkey,
+        initializer_deposit_token_account: Pubkey,
+        initializer_receive_token_account: Pubkey,
+        initializer_amount: u64,
+        taker_amount: u64,
+        is_initialized: bool,
+        bump_seed: u8,
+        token_program_id: Pubkey,
+        token_a_account: Pubkey,
+        token_b_account: Pubkey,
+        pool_mint: Pubkey,
+        token_a_mint: Pubkey,
+        token_b_mint: Pubkey,
+        pool_fee_account: Pubkey,
+        fees: [u64; 8],
+    }
+
+    #[test]
+    fn migrate_baseline_amm() {
+        set_test_syscalls();
+        let pool = TestPool::new();
+        let baseline = BaselineAmm {
+            initializer_key: pool.user,
+            initializer_deposit_token_account: Pubkey::new_unique(),
+            initializer_receive_token_account: Pubkey::new_unique(),
+            initializer_amount: 1_000,
+            taker_amount: 2_000,
+            is_initialized: true,
+            bump_seed: pool.amm.bump_seed,
+            token_program_id: token::ID,
+            token_a_account: pool.token_a_account,
+            token_b_account: pool.token_b_account,
+            pool_mint: pool.pool_mint,
+            token_a_mint: pool.token_a_mint,
+            token_b_mint: pool.token_b_mint,
+            pool_fee_account: pool.pool_fee_account,
+            fees: [25, 10_000, 5, 10_000, 1, 100, 20, 100],
+        };
+        let mut data = Amm::discriminator().to_vec();
+        baseline.serialize(&mut data).unwrap();
+        assert_eq!(data.len(), 8 + 32 * 3 + 8 * 2 + 1 + 1 + 32 * 7 + 8 * 8);
+
+        let payer = Pubkey::new_unique();
+        let accounts = crate::accounts::MigrateAmm {
+            payer,
+            amm: pool.amm_key,
+            system_program: System::id(),
+        };
+        let metas = accounts.to_account_metas(None);
+        let mut amm_account = TestAccount::new(pool.amm_key, data, crate::ID);
+        amm_account.lamports = Rent::default().minimum_balance(amm_account.data.len());
+        let mut test_accounts = vec![
+            TestAccount::new(payer, vec![], System::id()),
+            amm_account,
+            TestAccount::program(System::id()),
+        ];
+        let data = crate::instruction::MigrateAmm {}.data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+
+        let amm_account = &test_accounts[1];
+        assert_eq!(amm_account.data.len(), Amm::LEN);
+        assert_eq!(
+            amm_account.lamports,
+            Rent::default().minimum_balance(Amm::LEN)
+        );
+        let amm = Amm::try_deserialize(&mut &amm_account.data[..]).unwrap();
+        assert_eq!(amm.version, AMM_VERSION);
+        assert_eq!(amm.initializer_key, pool.user);
+        assert!(amm.is_initialized);
+        assert_eq!(amm.bump_seed, pool.amm.bump_seed);
+        assert_eq!(amm.token_program_id, token::ID);
+        assert_eq!(amm.token_a_account, pool.token_a_account);
+        assert_eq!(amm.token_b_account, pool.token_b_account);
+        assert_eq!(amm.pool_mint, pool.pool_mint);
+        assert_eq!(amm.token_a_mint, pool.token_a_mint);
+        assert_eq!(amm.token_b_mint, pool.token_b_mint);
+        assert_eq!(amm.pool_fee_account, pool.pool_fee_account);
+        assert_eq!(
+            amm.fees,
+            CurveFees {
+                trade_fee_numerator: 25,
+                trade_fee_denominator: 10_000,
+                owner_trade_fee_numerator: 5,
+                owner_trade_fee_denominator: 10_000,
+                owner_withdraw_fee_numerator: 1,
+                owner_withdraw_fee_denominator: 100,
+                host_fee_numerator: 20,
+                host_fee_denominator: 100,
+            }
+        );
+        assert_eq!(amm.curve.curve_type, CurveType::ConstantProduct as u8);
+        assert_eq!(amm.token_a_program_id, token::ID);
+        assert_eq!(amm.token_b_program_id, token::ID);
+        assert_eq!(amm.token_count, 2);
+
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::AlreadyMigrated,
+        );
+    }
+
+    #[test]
+    fn migrate_version_3_amm() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.version = 3;
+        pool.amm.curve = TestPool::stable_curve(100);
+        pool.amm.token_count = 3;
+        pool.amm.dynamic_fee = DynamicFee::new(1_000, 50_000, 300).unwrap();
+        pool.amm.imbalance_fee = ImbalanceFee::new(200).unwrap();
+        let mut amm_account = pool.amm_account();
+        // its fields had used up all but the last byte of the reserved space
+        assert!(amm_account.data[Amm::V3_LEN..]
+            .iter()
+            .all(|byte| *byte == 0));
+        amm_account.data.truncate(Amm::V3_LEN);
+        amm_account.lamports = Rent::default().minimum_balance(Amm::V3_LEN);
+
+        let payer = Pubkey::new_unique();
+        let accounts = crate::accounts::MigrateAmm {
+            payer,
+            amm: pool.amm_key,
+            system_program: System::id(),
+        };
+        let mut test_accounts = vec![
+            TestAccount::new(payer, vec![], System::id()),
+            amm_account,
+            TestAccount::program(System::id()),
+        ];
+        let data = crate::instruction::MigrateAmm {}.data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+
+        let amm_account = &test_accounts[1];
+        assert_eq!(amm_account.data.len(), Amm::LEN);
+        assert_eq!(
+            amm_account.lamports,
+            Rent::default().minimum_balance(Amm::LEN)
+        );
+        let amm = Amm::try_deserialize(&mut &amm_account.data[..]).unwrap();
+        assert_eq!(amm.version, AMM_VERSION);
+        assert_eq!(amm.token_count, 3);
+        assert_eq!(amm.dynamic_fee, pool.amm.dynamic_fee);
+        assert_eq!(amm.imbalance_fee.multiplier, 200);
+        assert_eq!(amm.reserved, [0u8; AMM_RESERVED_LEN]);
+    }
+
+    #[test]
+    fn imbalance_fee_stays_below_the_highest_dynamic_fee() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.curve = TestPool::stable_curve(100);
+        let owner = Pubkey::new_unique();
+        let constraints = swap_constraints_account(owner, pool.amm.fees.clone());
+        let mut test_accounts = vec![
+            TestAccount::new(owner, vec![], System::id()),
+            constraints.clone(),
+            pool.amm_account(),
+        ];
+        let dynamic_fee_metas = crate::accounts::SetDynamicFee {
+            owner,
+            swap_constraints: constraints.key,
+            amm: pool.amm_key,
+        }
+        .to_account_metas(None);
+        let imbalance_fee_metas = crate::accounts::SetImbalanceFee {
+            owner,
+            swap_constraints: constraints.key,
+            amm: pool.amm_key,
+        }
+        .to_account_metas(None);
+        let set_dynamic_fee = |max_fee_numerator| {
+            crate::instruction::SetDynamicFee {
+                base_fee_numerator: 2_500,
+                max_fee_numerator,
+                decay_period: 300,
+            }
+            .data()
+        };
+        let set_imbalance_fee =
+            |multiplier| crate::instruction::SetImbalanceFee { multiplier }.data();
+
+        // 11 times the static trade fee of 0.25% is a valid fee ...
+        process(
+            &imbalance_fee_metas,
+            &mut test_accounts,
+            &set_imbalance_fee(1_000),
+        )
+        .unwrap();
+        // ... but not 11 times a dynamic trade fee of up to 20%
+        assert_swap_error(
+            process(
+                &dynamic_fee_metas,
+                &mut test_accounts,
+                &set_dynamic_fee(200_000),
+            ),
+            error::SwapError::InvalidFee,
+        );
+        process(
+            &dynamic_fee_metas,
+            &mut test_accounts,
+            &set_dynamic_fee(50_000),
+        )
+        .unwrap();
+
+        // the other way round, the imbalance fee is bounded by the dynamic one
+        process(
+            &imbalance_fee_metas,
+            &mut test_accounts,
+            &set_imbalance_fee(0),
+        )
+        .unwrap();
+        process(
+            &dynamic_fee_metas,
+            &mut test_accounts,
+            &set_dynamic_fee(200_000),
+        )
+        .unwrap();
+        assert_swap_error(
+            process(
+                &imbalance_fee_metas,
+                &mut test_accounts,
+                &set_imbalance_fee(1_000),
+            ),
+            error::SwapError::InvalidFee,
+        );
+        process(
+            &imbalance_fee_metas,
+            &mut test_accounts,
+            &set_imbalance_fee(300),
+        )
+        .unwrap();
+        let amm = Amm::try_deserialize(&mut &test_accounts[2].data[..]).unwrap();
+        assert_eq!(amm.dynamic_fee.max_fee_numerator, 200_000);
+        assert_eq!(amm.imbalance_fee.multiplier, 300);
+
+        // at the highest dynamic fee, a trade leaving the pool imbalanced
+        // still swaps, paying the raised fee
+        pool.amm = amm;
+        pool.amm.dynamic_fee.volatility_accumulator = 200_000;
+        pool.amm.dynamic_fee.last_update_ts = NOW;
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        let data = crate::instruction::Swap {
+            amount_in: 1_000_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+        let event = last_swap_event(accounts.amm);
+        assert_eq!(event.trade_fee_denominator, DYNAMIC_FEE_DENOMINATOR);
+        assert!(event.trade_fee_numerator > 200_000);
+        assert!(event.trade_fee_numerator < DYNAMIC_FEE_DENOMINATOR);
+    }
+
+    #[test]
+    fn route_rejects_native_sol_round_trip() {
+        set_test_syscalls();
+        let user = Pubkey::new_unique();
+        let native_mint = spl_token::native_mint::ID;
+        // SOL -> token -> SOL, the user's wallet standing in for both ends
+        let mut sol_to_token = TestPool::new();
+        sol_to_token.user = user;
+        sol_to_token.token_a_mint = native_mint;
+        sol_to_token.amm.token_a_mint = native_mint;
+        let mut token_to_sol = TestPool::new();
+        token_to_sol.user = user;
+        token_to_sol.token_a_mint = sol_to_token.token_b_mint;
+        token_to_sol.amm.token_a_mint = sol_to_token.token_b_mint;
+        token_to_sol.token_b_mint = native_mint;
+        token_to_sol.amm.token_b_mint = native_mint;
+
+        let wallet = TestAccount::new(user, vec![], System::id());
+        let (metas, mut test_accounts) = route_accounts(
+            user,
+            wallet.clone(),
+            wallet,
+            TestAccount::mint(native_mint, user, 0),
+            &[&sol_to_token, &token_to_sol],
+        );
+        let data = crate::instruction::RouteSwap {
+            amount_in: 1_000_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::NativeSolRoundTrip,
+        );
+    }
+
+    /// Accounts of an initialization of a constant product pool of the two
+    /// mints by `payer`, in the order of `accounts::Initialize`
+    fn initialize_accounts(
+        payer: Pubkey,
+        token_a_mint: TestAccount,
+        token_b_mint: TestAccount,
+    ) -> (crate::accounts::Initialize, Vec<TestAccount>) {
+        let (amm, _) = Pubkey::find_program_address(
+            &[
+                AMM_SEED,
+                token_a_mint.key.as_ref(),
+                token_b_mint.key.as_ref(),
+                &[CurveType::ConstantProduct as u8],
+                &0u16.to_le_bytes(),
+            ],
+            &crate::ID,
+        );
+        let (authority, _) = Pubkey::find_program_address(&[amm.as_ref()], &crate::ID);
+        let token_a = Pubkey::new_unique();
+        let token_b = Pubkey::new_unique();
+        let pool_mint = Pubkey::new_unique();
+        let fee_account = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+        let fee_owner = Pubkey::new_unique();
+        let swap_constraints = swap_constraints_account(fee_owner, TestPool::new().amm.fees);
+
+        let accounts = crate::accounts::Initialize {
+            authority,
+            token_a_mint: token_a_mint.key,
+            token_b_mint: token_b_mint.key,
+            token_a,
+            token_b,
+            amm,
+            pool_mint,
+            fee_account,
+            destination,
+            token_program: token::ID,
+            swap_constraints: swap_constraints.key,
+            payer,
+            system_program: System::id(),
+        };
+        let mut token_a_account =
+            TestAccount::token_account(token_a, token_a_mint.key, authority, 1_000_000);
+        token_a_account.owner = token_a_mint.owner;
+        let mut token_b_account =
+            TestAccount::token_account(token_b, token_b_mint.key, authority, 1_000_000);
+        token_b_account.owner = token_b_mint.owner;
+        let test_accounts = vec![
+            TestAccount::new(authority, vec![], System::id()),
+            token_a_mint,
+            token_b_mint,
+            token_a_account,
+            token_b_account,
+            TestAccount::empty(amm),
+            TestAccount::mint(pool_mint, authority, 0),
+            TestAccount::token_account(fee_account, pool_mint, fee_owner, 0),
+            TestAccount::token_account(destination, pool_mint, payer, 0),
+            TestAccount::program(token::ID),
+            swap_constraints,
+            TestAccount::new(payer, vec![], System::id()),
+            TestAccount::program(System::id()),
+        ];
+        (accounts, test_accounts)
+    }
+
+    /// Keys of two mints, in the order pools require
+    fn sorted_mint_keys() -> (Pubkey, Pubkey) {
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        (first.min(second), first.max(second))
+    }
+
+    fn initialize_data() -> Vec<u8> {
+        crate::instruction::Initialize {
+            fees_input: TestPool::new().amm.fees,
+            curve_input: CurveInput {
+                curve_type: CurveType::ConstantProduct as u8,
+                curve_parameters: [0u8; 32],
+            },
+            fee_tier: 0,
+        }
+        .data()
+    }
+
+    #[test]
+    fn initialize_accepts_transfer_fee_and_metadata_extensions() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let token_a_mint = TestAccount::mint_2022(
+            token_a_mint,
+            payer,
+            &[
+                transfer_fee_config(100),
+                // metadata pointer & token metadata of later Token-2022 versions
+                (18, vec![0u8; 64]),
+                (19, vec![1u8; 100]),
+            ],
+        );
+        let (accounts, mut test_accounts) = initialize_accounts(
+            payer,
+            token_a_mint,
+            TestAccount::mint(token_b_mint, payer, 0),
+        );
+        process(
+            &accounts.to_account_metas(None),
+            &mut test_accounts,
+            &initialize_data(),
+        )
+        .unwrap();
+
+        let amm = Amm::try_deserialize(&mut &test_accounts[5].data[..]).unwrap();
+        assert!(amm.is_initialized);
+        assert_eq!(amm.token_a_program_id, spl_token_2022::ID);
+        assert_eq!(amm.token_b_program_id, token::ID);
+    }
+
+    #[test]
+    fn initialize_rejects_unsupported_mint_extensions() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        // a permanent delegate could move the vault's tokens from under the pool
+        let permanent_delegate = spl_token_2022::pod::pod_bytes_of(
+            &spl_token_2022::extension::permanent_delegate::PermanentDelegate::default(),
+        )
+        .to_vec();
+        let token_b_mint = TestAccount::mint_2022(
+            token_b_mint,
+            payer,
+            &[(ExtensionType::PermanentDelegate.into(), permanent_delegate)],
+        );
+        let (accounts, mut test_accounts) = initialize_accounts(
+            payer,
+            TestAccount::mint(token_a_mint, payer, 0),
+            token_b_mint,
+        );
+        assert_swap_error(
+            process(
+                &accounts.to_account_metas(None),
+                &mut test_accounts,
+                &initialize_data(),
+            ),
+            error::SwapError::UnsupportedMintExtension,
+        );
+    }
+
+    #[test]
+    fn swap_rejects_output_below_minimum() {
+        set_test_syscalls();
+        let pool = TestPool::new();
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        // 100_000 A only buys 98_764 B once the 0.25% trade fee is taken
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 98_765,
+        }
+        .data();
+        assert_swap_error(
+            process(&accounts.to_account_metas(None), &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 98_764,
+        }
+        .data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[3].token_amount(), 900_000);
+        assert_eq!(test_accounts[4].token_amount(), 10_100_000);
+        assert_eq!(test_accounts[5].token_amount(), 10_000_000 - 98_764);
+        assert_eq!(test_accounts[6].token_amount(), 98_764);
+    }
+
+    #[test]
+    fn swap_mints_owner_fee_to_pool_fee_account() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.fees.owner_trade_fee_numerator = 5;
+        pool.amm.fees.owner_trade_fee_denominator = 10_000;
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+
+        // the 50 A of owner fee are minted to the owner as their worth in LP tokens
+        let pool_fee_amount = test_accounts[8].token_amount();
+        assert_eq!(pool_fee_amount, 2_426);
+        let pool_mint = spl_token::state::Mint::unpack(&test_accounts[7].data).unwrap();
+        assert_eq!(pool_mint.supply, 1_000_000_000 + pool_fee_amount);
+        // ... while the trader gets what's left of the other 99_700 A
+        assert_eq!(test_accounts[6].token_amount(), 98_715);
+    }
+
+    /// Accounts of a deposit of both tokens into `pool`, in the order of
+    /// `accounts::DepositAllTokenTypes`
+    fn deposit_all_accounts(
+        pool: &TestPool,
+    ) -> (crate::accounts::DepositAllTokenTypes, Vec<TestAccount>) {
+        let user = pool.user;
+        let (wsol_account, _) =
+            Pubkey::find_program_address(&[WSOL_SEED, user.as_ref()], &crate::ID);
+        let source_a = Pubkey::new_unique();
+        let source_b = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        let accounts = crate::accounts::DepositAllTokenTypes {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            source_a,
+            source_b,
+            token_a: pool.token_a_account,
+            token_b: pool.token_b_account,
+            pool_mint: pool.pool_mint,
+            destination,
+            token_a_mint: pool.token_a_mint,
+            token_b_mint: pool.token_b_mint,
+            token_a_program: token::ID,
+            token_b_program: token::ID,
+            token_program: token::ID,
+            wsol_account,
+            system_program: System::id(),
+        };
+        let test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::token_account(source_a, pool.token_a_mint, user, 1_000_000),
+            TestAccount::token_account(source_b, pool.token_b_mint, user, 1_000_000),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(destination, pool.pool_mint, user, 0),
+            TestAccount::mint(pool.token_a_mint, user, 11_000_000),
+            TestAccount::mint(pool.token_b_mint, user, 11_000_000),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::empty(wsol_account),
+            TestAccount::program(System::id()),
+        ];
+        (accounts, test_accounts)
+    }
+
+    /// Accounts of a withdrawal of both tokens from `pool` by a user holding
+    /// 100_000_000 of its LP tokens, in the order of
+    /// `accounts::WithdrawAllTokenTypes`
+    fn withdraw_all_accounts(
+        pool: &TestPool,
+    ) -> (crate::accounts::WithdrawAllTokenTypes, Vec<TestAccount>) {
+        let user = pool.user;
+        let (wsol_account, _) =
+            Pubkey::find_program_address(&[WSOL_SEED, user.as_ref()], &crate::ID);
+        let source = Pubkey::new_unique();
+        let destination_a = Pubkey::new_unique();
+        let destination_b = Pubkey::new_unique();
+
+        let accounts = crate::accounts::WithdrawAllTokenTypes {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            pool_mint: pool.pool_mint,
+            source,
+            token_a: pool.token_a_account,
+            token_b: pool.token_b_account,
+            destination_a,
+            destination_b,
+            pool_fee_account: pool.pool_fee_account,
+            token_a_mint: pool.token_a_mint,
+            token_b_mint: pool.token_b_mint,
+            token_a_program: token::ID,
+            token_b_program: token::ID,
+            token_program: token::ID,
+            wsol_account,
+            system_program: System::id(),
+        };
+        let test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(source, pool.pool_mint, user, 100_000_000),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(destination_a, pool.token_a_mint, user, 0),
+            TestAccount::token_account(destination_b, pool.token_b_mint, user, 0),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, pool.user, 0),
+            TestAccount::mint(pool.token_a_mint, user, 10_000_000),
+            TestAccount::mint(pool.token_b_mint, user, 10_000_000),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::empty(wsol_account),
+            TestAccount::program(System::id()),
+        ];
+        (accounts, test_accounts)
+    }
+
+    #[test]
+    fn deposit_all_rounds_token_amounts_up() {
+        set_test_syscalls();
+        let pool = TestPool::new();
+        let (accounts, mut test_accounts) = deposit_all_accounts(&pool);
+        let metas = accounts.to_account_metas(None);
+        // 1% of the supply & then some costs just over 1% of each vault
+        let data = crate::instruction::DepositAllTokenTypes {
+            pool_token_amount: 10_000_001,
+            maximum_token_a_amount: 100_001,
+            maximum_token_b_amount: 100_000,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::DepositAllTokenTypes {
+            pool_token_amount: 10_000_001,
+            maximum_token_a_amount: 100_001,
+            maximum_token_b_amount: 100_001,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[3].token_amount(), 1_000_000 - 100_001);
+        assert_eq!(test_accounts[4].token_amount(), 1_000_000 - 100_001);
+        assert_eq!(test_accounts[5].token_amount(), 10_100_001);
+        assert_eq!(test_accounts[6].token_amount(), 10_100_001);
+        assert_eq!(test_accounts[8].token_amount(), 10_000_001);
+    }
+
+    #[test]
+    fn withdraw_all_charges_owner_withdraw_fee() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.fees.owner_withdraw_fee_numerator = 1;
+        pool.amm.fees.owner_withdraw_fee_denominator = 100;
+        let (accounts, mut test_accounts) = withdraw_all_accounts(&pool);
+        let metas = accounts.to_account_metas(None);
+        // the 1% fee leaves 9_900_001 LP tokens, worth just under 99_000 of each vault
+        let data = crate::instruction::WithdrawAllTokenTypes {
+            pool_token_amount: 10_000_001,
+            minimum_token_a_amount: 99_000,
+            minimum_token_b_amount: 99_000,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[4].token_amount(), 100_000_000 - 10_000_001);
+        assert_eq!(test_accounts[7].token_amount(), 99_000);
+        assert_eq!(test_accounts[8].token_amount(), 99_000);
+        assert_eq!(test_accounts[9].token_amount(), 100_000);
+        let pool_mint = spl_token::state::Mint::unpack(&test_accounts[3].data).unwrap();
+        assert_eq!(pool_mint.supply, 1_000_000_000 - 9_900_001);
+
+        let data = crate::instruction::WithdrawAllTokenTypes {
+            pool_token_amount: 10_000_001,
+            minimum_token_a_amount: 99_001,
+            minimum_token_b_amount: 0,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+    }
+
+    /// Accounts of a deposit of token A alone into `pool`, in the order of
+    /// `accounts::DepositSingleTokenType`
+    fn deposit_single_accounts(
+        pool: &TestPool,
+    ) -> (crate::accounts::DepositSingleTokenType, Vec<TestAccount>) {
+        let user = pool.user;
+        let (wsol_account, _) =
+            Pubkey::find_program_address(&[WSOL_SEED, user.as_ref()], &crate::ID);
+        let source = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        let accounts = crate::accounts::DepositSingleTokenType {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            source,
+            swap_token_a: pool.token_a_account,
+            swap_token_b: pool.token_b_account,
+            pool_mint: pool.pool_mint,
+            destination,
+            source_mint: pool.token_a_mint,
+            source_token_program: token::ID,
+            token_program: token::ID,
+            wsol_account,
+            system_program: System::id(),
+        };
+        let test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::token_account(source, pool.token_a_mint, user, 1_000_000),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(destination, pool.pool_mint, user, 0),
+            TestAccount::mint(pool.token_a_mint, user, 11_000_000),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::empty(wsol_account),
+            TestAccount::program(System::id()),
+        ];
+        (accounts, test_accounts)
+    }
+
+    #[test]
+    fn deposit_single_enforces_minimum_pool_tokens() {
+        set_test_syscalls();
+        let pool = TestPool::new();
+        let (accounts, mut test_accounts) = deposit_single_accounts(&pool);
+        let metas = accounts.to_account_metas(None);
+        // half of the 100_000 A is traded for B, paying the trade fee on it
+        let data = crate::instruction::DepositSingleTokenTypeExactAmountIn {
+            source_token_amount: 100_000,
+            minimum_pool_token_amount: 4_981_344,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::DepositSingleTokenTypeExactAmountIn {
+            source_token_amount: 100_000,
+            minimum_pool_token_amount: 4_981_343,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[3].token_amount(), 900_000);
+        assert_eq!(test_accounts[4].token_amount(), 10_100_000);
+        assert_eq!(test_accounts[7].token_amount(), 4_981_343);
+    }
+
+    #[test]
+    fn deposit_single_rejects_offset_curve() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.curve.curve_type = CurveType::Offset as u8;
+        pool.amm.curve.curve_parameters[..8].copy_from_slice(&1_000_000u64.to_le_bytes());
+        let (accounts, mut test_accounts) = deposit_single_accounts(&pool);
+        let data = crate::instruction::DepositSingleTokenTypeExactAmountIn {
+            source_token_amount: 100_000,
+            minimum_pool_token_amount: 0,
+        }
+        .data();
+        assert_swap_error(
+            process(&accounts.to_account_metas(None), &mut test_accounts, &data),
+            error::SwapError::UnsupportedCurveOperation,
+        );
+    }
+
+    /// Accounts of a withdrawal of token A alone from `pool` by a user holding
+    /// 100_000_000 of its LP tokens, in the order of
+    /// `accounts::WithdrawSingleTokenType`
+    fn withdraw_single_accounts(
+        pool: &TestPool,
+    ) -> (crate::accounts::WithdrawSingleTokenType, Vec<TestAccount>) {
+        let user = pool.user;
+        let (wsol_account, _) =
+            Pubkey::find_program_address(&[WSOL_SEED, user.as_ref()], &crate::ID);
+        let source = Pubkey::new_unique();
+        let destination = Pubkey::new_unique();
+
+        let accounts = crate::accounts::WithdrawSingleTokenType {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: user,
+            pool_mint: pool.pool_mint,
+            source,
+            swap_token_a: pool.token_a_account,
+            swap_token_b: pool.token_b_account,
+            destination,
+            pool_fee_account: pool.pool_fee_account,
+            destination_mint: pool.token_a_mint,
+            destination_token_program: token::ID,
+            token_program: token::ID,
+            wsol_account,
+            system_program: System::id(),
+        };
+        let test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(user, vec![], System::id()),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(source, pool.pool_mint, user, 100_000_000),
+            TestAccount::token_account(
+                pool.token_a_account,
+                pool.token_a_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(
+                pool.token_b_account,
+                pool.token_b_mint,
+                pool.authority,
+                10_000_000,
+            ),
+            TestAccount::token_account(destination, pool.token_a_mint, user, 0),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, pool.user, 0),
+            TestAccount::mint(pool.token_a_mint, user, 10_000_000),
+            TestAccount::program(token::ID),
+            TestAccount::program(token::ID),
+            TestAccount::empty(wsol_account),
+            TestAccount::program(System::id()),
+        ];
+        (accounts, test_accounts)
+    }
+
+    #[test]
+    fn withdraw_single_charges_owner_withdraw_fee() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.fees.owner_withdraw_fee_numerator = 1;
+        pool.amm.fees.owner_withdraw_fee_denominator = 100;
+        let (accounts, mut test_accounts) = withdraw_single_accounts(&pool);
+        let metas = accounts.to_account_metas(None);
+        // 100_000 A cost 5_006_282 LP tokens burnt, plus 1% of them to the owner
+        let data = crate::instruction::WithdrawSingleTokenTypeExactAmountOut {
+            destination_token_amount: 100_000,
+            maximum_pool_token_amount: 5_056_343,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::WithdrawSingleTokenTypeExactAmountOut {
+            destination_token_amount: 100_000,
+            maximum_pool_token_amount: 5_056_344,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[4].token_amount(), 100_000_000 - 5_056_344);
+        assert_eq!(test_accounts[5].token_amount(), 9_900_000);
+        assert_eq!(test_accounts[7].token_amount(), 100_000);
+        assert_eq!(test_accounts[8].token_amount(), 50_062);
+        let pool_mint = spl_token::state::Mint::unpack(&test_accounts[3].data).unwrap();
+        assert_eq!(pool_mint.supply, 1_000_000_000 - 5_006_282);
+    }
+
+    #[test]
+    fn swap_pays_host_fee_to_trailing_account() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.fees.owner_trade_fee_numerator = 5;
+        pool.amm.fees.owner_trade_fee_denominator = 10_000;
+        pool.amm.fees.host_fee_numerator = 20;
+        pool.amm.fees.host_fee_denominator = 100;
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        let mut metas = accounts.to_account_metas(None);
+        let host_fee_account = Pubkey::new_unique();
+        metas.push(AccountMeta::new(host_fee_account, false));
+        test_accounts.push(TestAccount::token_account(
+            host_fee_account,
+            pool.pool_mint,
+            Pubkey::new_unique(),
+            0,
+        ));
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+
+        // the host takes 20% of the 2_426 LP tokens of owner fee
+        assert_eq!(test_accounts[16].token_amount(), 485);
+        assert_eq!(test_accounts[8].token_amount(), 1_941);
+        let pool_mint = spl_token::state::Mint::unpack(&test_accounts[7].data).unwrap();
+        assert_eq!(pool_mint.supply, 1_000_000_000 + 2_426);
+    }
+
+    #[test]
+    fn swap_rejects_host_fee_account_of_another_mint() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.fees.owner_trade_fee_numerator = 5;
+        pool.amm.fees.owner_trade_fee_denominator = 10_000;
+        pool.amm.fees.host_fee_numerator = 20;
+        pool.amm.fees.host_fee_denominator = 100;
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        let mut metas = accounts.to_account_metas(None);
+        let host_fee_account = Pubkey::new_unique();
+        metas.push(AccountMeta::new(host_fee_account, false));
+        test_accounts.push(TestAccount::token_account(
+            host_fee_account,
+            pool.token_a_mint,
+            Pubkey::new_unique(),
+            0,
+        ));
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::IncorrectPoolMint,
+        );
+    }
+
+    #[test]
+    fn initialize_rejects_fees_below_swap_constraints() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let (accounts, mut test_accounts) = initialize_accounts(
+            payer,
+            TestAccount::mint(token_a_mint, payer, 0),
+            TestAccount::mint(token_b_mint, payer, 0),
+        );
+        // the constraints require a trade fee of at least 25/10_000
+        let mut fees_input = TestPool::new().amm.fees;
+        fees_input.trade_fee_numerator = 24;
+        let data = crate::instruction::Initialize {
+            fees_input,
+            curve_input: CurveInput {
+                curve_type: CurveType::ConstantProduct as u8,
+                curve_parameters: [0u8; 32],
+            },
+            fee_tier: 0,
+        }
+        .data();
+        assert_swap_error(
+            process(&accounts.to_account_metas(None), &mut test_accounts, &data),
+            error::SwapError::InvalidFee,
+        );
+    }
+
+    #[test]
+    fn initialize_rejects_curve_types_outside_swap_constraints() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let (accounts, mut test_accounts) = initialize_accounts(
+            payer,
+            TestAccount::mint(token_a_mint, payer, 0),
+            TestAccount::mint(token_b_mint, payer, 0),
+        );
+        let swap_constraints = &mut test_accounts[10];
+        let mut constraints =
+            SwapConstraints::try_deserialize(&mut &swap_constraints.data[..]).unwrap();
+        constraints.valid_curve_types = vec![CurveType::Stable as u8];
+        constraints
+            .try_serialize(&mut &mut swap_constraints.data[..])
+            .unwrap();
+        assert_swap_error(
+            process(
+                &accounts.to_account_metas(None),
+                &mut test_accounts,
+                &initialize_data(),
+            ),
+            error::SwapError::UnsupportedCurveType,
+        );
+    }
+
+    #[test]
+    fn initialize_derives_amm_and_stores_authority_bump() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let (accounts, mut test_accounts) = initialize_accounts(
+            payer,
+            TestAccount::mint(token_a_mint, payer, 0),
+            TestAccount::mint(token_b_mint, payer, 0),
+        );
+        process(
+            &accounts.to_account_metas(None),
+            &mut test_accounts,
+            &initialize_data(),
+        )
+        .unwrap();
+
+        let amm = Amm::try_deserialize(&mut &test_accounts[5].data[..]).unwrap();
+        assert_eq!(test_accounts[5].owner, crate::ID);
+        let (authority, bump_seed) =
+            Pubkey::find_program_address(&[accounts.amm.as_ref()], &crate::ID);
+        assert_eq!(accounts.authority, authority);
+        assert_eq!(amm.bump_seed, bump_seed);
+        assert_eq!(
+            authority_id(&crate::ID, &accounts.amm, amm.bump_seed).unwrap(),
+            authority
+        );
+        // the initial LP tokens went to the payer
+        assert!(test_accounts[8].token_amount() > 0);
+    }
+
+    #[test]
+    fn initialize_rejects_amm_off_the_seed_scheme() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let (mut accounts, mut test_accounts) = initialize_accounts(
+            payer,
+            TestAccount::mint(token_a_mint, payer, 0),
+            TestAccount::mint(token_b_mint, payer, 0),
+        );
+        // the amm of another fee tier, which the seeds of this one can't sign for
+        let (amm, _) = Pubkey::find_program_address(
+            &[
+                AMM_SEED,
+                token_a_mint.as_ref(),
+                token_b_mint.as_ref(),
+                &[CurveType::ConstantProduct as u8],
+                &1u16.to_le_bytes(),
+            ],
+            &crate::ID,
+        );
+        accounts.amm = amm;
+        test_accounts[5].key = amm;
+        assert_eq!(
+            process(
+                &accounts.to_account_metas(None),
+                &mut test_accounts,
+                &initialize_data(),
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    /// Rent sysvar account, as the runtime lends it
+    fn rent_account() -> TestAccount {
+        let rent = Rent::default();
+        let mut data = rent.lamports_per_byte_year.to_le_bytes().to_vec();
+        data.extend_from_slice(&rent.exemption_threshold.to_le_bytes());
+        data.push(rent.burn_percent);
+        TestAccount::new(
+            solana_program::sysvar::rent::ID,
+            data,
+            solana_program::sysvar::ID,
+        )
+    }
+
+    /// Accounts of the creation of a constant product pool of the two mints by
+    /// `payer`, funded from the payer's `source_a` & `source_b`, in the order
+    /// of `accounts::CreatePool`
+    fn create_pool_accounts(
+        payer: Pubkey,
+        token_a_mint: TestAccount,
+        token_b_mint: TestAccount,
+        source_a: TestAccount,
+        source_b: TestAccount,
+    ) -> (crate::accounts::CreatePool, Vec<TestAccount>) {
+        let (amm, _) = Pubkey::find_program_address(
+            &[
+                AMM_SEED,
+                token_a_mint.key.as_ref(),
+                token_b_mint.key.as_ref(),
+                &[CurveType::ConstantProduct as u8],
+                &0u16.to_le_bytes(),
+            ],
+            &crate::ID,
+        );
+        let pda = |seeds: &[&[u8]]| Pubkey::find_program_address(seeds, &crate::ID).0;
+        let authority = pda(&[amm.as_ref()]);
+        let token_a = pda(&[VAULT_SEED, amm.as_ref(), token_a_mint.key.as_ref()]);
+        let token_b = pda(&[VAULT_SEED, amm.as_ref(), token_b_mint.key.as_ref()]);
+        let pool_mint = pda(&[POOL_MINT_SEED, amm.as_ref()]);
+        let fee_account = pda(&[FEE_ACCOUNT_SEED, amm.as_ref()]);
+        let destination =
+            spl_associated_token_account::get_associated_token_address(&payer, &pool_mint);
+        let fee_owner = Pubkey::new_unique();
+        let swap_constraints = swap_constraints_account(fee_owner, TestPool::new().amm.fees);
+
+        let accounts = crate::accounts::CreatePool {
+            payer,
+            token_a_mint: token_a_mint.key,
+            token_b_mint: token_b_mint.key,
+            amm,
+            authority,
+            token_a,
+            token_b,
+            pool_mint,
+            swap_constraints: swap_constraints.key,
+            fee_owner,
+            fee_account,
+            destination,
+            source_a: source_a.key,
+            source_b: source_b.key,
+            token_a_program: token_a_mint.owner,
+            token_b_program: token_b_mint.owner,
+            token_program: token::ID,
+            associated_token_program: spl_associated_token_account::ID,
+            system_program: System::id(),
+            rent: solana_program::sysvar::rent::ID,
+        };
+        let test_accounts = vec![
+            TestAccount::new(payer, vec![], System::id()),
+            token_a_mint.clone(),
+            token_b_mint.clone(),
+            TestAccount::empty(amm),
+            TestAccount::new(authority, vec![], System::id()),
+            TestAccount::empty(token_a),
+            TestAccount::empty(token_b),
+            TestAccount::empty(pool_mint),
+            swap_constraints,
+            TestAccount::new(fee_owner, vec![], System::id()),
+            TestAccount::empty(fee_account),
+            TestAccount::empty(destination),
+            source_a,
+            source_b,
+            TestAccount::program(token_a_mint.owner),
+            TestAccount::program(token_b_mint.owner),
+            TestAccount::program(token::ID),
+            TestAccount::program(spl_associated_token_account::ID),
+            TestAccount::program(System::id()),
+            rent_account(),
+        ];
+        (accounts, test_accounts)
+    }
+
+    fn create_pool_data(initial_token_a_amount: u64, initial_token_b_amount: u64) -> Vec<u8> {
+        crate::instruction::CreatePool {
+            fees_input: TestPool::new().amm.fees,
+            curve_input: CurveInput {
+                curve_type: CurveType::ConstantProduct as u8,
+                curve_parameters: [0u8; 32],
+            },
+            fee_tier: 0,
+            initial_token_a_amount,
+            initial_token_b_amount,
+        }
+        .data()
+    }
+
+    #[test]
+    fn create_pool_creates_vaults_pool_mint_and_fee_account() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let (accounts, mut test_accounts) = create_pool_accounts(
+            payer,
+            TestAccount::mint(token_a_mint, payer, 1_000_000),
+            TestAccount::mint(token_b_mint, payer, 2_000_000),
+            TestAccount::token_account(Pubkey::new_unique(), token_a_mint, payer, 1_000_000),
+            TestAccount::token_account(Pubkey::new_unique(), token_b_mint, payer, 2_000_000),
+        );
+        process(
+            &accounts.to_account_metas(None),
+            &mut test_accounts,
+            &create_pool_data(1_000_000, 2_000_000),
+        )
+        .unwrap();
+
+        let amm = Amm::try_deserialize(&mut &test_accounts[3].data[..]).unwrap();
+        assert_eq!(amm.token_a_account, accounts.token_a);
+        assert_eq!(amm.token_b_account, accounts.token_b);
+        assert_eq!(amm.pool_mint, accounts.pool_mint);
+        assert_eq!(amm.pool_fee_account, accounts.fee_account);
+        assert_eq!(amm.token_count, 2);
+
+        // the vaults hold the initial liquidity, under the swap authority
+        for (vault, amount) in [
+            (&test_accounts[5], 1_000_000),
+            (&test_accounts[6], 2_000_000),
+        ] {
+            assert_eq!(vault.owner, token::ID);
+            let vault = spl_token::state::Account::unpack(&vault.data).unwrap();
+            assert_eq!(vault.owner, accounts.authority);
+            assert_eq!(vault.amount, amount);
+        }
+        assert_eq!(test_accounts[12].token_amount(), 0);
+        assert_eq!(test_accounts[13].token_amount(), 0);
+
+        // the LP mint is the swap authority's, its initial supply the payer's
+        let pool_mint = spl_token::state::Mint::unpack(&test_accounts[7].data).unwrap();
+        assert_eq!(pool_mint.mint_authority, COption::Some(accounts.authority));
+        assert_eq!(pool_mint.freeze_authority, COption::None);
+        assert_eq!(pool_mint.decimals, POOL_MINT_DECIMALS);
+        assert_eq!(test_accounts[11].token_amount(), pool_mint.supply);
+        assert!(pool_mint.supply > 0);
+
+        // ... and pool fees go to the program owner
+        let fee_account = spl_token::state::Account::unpack(&test_accounts[10].data).unwrap();
+        assert_eq!(fee_account.mint, accounts.pool_mint);
+        assert_eq!(fee_account.owner, accounts.fee_owner);
+        assert_eq!(fee_account.amount, 0);
+    }
+
+    /// Transfer fee config extension charging `basis_points` on every transfer
+    fn transfer_fee_config(basis_points: u16) -> (u16, Vec<u8>) {
+        let transfer_fee = TransferFee {
+            epoch: 0.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: basis_points.into(),
+        };
+        let config = TransferFeeConfig {
+            older_transfer_fee: transfer_fee,
+            newer_transfer_fee: transfer_fee,
+            ..TransferFeeConfig::default()
+        };
+        (
+            ExtensionType::TransferFeeConfig.into(),
+            spl_token_2022::pod::pod_bytes_of(&config).to_vec(),
+        )
+    }
+
+    #[test]
+    fn create_pool_funds_vaults_net_of_transfer_fee() {
+        set_test_syscalls();
+        let payer = Pubkey::new_unique();
+        let (token_a_mint, token_b_mint) = sorted_mint_keys();
+        let (accounts, mut test_accounts) = create_pool_accounts(
+            payer,
+            TestAccount::mint_2022(token_a_mint, payer, &[transfer_fee_config(100)]),
+            TestAccount::mint(token_b_mint, payer, 1_000_000),
+            TestAccount::token_account_2022(Pubkey::new_unique(), token_a_mint, payer, 1_000_000),
+            TestAccount::token_account(Pubkey::new_unique(), token_b_mint, payer, 1_000_000),
+        );
+        process(
+            &accounts.to_account_metas(None),
+            &mut test_accounts,
+            &create_pool_data(1_000_000, 1_000_000),
+        )
+        .unwrap();
+
+        // the vault of token A is a Token-2022 account withholding the 1% fee
+        let amm = Amm::try_deserialize(&mut &test_accounts[3].data[..]).unwrap();
+        assert_eq!(amm.token_a_program_id, spl_token_2022::ID);
+        assert_eq!(amm.token_b_program_id, token::ID);
+        assert_eq!(test_accounts[5].owner, spl_token_2022::ID);
+        assert_eq!(test_accounts[5].token_amount(), 990_000);
+        assert_eq!(test_accounts[5].withheld_amount(), 10_000);
+        assert_eq!(test_accounts[6].token_amount(), 1_000_000);
+    }
+
+    #[test]
+    fn swap_trades_amount_net_of_transfer_fee() {
+        set_test_syscalls();
+        let mut pool = TestPool::new();
+        pool.amm.token_a_program_id = spl_token_2022::ID;
+        let (mut accounts, mut test_accounts) = swap_accounts(&pool);
+        accounts.source_token_program = spl_token_2022::ID;
+        test_accounts[3] = TestAccount::token_account_2022(
+            accounts.source_info,
+            pool.token_a_mint,
+            pool.user,
+            1_000_000,
+        );
+        test_accounts[4] = TestAccount::token_account_2022(
+            pool.token_a_account,
+            pool.token_a_mint,
+            pool.authority,
+            10_000_000,
+        );
+        test_accounts[9] =
+            TestAccount::mint_2022(pool.token_a_mint, pool.user, &[transfer_fee_config(100)]);
+        test_accounts[11] = TestAccount::program(spl_token_2022::ID);
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+
+        // only the 99_000 A left after the 1% transfer fee are traded
+        assert_eq!(test_accounts[3].token_amount(), 900_000);
+        assert_eq!(test_accounts[4].token_amount(), 10_099_000);
+        assert_eq!(test_accounts[4].withheld_amount(), 1_000);
+        assert_eq!(test_accounts[6].token_amount(), 97_787);
+        let event = last_swap_event(accounts.amm);
+        assert_eq!(event.source_amount_swapped, 99_000);
+    }
+
+    #[test]
+    fn swap_wraps_native_sol_source() {
+        set_test_syscalls();
+        let native_mint = spl_token::native_mint::ID;
+        let mut pool = TestPool::new();
+        pool.token_a_mint = native_mint;
+        pool.amm.token_a_mint = native_mint;
+        let (mut accounts, mut test_accounts) = swap_accounts(&pool);
+        // the user's wallet stands in for their token A account
+        accounts.source_info = pool.user;
+        test_accounts[3] = test_accounts[2].clone();
+        test_accounts[4] =
+            TestAccount::native_token_account(pool.token_a_account, pool.authority, 10_000_000);
+        let vault_lamports = test_accounts[4].lamports;
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+
+        // only the SOL swapped left the wallet, the temporary account's rent refunded
+        assert_eq!(test_accounts[2].lamports, 1_000_000_000 - 100_000);
+        assert_eq!(test_accounts[14].lamports, 0);
+        assert_eq!(test_accounts[4].token_amount(), 10_100_000);
+        assert_eq!(test_accounts[4].lamports, vault_lamports + 100_000);
+        assert_eq!(test_accounts[6].token_amount(), 98_764);
+    }
+
+    #[test]
+    fn swap_unwraps_native_sol_destination() {
+        set_test_syscalls();
+        let native_mint = spl_token::native_mint::ID;
+        let mut pool = TestPool::new();
+        pool.token_b_mint = native_mint;
+        pool.amm.token_b_mint = native_mint;
+        let (mut accounts, mut test_accounts) = swap_accounts(&pool);
+        // the user's wallet stands in for their token B account
+        accounts.destination = pool.user;
+        test_accounts[5] =
+            TestAccount::native_token_account(pool.token_b_account, pool.authority, 10_000_000);
+        test_accounts[6] = test_accounts[2].clone();
+        let vault_lamports = test_accounts[5].lamports;
+        let data = crate::instruction::Swap {
+            amount_in: 100_000,
+            minimum_amount_out: 1,
+        }
+        .data();
+        process(&accounts.to_account_metas(None), &mut test_accounts, &data).unwrap();
+
+        assert_eq!(test_accounts[3].token_amount(), 900_000);
+        assert_eq!(test_accounts[5].token_amount(), 10_000_000 - 98_764);
+        assert_eq!(test_accounts[5].lamports, vault_lamports - 98_764);
+        // the SOL bought lands in the wallet, the temporary account's rent refunded
+        assert_eq!(test_accounts[2].lamports, 1_000_000_000 + 98_764);
+        assert_eq!(test_accounts[14].lamports, 0);
+    }
+
+    #[test]
+    fn swap_exact_out_grosses_up_input_for_trade_fee() {
+        set_test_syscalls();
+        let pool = TestPool::new();
+        let (accounts, mut test_accounts) = swap_accounts(&pool);
+        let metas = accounts.to_account_metas(None);
+        // the 99_750 A buying 98_764 B come to 100_000 A with the 0.25% trade fee
+        let data = crate::instruction::SwapExactOut {
+            amount_out: 98_764,
+            maximum_amount_in: 99_999,
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::SwapExactOut {
+            amount_out: 98_764,
+            maximum_amount_in: 100_000,
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[3].token_amount(), 900_000);
+        assert_eq!(test_accounts[4].token_amount(), 10_100_000);
+        assert_eq!(test_accounts[6].token_amount(), 98_764);
+        let event = last_swap_event(accounts.amm);
+        assert_eq!(event.source_amount_swapped, 100_000);
+        assert_eq!(event.trade_fee, 250);
+    }
+
+    #[test]
+    fn route_enforces_end_to_end_minimum() {
+        set_test_syscalls();
+        let user = Pubkey::new_unique();
+        let mut a_to_b = TestPool::new();
+        a_to_b.user = user;
+        let mut b_to_c = TestPool::new();
+        b_to_c.user = user;
+        b_to_c.token_a_mint = a_to_b.token_b_mint;
+        b_to_c.amm.token_a_mint = a_to_b.token_b_mint;
+        let route = |minimum_amount_out: u64| {
+            let (metas, mut test_accounts) = route_accounts(
+                user,
+                TestAccount::token_account(
+                    Pubkey::new_unique(),
+                    a_to_b.token_a_mint,
+                    user,
+                    1_000_000,
+                ),
+                TestAccount::token_account(Pubkey::new_unique(), b_to_c.token_b_mint, user, 0),
+                TestAccount::mint(a_to_b.token_a_mint, user, 11_000_000),
+                &[&a_to_b, &b_to_c],
+            );
+            let data = crate::instruction::RouteSwap {
+                amount_in: 100_000,
+                minimum_amount_out,
+            }
+            .data();
+            let result = process(&metas, &mut test_accounts, &data);
+            (result, test_accounts)
+        };
+
+        // each hop would clear a per-hop minimum, the route as a whole falls short
+        assert_swap_error(route(97_557).0, error::SwapError::ExceededSlippage);
+
+        let (result, test_accounts) = route(97_556);
+        result.unwrap();
+        assert_eq!(test_accounts[1].token_amount(), 900_000);
+        assert_eq!(test_accounts[2].token_amount(), 97_556);
+        // the 98_764 B paid out by the first hop went straight into the second
+        assert_eq!(test_accounts[10].token_amount(), 10_100_000);
+        assert_eq!(test_accounts[11].token_amount(), 10_000_000 - 98_764);
+        assert_eq!(test_accounts[18].token_amount(), 10_000_000 + 98_764);
+        assert_eq!(test_accounts[19].token_amount(), 10_000_000 - 97_556);
+    }
+
+    /// Stable pool of tokens A, B & a third token, holding 10_000_000,
+    /// 20_000_000 & 30_000_000 of them
+    fn three_token_pool() -> (TestPool, Vec<u64>) {
+        let mut pool = TestPool::new();
+        pool.amm.curve = TestPool::stable_curve(100);
+        pool.amm.token_count = 3;
+        pool.amm.extra_tokens[0] = PoolToken {
+            account: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            program_id: token::ID,
+        };
+        (pool, vec![10_000_000, 20_000_000, 30_000_000])
+    }
+
+    /// Remaining accounts of every token of `pool`, in the order of
+    /// `PoolTokenAccounts`, the user holding `user_amount` of each
+    fn pool_token_accounts(
+        pool: &TestPool,
+        balances: &[u64],
+        user_amount: u64,
+    ) -> (Vec<AccountMeta>, Vec<TestAccount>) {
+        let mut metas = vec![];
+        let mut test_accounts = vec![];
+        for (token, balance) in pool.amm.tokens().iter().zip(balances) {
+            let user_account = Pubkey::new_unique();
+            metas.extend([
+                AccountMeta::new(token.account, false),
+                AccountMeta::new(user_account, false),
+                AccountMeta::new_readonly(token.mint, false),
+                AccountMeta::new_readonly(token.program_id, false),
+            ]);
+            test_accounts.extend([
+                TestAccount::token_account(token.account, token.mint, pool.authority, *balance),
+                TestAccount::token_account(user_account, token.mint, pool.user, user_amount),
+                TestAccount::mint(token.mint, pool.user, balance + user_amount),
+                TestAccount::program(token.program_id),
+            ]);
+        }
+        (metas, test_accounts)
+    }
+
+    #[test]
+    fn deposit_all_multi_takes_each_token_in_proportion() {
+        set_test_syscalls();
+        let (pool, balances) = three_token_pool();
+        let destination = Pubkey::new_unique();
+        let accounts = crate::accounts::DepositAllTokenTypesMulti {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: pool.user,
+            pool_mint: pool.pool_mint,
+            destination,
+            token_program: token::ID,
+        };
+        let (remaining_metas, remaining_accounts) =
+            pool_token_accounts(&pool, &balances, 1_000_000);
+        let mut metas = accounts.to_account_metas(None);
+        metas.extend(remaining_metas);
+        let mut test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(pool.user, vec![], System::id()),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(destination, pool.pool_mint, pool.user, 0),
+            TestAccount::program(token::ID),
+        ];
+        test_accounts.extend(remaining_accounts);
+
+        // just over 1% of the supply costs just over 1% of each vault
+        let data = crate::instruction::DepositAllTokenTypesMulti {
+            pool_token_amount: 10_000_001,
+            maximum_token_amounts: vec![100_001, 200_001, 300_000],
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::DepositAllTokenTypesMulti {
+            pool_token_amount: 10_000_001,
+            maximum_token_amounts: vec![100_001, 200_001, 300_001],
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[4].token_amount(), 10_000_001);
+        for (token_index, deposited) in [100_001, 200_001, 300_001].iter().enumerate() {
+            let vault = &test_accounts[6 + token_index * POOL_TOKEN_ACCOUNTS];
+            let user_account = &test_accounts[7 + token_index * POOL_TOKEN_ACCOUNTS];
+            assert_eq!(vault.token_amount(), balances[token_index] + deposited);
+            assert_eq!(user_account.token_amount(), 1_000_000 - deposited);
+        }
+    }
+
+    #[test]
+    fn withdraw_all_multi_pays_each_token_in_proportion() {
+        set_test_syscalls();
+        let (mut pool, balances) = three_token_pool();
+        pool.amm.fees.owner_withdraw_fee_numerator = 1;
+        pool.amm.fees.owner_withdraw_fee_denominator = 100;
+        let source = Pubkey::new_unique();
+        let accounts = crate::accounts::WithdrawAllTokenTypesMulti {
+            authority: pool.authority,
+            amm: pool.amm_key,
+            user_transfer_authority: pool.user,
+            pool_mint: pool.pool_mint,
+            source,
+            pool_fee_account: pool.pool_fee_account,
+            token_program: token::ID,
+        };
+        let (remaining_metas, remaining_accounts) = pool_token_accounts(&pool, &balances, 0);
+        let mut metas = accounts.to_account_metas(None);
+        metas.extend(remaining_metas);
+        let mut test_accounts = vec![
+            TestAccount::new(pool.authority, vec![], System::id()),
+            pool.amm_account(),
+            TestAccount::new(pool.user, vec![], System::id()),
+            TestAccount::mint(pool.pool_mint, pool.authority, 1_000_000_000),
+            TestAccount::token_account(source, pool.pool_mint, pool.user, 100_000_000),
+            TestAccount::token_account(pool.pool_fee_account, pool.pool_mint, pool.user, 0),
+            TestAccount::program(token::ID),
+        ];
+        test_accounts.extend(remaining_accounts);
+
+        // the 1% fee leaves 9_900_001 LP tokens, worth just under 0.99% of each vault
+        let data = crate::instruction::WithdrawAllTokenTypesMulti {
+            pool_token_amount: 10_000_001,
+            minimum_token_amounts: vec![99_000, 198_000, 297_001],
+        }
+        .data();
+        assert_swap_error(
+            process(&metas, &mut test_accounts, &data),
+            error::SwapError::ExceededSlippage,
+        );
+
+        let data = crate::instruction::WithdrawAllTokenTypesMulti {
+            pool_token_amount: 10_000_001,
+            minimum_token_amounts: vec![99_000, 198_000, 297_000],
+        }
+        .data();
+        process(&metas, &mut test_accounts, &data).unwrap();
+        assert_eq!(test_accounts[4].token_amount(), 100_000_000 - 10_000_001);
+        assert_eq!(test_accounts[5].token_amount(), 100_000);
+        for (token_index, withdrawn) in [99_000, 198_000, 297_000].iter().enumerate() {
+            let vault = &test_accounts[7 + token_index * POOL_TOKEN_ACCOUNTS];
+            let user_account = &test_accounts[8 + token_index * POOL_TOKEN_ACCOUNTS];
+            assert_eq!(vault.token_amount(), balances[token_index] - withdrawn);
+            assert_eq!(user_account.token_amount(), *withdrawn);
+        }
+    }
 }
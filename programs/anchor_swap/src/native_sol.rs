@@ -0,0 +1,139 @@
+//! Wrapping & unwrapping native SOL for users trading SOL pairs
+
+use crate::error::SwapError;
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+use solana_program::program::{invoke, invoke_signed};
+use solana_program::program_pack::Pack;
+use solana_program::system_instruction;
+
+/// Seed of the temporary wSOL account, along with the user's pubkey
+pub const WSOL_SEED: &[u8] = b"wsol";
+
+/// Accounts moving native SOL through a temporary wSOL account, which only
+/// lives for the duration of the instruction. The user opts in by passing
+/// their own wallet instead of a token account of the native mint.
+pub struct NativeSol<'info> {
+    /// User transfer authority, paying for & owning the temporary account
+    pub user: AccountInfo<'info>,
+    /// Temporary wSOL account: A PDA (seed: `WSOL_SEED` & user's pubkey)
+    pub wsol_account: AccountInfo<'info>,
+    pub system_program: AccountInfo<'info>,
+    /// Bump seed of the temporary wSOL account
+    pub bump: u8,
+}
+
+impl<'info> NativeSol<'info> {
+    /// Token account to use in place of `account`: the account itself, or,
+    /// when it is the user's wallet, a temporary wSOL account funded with
+    /// `lamports` of the user's SOL
+    pub fn wrap(
+        &self,
+        account: AccountInfo<'info>,
+        mint: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+        lamports: u64,
+    ) -> Result<AccountInfo<'info>> {
+        if account.key != self.user.key {
+            return Ok(account);
+        }
+        if *mint.key != spl_token::native_mint::ID || *token_program.key != token::ID {
+            return Err(SwapError::ExpectedAccount.into());
+        }
+
+        let space = spl_token::state::Account::LEN;
+        let required_lamports = Rent::get()?
+            .minimum_balance(space)
+            .checked_add(lamports)
+            .ok_or(SwapError::CalculationFailure)?;
+        let user_key = self.user.key();
+        let seeds: &[&[u8]] = &[WSOL_SEED, user_key.as_ref(), &[self.bump]];
+
+        // Anyone can send lamports to the PDA, so it may not be empty
+        let current_lamports = self.wsol_account.lamports();
+        if current_lamports == 0 {
+            invoke_signed(
+                &system_instruction::create_account(
+                    self.user.key,
+                    self.wsol_account.key,
+                    required_lamports,
+                    space as u64,
+                    token_program.key,
+                ),
+                &[
+                    self.user.clone(),
+                    self.wsol_account.clone(),
+                    self.system_program.clone(),
+                ],
+                &[seeds],
+            )?;
+        } else {
+            let top_up = required_lamports.saturating_sub(current_lamports);
+            if top_up > 0 {
+                invoke(
+                    &system_instruction::transfer(self.user.key, self.wsol_account.key, top_up),
+                    &[
+                        self.user.clone(),
+                        self.wsol_account.clone(),
+                        self.system_program.clone(),
+                    ],
+                )?;
+            }
+            invoke_signed(
+                &system_instruction::allocate(self.wsol_account.key, space as u64),
+                &[self.wsol_account.clone(), self.system_program.clone()],
+                &[seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(self.wsol_account.key, token_program.key),
+                &[self.wsol_account.clone(), self.system_program.clone()],
+                &[seeds],
+            )?;
+        }
+
+        // The native mint sets the balance to the lamports above the rent reserve
+        invoke(
+            &spl_token::instruction::initialize_account3(
+                token_program.key,
+                self.wsol_account.key,
+                mint.key,
+                self.user.key,
+            )?,
+            &[
+                self.wsol_account.clone(),
+                mint.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        Ok(self.wsol_account.clone())
+    }
+
+    /// Closes `account` if it is the temporary wSOL account, unwrapping its
+    /// balance & rent back to the user's wallet
+    pub fn unwrap(
+        &self,
+        account: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+    ) -> Result<()> {
+        if account.key != self.wsol_account.key {
+            return Ok(());
+        }
+        invoke(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                self.wsol_account.key,
+                self.user.key,
+                self.user.key,
+                &[],
+            )?,
+            &[
+                self.wsol_account.clone(),
+                self.user.clone(),
+                self.user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+        Ok(())
+    }
+}
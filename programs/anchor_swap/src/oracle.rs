@@ -0,0 +1,209 @@
+//! Reading prices from oracle accounts with the Pyth price account layout
+
+use crate::error::SwapError;
+use anchor_lang::prelude::*;
+use arrayref::array_ref;
+use std::convert::TryFrom;
+
+/// Magic number opening every price account
+pub const PRICE_MAGIC: u32 = 0xa1b2_c3d4;
+/// Layout version of the price accounts read
+pub const PRICE_VERSION: u32 = 2;
+/// Account type of a price account, as opposed to mapping & product accounts
+pub const PRICE_ACCOUNT_TYPE: u32 = 3;
+/// Status of an aggregate price published while the feed is trading
+pub const PRICE_STATUS_TRADING: u32 = 1;
+/// Space of a price account up to the end of its aggregate price
+pub const PRICE_ACCOUNT_LEN: usize = 240;
+
+/// Oldest price accepted, in seconds since its publication
+pub const MAX_PRICE_AGE: i64 = 60;
+/// Widest confidence interval accepted, in basis points of the price
+pub const MAX_CONFIDENCE_BPS: u64 = 100;
+
+/// Aggregate price of a price account, worth `price * 10 ^ expo` with a
+/// confidence interval of `conf * 10 ^ expo`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OraclePrice {
+    /// Price, in units of `10 ^ expo`
+    pub price: i64,
+    /// Confidence interval of the price, in units of `10 ^ expo`
+    pub conf: u64,
+    /// Exponent of the price & confidence interval
+    pub expo: i32,
+    /// Status of the aggregate price, see `PRICE_STATUS_TRADING`
+    pub status: u32,
+    /// Unix timestamp the price was published at
+    pub publish_time: i64,
+}
+
+impl OraclePrice {
+    /// Unpacks the aggregate price of a price account's data
+    pub fn unpack(data: &[u8]) -> Result<Self> {
+        if data.len() < PRICE_ACCOUNT_LEN {
+            return Err(SwapError::InvalidOracle.into());
+        }
+        let magic = u32::from_le_bytes(*array_ref![data, 0, 4]);
+        let version = u32::from_le_bytes(*array_ref![data, 4, 4]);
+        let account_type = u32::from_le_bytes(*array_ref![data, 8, 4]);
+        if magic != PRICE_MAGIC || version != PRICE_VERSION || account_type != PRICE_ACCOUNT_TYPE {
+            return Err(SwapError::InvalidOracle.into());
+        }
+        Ok(Self {
+            price: i64::from_le_bytes(*array_ref![data, 208, 8]),
+            conf: u64::from_le_bytes(*array_ref![data, 216, 8]),
+            expo: i32::from_le_bytes(*array_ref![data, 20, 4]),
+            status: u32::from_le_bytes(*array_ref![data, 224, 4]),
+            publish_time: i64::from_le_bytes(*array_ref![data, 96, 8]),
+        })
+    }
+
+    /// Reads the price of `oracle`, only accepting a positive price, currently
+    /// trading, published at most `MAX_PRICE_AGE` ago & not ahead of
+    /// `unix_timestamp`, with a confidence no wider than `MAX_CONFIDENCE_BPS`
+    pub fn load(oracle: &AccountInfo, unix_timestamp: i64) -> Result<Self> {
+        let price = Self::unpack(&oracle.try_borrow_data()?)?;
+        if price.status != PRICE_STATUS_TRADING || price.price <= 0 {
+            return Err(SwapError::InvalidOracle.into());
+        }
+        let age = unix_timestamp
+            .checked_sub(price.publish_time)
+            .ok_or(SwapError::CalculationFailure)?;
+        // A price published ahead of the clock is as untrustworthy as an old one
+        if !(0..=MAX_PRICE_AGE).contains(&age) {
+            return Err(SwapError::StaleOraclePrice.into());
+        }
+        let max_conf = u128::from(price.price as u64)
+            .checked_mul(u128::from(MAX_CONFIDENCE_BPS))
+            .ok_or(SwapError::CalculationFailure)?
+            / 10_000;
+        if u128::from(price.conf) > max_conf {
+            return Err(SwapError::UncertainOraclePrice.into());
+        }
+        Ok(price)
+    }
+
+    /// Amount of token A for 1 token B, in the tokens' smallest units, as a
+    /// numerator & denominator, given the oracle prices whole tokens B in
+    /// whole tokens A & token A has `decimals_offset` more decimals than
+    /// token B
+    pub fn token_b_price(&self, decimals_offset: i8) -> Option<(u64, u64)> {
+        let price = u64::try_from(self.price).ok().filter(|price| *price > 0)?;
+        let exponent = self.expo.checked_add(i32::from(decimals_offset))?;
+        let scale = 10u64.checked_pow(exponent.unsigned_abs())?;
+        if exponent >= 0 {
+            Some((price.checked_mul(scale)?, 1))
+        } else {
+            Some((price, scale))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data of a fake price account, as an oracle would write it
+    fn price_account_data(price: i64, conf: u64, expo: i32, publish_time: i64) -> Vec<u8> {
+        let mut data = vec![0u8; 3312];
+        data[0..4].copy_from_slice(&PRICE_MAGIC.to_le_bytes());
+        data[4..8].copy_from_slice(&PRICE_VERSION.to_le_bytes());
+        data[8..12].copy_from_slice(&PRICE_ACCOUNT_TYPE.to_le_bytes());
+        data[20..24].copy_from_slice(&expo.to_le_bytes());
+        data[96..104].copy_from_slice(&publish_time.to_le_bytes());
+        data[208..216].copy_from_slice(&price.to_le_bytes());
+        data[216..224].copy_from_slice(&conf.to_le_bytes());
+        data[224..228].copy_from_slice(&PRICE_STATUS_TRADING.to_le_bytes());
+        data
+    }
+
+    fn load(data: &mut [u8], unix_timestamp: i64) -> Result<OraclePrice> {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let oracle = AccountInfo::new(&key, false, false, &mut lamports, data, &owner, false, 0);
+        OraclePrice::load(&oracle, unix_timestamp)
+    }
+
+    fn assert_load_err(data: &mut [u8], unix_timestamp: i64, expected: SwapError) {
+        let error = ProgramError::from(load(data, unix_timestamp).unwrap_err());
+        assert_eq!(error, ProgramError::Custom(expected.into()));
+    }
+
+    #[test]
+    fn load_fresh_price() {
+        let mut data = price_account_data(105_000_000, 10_000, -8, 1_000);
+        let price = load(&mut data, 1_000 + MAX_PRICE_AGE).unwrap();
+        assert_eq!(
+            price,
+            OraclePrice {
+                price: 105_000_000,
+                conf: 10_000,
+                expo: -8,
+                status: PRICE_STATUS_TRADING,
+                publish_time: 1_000,
+            }
+        );
+    }
+
+    #[test]
+    fn reject_stale_price() {
+        let mut data = price_account_data(105_000_000, 10_000, -8, 1_000);
+        assert_load_err(
+            &mut data,
+            1_001 + MAX_PRICE_AGE,
+            SwapError::StaleOraclePrice,
+        );
+    }
+
+    #[test]
+    fn reject_future_price() {
+        let mut data = price_account_data(105_000_000, 10_000, -8, 1_001);
+        assert_load_err(&mut data, 1_000, SwapError::StaleOraclePrice);
+    }
+
+    #[test]
+    fn reject_uncertain_price() {
+        let price = 105_000_000;
+        let max_conf = price as u64 * MAX_CONFIDENCE_BPS / 10_000;
+        let mut data = price_account_data(price, max_conf, -8, 1_000);
+        assert!(load(&mut data, 1_000).is_ok());
+        let mut data = price_account_data(price, max_conf + 1, -8, 1_000);
+        assert_load_err(&mut data, 1_000, SwapError::UncertainOraclePrice);
+    }
+
+    #[test]
+    fn reject_invalid_account() {
+        let mut data = price_account_data(105_000_000, 10_000, -8, 1_000);
+        data[224..228].copy_from_slice(&0u32.to_le_bytes());
+        assert_load_err(&mut data, 1_000, SwapError::InvalidOracle);
+
+        let mut data = price_account_data(-1, 0, -8, 1_000);
+        assert_load_err(&mut data, 1_000, SwapError::InvalidOracle);
+
+        let mut data = price_account_data(105_000_000, 10_000, -8, 1_000);
+        data[0] = 0;
+        assert_load_err(&mut data, 1_000, SwapError::InvalidOracle);
+
+        let mut data = price_account_data(105_000_000, 10_000, -8, 1_000);
+        assert_load_err(
+            &mut data[..PRICE_ACCOUNT_LEN - 1],
+            1_000,
+            SwapError::InvalidOracle,
+        );
+    }
+
+    #[test]
+    fn token_b_price_from_decimals() {
+        let price = OraclePrice::unpack(&price_account_data(2_500_000, 0, -4, 0)).unwrap();
+        // 250 whole A per whole B, both sides with the same decimals
+        assert_eq!(price.token_b_price(0), Some((2_500_000, 10_000)));
+        // token A with 6 more decimals than token B
+        assert_eq!(price.token_b_price(6), Some((250_000_000, 1)));
+        // token B with 5 more decimals than token A, below 1 token A per token B
+        assert_eq!(price.token_b_price(-5), Some((2_500_000, 1_000_000_000)));
+        // too large for the curve
+        assert_eq!(price.token_b_price(30), None);
+        assert_eq!(price.token_b_price(-30), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Reading & moving pool tokens owned by either the Token or the Token-2022 program
+
+use crate::error::SwapError;
+use anchor_lang::prelude::*;
+use anchor_spl::token;
+use solana_program::program::{invoke, invoke_signed};
+use solana_program::program_pack::Pack;
+use solana_program::system_instruction;
+use spl_token_2022::extension::{
+    transfer_fee::TransferFeeConfig, AccountType, ExtensionType, StateWithExtensions,
+};
+use spl_token_2022::pod::pod_from_bytes;
+use spl_token_2022::state::{Account, Mint};
+use std::convert::TryFrom;
+
+/// Extension types of later Token-2022 versions, which this one can't parse:
+/// the pointer to a mint's metadata & the metadata stored on the mint itself
+const METADATA_POINTER_EXTENSION: u16 = 18;
+const TOKEN_METADATA_EXTENSION: u16 = 19;
+
+/// Checks that the program is one of the token programs a pool side may use
+pub fn check_token_program(program_id: &Pubkey) -> Result<()> {
+    if *program_id == token::ID || *program_id == spl_token_2022::ID {
+        Ok(())
+    } else {
+        Err(SwapError::IncorrectTokenProgramId.into())
+    }
+}
+
+/// Unpacks the base state of a token account, extensions ignored
+pub fn unpack_token_account(account_info: &AccountInfo) -> Result<Account> {
+    check_token_program(account_info.owner)?;
+    let data = account_info.try_borrow_data()?;
+    StateWithExtensions::<Account>::unpack(&data)
+        .map(|account| account.base)
+        .map_err(|_| SwapError::ExpectedAccount.into())
+}
+
+/// Unpacks the base state of a mint, extensions ignored
+pub fn unpack_mint(account_info: &AccountInfo) -> Result<Mint> {
+    check_token_program(account_info.owner)?;
+    let data = account_info.try_borrow_data()?;
+    StateWithExtensions::<Mint>::unpack(&data)
+        .map(|mint| mint.base)
+        .map_err(|_| SwapError::ExpectedMint.into())
+}
+
+/// Type & value of every extension of a Token-2022 mint, read from the raw
+/// TLV entries so that extensions of later Token-2022 versions are listed too
+fn mint_extensions(data: &[u8]) -> Result<Vec<(u16, &[u8])>> {
+    StateWithExtensions::<Mint>::unpack(data).map_err(|_| SwapError::ExpectedMint)?;
+    if data.len() == Mint::LEN {
+        return Ok(vec![]);
+    }
+    // Extensions follow the base state, padded to an account's length, & the account type
+    if data.get(Account::LEN) != Some(&(AccountType::Mint as u8)) {
+        return Err(SwapError::ExpectedMint.into());
+    }
+    let mut tlv_data = &data[Account::LEN + 1..];
+    let mut extensions = vec![];
+    while tlv_data.len() >= 4 {
+        let extension_type = u16::from_le_bytes([tlv_data[0], tlv_data[1]]);
+        if extension_type == u16::from(ExtensionType::Uninitialized) {
+            break;
+        }
+        let length = usize::from(u16::from_le_bytes([tlv_data[2], tlv_data[3]]));
+        let value = tlv_data.get(4..4 + length).ok_or(SwapError::ExpectedMint)?;
+        extensions.push((extension_type, value));
+        tlv_data = &tlv_data[4 + length..];
+    }
+    Ok(extensions)
+}
+
+/// Checks that a pool can hold the mint: besides token metadata, Token-2022
+/// mints may only carry the transfer fee pools trade net of. Other extensions,
+/// such as a permanent delegate or interest, would move the vaults' balances
+/// under the pool.
+pub fn check_mint_extensions(mint_info: &AccountInfo) -> Result<()> {
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(());
+    }
+    let data = mint_info.try_borrow_data()?;
+    for (extension_type, _) in mint_extensions(&data)? {
+        if extension_type != u16::from(ExtensionType::TransferFeeConfig)
+            && extension_type != METADATA_POINTER_EXTENSION
+            && extension_type != TOKEN_METADATA_EXTENSION
+        {
+            return Err(SwapError::UnsupportedMintExtension.into());
+        }
+    }
+    Ok(())
+}
+
+/// Reads the mint's transfer fee config, if it has one
+fn with_transfer_fee_config<F>(mint_info: &AccountInfo, f: F) -> Result<u64>
+where
+    F: FnOnce(&TransferFeeConfig) -> Option<u64>,
+{
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(0);
+    }
+    let data = mint_info.try_borrow_data()?;
+    let transfer_fee_config = mint_extensions(&data)?
+        .into_iter()
+        .find(|(extension_type, _)| *extension_type == u16::from(ExtensionType::TransferFeeConfig));
+    match transfer_fee_config {
+        Some((_, value)) => {
+            let transfer_fee_config =
+                pod_from_bytes::<TransferFeeConfig>(value).map_err(|_| SwapError::ExpectedMint)?;
+            Ok(f(transfer_fee_config).ok_or(SwapError::FeeCalculationFailure)?)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Fee withheld by the mint when `amount` is sent, so the receiver gets
+/// `amount - fee`
+pub fn transfer_fee(mint_info: &AccountInfo, amount: u64) -> Result<u64> {
+    let epoch = Clock::get()?.epoch;
+    with_transfer_fee_config(mint_info, |config| {
+        config.calculate_epoch_fee(epoch, amount)
+    })
+}
+
+/// Fee withheld by the mint on the transfer that leaves exactly `amount` to
+/// the receiver, so the sender pays `amount + fee`
+pub fn inverse_transfer_fee(mint_info: &AccountInfo, amount: u64) -> Result<u64> {
+    let epoch = Clock::get()?.epoch;
+    with_transfer_fee_config(mint_info, |config| {
+        config.calculate_inverse_epoch_fee(epoch, amount)
+    })
+}
+
+/// Transfers through the token program owning the mint. `TransferChecked` is
+/// used as Token-2022 rejects plain transfers of mints with a transfer fee.
+pub fn transfer<'info>(
+    token_program: AccountInfo<'info>,
+    source: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    destination: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let decimals = unpack_mint(&mint)?.decimals;
+    let ix = spl_token_2022::instruction::transfer_checked(
+        token_program.key,
+        source.key,
+        mint.key,
+        destination.key,
+        authority.key,
+        &[],
+        amount,
+        decimals,
+    )?;
+    invoke_signed(
+        &ix,
+        &[source, mint, destination, authority, token_program],
+        signer_seeds,
+    )?;
+    Ok(())
+}
+
+/// Creates the token account of `mint` at the `vault` PDA, sized for the
+/// account extensions the mint requires, and hands it to `authority`
+pub fn create_vault<'info>(
+    payer: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    vault_seeds: &[&[u8]],
+) -> Result<()> {
+    check_token_program(token_program.key)?;
+    if mint.owner != token_program.key {
+        return Err(SwapError::IncorrectTokenProgramId.into());
+    }
+    let space = if *mint.owner == spl_token_2022::ID {
+        let data = mint.try_borrow_data()?;
+        // Extensions this version can't parse require no account extension
+        let mint_extension_types = mint_extensions(&data)?
+            .into_iter()
+            .filter_map(|(extension_type, _)| ExtensionType::try_from(extension_type).ok())
+            .collect::<Vec<_>>();
+        let account_extensions =
+            ExtensionType::get_required_init_account_extensions(&mint_extension_types);
+        ExtensionType::get_account_len::<Account>(&account_extensions)
+    } else {
+        Account::LEN
+    };
+
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            vault.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            token_program.key,
+        ),
+        &[payer, vault.clone(), system_program],
+        &[vault_seeds],
+    )?;
+    invoke(
+        &spl_token_2022::instruction::initialize_account3(
+            token_program.key,
+            vault.key,
+            mint.key,
+            authority.key,
+        )?,
+        &[vault, mint, token_program],
+    )?;
+    Ok(())
+}